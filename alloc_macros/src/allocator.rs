@@ -253,62 +253,3 @@ pub fn tls_static(input: TokenStream) -> TokenStream {
         .parse()
         .expect("Error parsing formatted string into token stream.")
 }
-
-fn gcd(a: usize, b: usize) -> usize {
-    match ((a, b), (a & 1, b & 1)) {
-        ((x, y), _) if x == y => y,
-        ((0, x), _) | ((x, 0), _) => x,
-        ((x, y), (0, 1)) | ((y, x), (1, 0)) => gcd(x >> 1, y),
-        ((x, y), (0, 0)) => gcd(x >> 1, y >> 1) << 1,
-        ((x, y), (1, 1)) => {
-            let (x, y) = (core::cmp::min(x, y), core::cmp::max(x, y));
-            gcd((y - x) >> 1, x)
-        }
-        _ => unreachable!(),
-    }
-}
-
-fn lcm(a: usize, b: usize) -> usize {
-    a * b / gcd(a, b)
-}
-
-/// Calculates the gcd of
-pub fn generate_num_pages(input: TokenStream) -> TokenStream {
-    let mut it = input.into_iter();
-    let mut vals = String::new();
-    let mut idx = 0;
-
-    loop {
-        let cl = match it.next() {
-            Some(TokenTree::Literal(l)) => l.to_string(),
-            Some(TokenTree::Punct(_)) => continue,
-            Some(_) => panic!("Expect Literal, Punct, or end"),
-            None => break,
-        };
-
-        let cl = cl.parse::<usize>().unwrap();
-        // #[cfg(target_arch = "x86_64")]
-        // TODO: check how to determine page size dynamically
-        const PAGE_SIZE: usize = 0x1000;
-        // #[cfg(target_arch = "aarch64")]
-        // let page_size = 0x10000;
-
-        let num_pages = lcm(PAGE_SIZE, cl) / PAGE_SIZE;
-
-        vals.push_str(&format!("{}, ", num_pages));
-
-        idx += 1;
-    }
-
-    format!(
-        "
-            const SIZE_CLASS_PAGES: [usize; {sz}] = [
-                {vals}
-            ];
-        ",
-        sz = idx,
-        vals = vals,
-    )
-    .parse()
-    .expect("Error parsing formatted string into token stream.")
-}
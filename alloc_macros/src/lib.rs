@@ -11,8 +11,3 @@ pub fn atomic_static(ts: TokenStream) -> TokenStream {
 pub fn tls_static(ts: TokenStream) -> TokenStream {
     allocator::tls_static(ts)
 }
-
-#[proc_macro]
-pub fn generate_num_pages(ts: TokenStream) -> TokenStream {
-    allocator::generate_num_pages(ts)
-}
@@ -0,0 +1,104 @@
+//! Fragmentation left by a churn workload
+//!
+//! Rounds of allocations are followed by frees of most live objects, picked
+//! at random, while the sizes allocated drift from round to round. The
+//! survivors are scattered over the slabs of every class. The benchmark
+//! reports the resident memory the workload added divided by the bytes still
+//! live, 1.0 being no overhead at all. Run it for every allocator with
+//! `cargo bench --bench fragmentation --features bench_jemalloc` and so on.
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use std::alloc::{alloc, dealloc, Layout};
+use std::fs;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "bench_jemalloc")] {
+        use jemallocator::Jemalloc;
+        #[global_allocator]
+        static JEMALLOC: Jemalloc = Jemalloc;
+    } else if #[cfg(feature = "bench_mimalloc")] {
+        use mimalloc::MiMalloc;
+        #[global_allocator]
+        static MIMALLOC: MiMalloc = MiMalloc;
+    } else if #[cfg(feature = "bench_tcmalloc")] {
+        use tcmalloc::TCMalloc;
+        #[global_allocator]
+        static TCMALLOC: TCMalloc = TCMalloc;
+    } else if #[cfg(feature = "bench_snmalloc")] {
+        #[global_allocator]
+        static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+    } else {
+        use unialloc::UniAlloc;
+        #[global_allocator]
+        static OURSELF: UniAlloc = UniAlloc;
+    }
+}
+
+const SEED: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+const ROUNDS: usize = 32;
+
+/// Objects allocated each round
+const BATCH: usize = 1 << 18;
+
+/// Live objects freed each round, in percent
+const FREED: usize = 90;
+
+/// Sizes allocated, each round draws from a window sliding over them
+const SIZES: &[usize] = &[
+    16, 24, 32, 48, 64, 80, 96, 128, 160, 192, 256, 320, 384, 512, 768, 1024, 2048,
+];
+
+/// Sizes in the window of a round
+const WINDOW: usize = 6;
+
+/// Resident bytes of the process
+fn rss() -> usize {
+    let statm = fs::read_to_string("/proc/self/statm").expect("cannot read statm");
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|field| field.parse().ok())
+        .expect("malformed statm");
+    pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize
+}
+
+fn main() {
+    let mut rng = XorShiftRng::from_seed(SEED);
+    // the bookkeeping is touched before the baseline, so it is not counted
+    let mut live: Vec<(usize, Layout)> = Vec::with_capacity(2 * BATCH);
+    live.resize(2 * BATCH, (0, Layout::new::<u8>()));
+    live.clear();
+    let base = rss();
+
+    let mut live_bytes = 0;
+    let mut peak = 0;
+    for round in 0..ROUNDS {
+        for _ in 0..BATCH {
+            let size = SIZES[(round + rng.gen_range(0, WINDOW)) % SIZES.len()];
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr = unsafe { alloc(layout) };
+            assert!(!ptr.is_null());
+            unsafe { ptr.write_bytes(round as u8, size) };
+            live.push((ptr as usize, layout));
+            live_bytes += size;
+        }
+        peak = peak.max(rss() - base);
+        for _ in 0..live.len() * FREED / 100 {
+            let (ptr, layout) = live.swap_remove(rng.gen_range(0, live.len()));
+            unsafe { dealloc(ptr as *mut u8, layout) };
+            live_bytes -= layout.size();
+        }
+    }
+
+    let resident = rss() - base;
+    println!("live objects: {}", live.len());
+    println!("live bytes:   {}", live_bytes);
+    println!("peak rss:     {}", peak);
+    println!("rss:          {}", resident);
+    println!("rss / live:   {:.2}", resident as f64 / live_bytes as f64);
+
+    for (ptr, layout) in live {
+        unsafe { dealloc(ptr as *mut u8, layout) };
+    }
+}
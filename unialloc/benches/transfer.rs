@@ -0,0 +1,136 @@
+//! Multi-threaded churn of small objects
+//!
+//! Every thread keeps refilling and flushing its cache for the 32 to 64 bytes
+//! classes, which is what the transfer caches of the zone take off the slab
+//! locks. Compare against a previous build with
+//! `cargo bench --bench transfer -- --save-baseline before` there and
+//! `cargo bench --bench transfer -- --baseline before` here.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::alloc::{alloc, dealloc, Layout};
+use std::{
+    sync::{mpsc, Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "bench_jemalloc")] {
+        use jemallocator::Jemalloc;
+        #[global_allocator]
+        static JEMALLOC: Jemalloc = Jemalloc;
+    } else if #[cfg(feature = "bench_mimalloc")] {
+        use mimalloc::MiMalloc;
+        #[global_allocator]
+        static MIMALLOC: MiMalloc = MiMalloc;
+    } else if #[cfg(feature = "bench_tcmalloc")] {
+        use tcmalloc::TCMalloc;
+        #[global_allocator]
+        static TCMALLOC: TCMalloc = TCMalloc;
+    } else if #[cfg(feature = "bench_snmalloc")] {
+        #[global_allocator]
+        static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+    } else {
+        use unialloc::UniAlloc;
+        #[global_allocator]
+        static OURSELF: UniAlloc = UniAlloc;
+    }
+}
+
+const N_THREADS: &[usize] = &[1, 4, 16, 64, 128];
+
+/// Objects each thread holds at once, a few slabs worth
+const LIVE: usize = 4096;
+
+const ROUNDS: usize = 16;
+
+/// Sizes cycled through, all in the 32 to 64 bytes classes
+const SIZES: &[usize] = &[32, 40, 48, 56, 64];
+
+/// Allocates `LIVE` objects and frees them, `ROUNDS` times
+fn churn() {
+    let mut ptrs = Vec::with_capacity(LIVE);
+    for _ in 0..ROUNDS {
+        for i in 0..LIVE {
+            let layout = Layout::from_size_align(SIZES[i % SIZES.len()], 8).unwrap();
+            let ptr = unsafe { alloc(layout) };
+            assert!(!ptr.is_null());
+            unsafe { ptr.write(i as u8) };
+            ptrs.push((ptr, layout));
+        }
+        for (ptr, layout) in ptrs.drain(..) {
+            unsafe { dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Runs `n_threads` threads in a ring, each allocating `LIVE` objects and
+/// freeing the ones of the previous thread, `ROUNDS` times
+///
+/// The frees go through the remote lists, and the surplus of the owners
+/// through the transfer caches.
+fn handoff(n_threads: usize) -> Duration {
+    let barrier = Arc::new(Barrier::new(n_threads + 1));
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..n_threads).map(|_| mpsc::channel()).unzip();
+    let mut receivers: Vec<_> = receivers.into_iter().map(Some).collect();
+    let mut children = Vec::with_capacity(n_threads);
+    for (i, sender) in senders.into_iter().enumerate() {
+        let receiver: mpsc::Receiver<Vec<usize>> = receivers[(i + 1) % n_threads].take().unwrap();
+        let barrier = barrier.clone();
+        children.push(thread::spawn(move || {
+            barrier.wait();
+            let layout = Layout::from_size_align(48, 8).unwrap();
+            for _ in 0..ROUNDS {
+                let ptrs: Vec<_> = (0..LIVE)
+                    .map(|_| unsafe { alloc(layout) } as usize)
+                    .collect();
+                sender.send(ptrs).unwrap();
+                for ptr in receiver.recv().unwrap() {
+                    unsafe { dealloc(ptr as *mut u8, layout) };
+                }
+            }
+        }));
+    }
+    barrier.wait();
+    let start = Instant::now();
+    for child in children {
+        child.join().unwrap();
+    }
+    start.elapsed()
+}
+
+/// Runs `churn` on `n_threads` threads started together
+fn churn_on(n_threads: usize) -> Duration {
+    let barrier = Arc::new(Barrier::new(n_threads + 1));
+    let children: Vec<_> = (0..n_threads)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                churn();
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for child in children {
+        child.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn transfer_multithread(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transfer_multithreaded");
+    group.sample_size(10);
+    for n in N_THREADS {
+        group.bench_with_input(BenchmarkId::new("churn", n), n, |b, &n| {
+            b.iter_custom(|iters| (0..iters).map(|_| churn_on(n)).sum())
+        });
+        group.bench_with_input(BenchmarkId::new("handoff", n), n, |b, &n| {
+            b.iter_custom(|iters| (0..iters).map(|_| handoff(n)).sum())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, transfer_multithread);
+criterion_main!(benches);
@@ -219,6 +219,37 @@ fn bench_extend_recycle(b: &mut Bencher) {
     black_box(data);
 }
 
+// Growing past the backend blocks, a vector is moved by the OS rather than
+// copied, see `ZoneAllocator::reallocate_large`
+
+#[bench]
+fn bench_push_large(b: &mut Bencher) {
+    let len = 2 << 20;
+    b.bytes = (len * std::mem::size_of::<u64>()) as u64;
+
+    b.iter(|| {
+        let mut dst = Vec::new();
+        for i in 0..len as u64 {
+            dst.push(i);
+        }
+        dst
+    });
+}
+
+#[bench]
+fn bench_extend_large(b: &mut Bencher) {
+    let src: Vec<u64> = FromIterator::from_iter(0..1 << 16);
+    b.bytes = (64 * src.len() * std::mem::size_of::<u64>()) as u64;
+
+    b.iter(|| {
+        let mut dst = Vec::new();
+        for _ in 0..64 {
+            dst.extend(src.iter().copied());
+        }
+        dst
+    });
+}
+
 #[bench]
 fn bench_extend_from_slice_0000_0000(b: &mut Bencher) {
     do_bench_extend_from_slice(b, 0, 0)
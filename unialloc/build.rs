@@ -3,42 +3,24 @@ use std::env;
 use std::fs;
 use std::path::Path;
 
-#[cfg(rseq)]
-fn rseq_available() -> bool {
-    unsafe {
-        let rc = libc::syscall(334, 0, 0, 0, 0);
-        if rc != -1 {
-            panic!("WTF! (rc = {})", rc);
-        }
+/// Size of the pages the allocator manages, not necessarily the one of the OS
+const DEFAULT_PAGE_SIZE: usize = 4096;
 
-        match *libc::__errno_location() {
-            libc::ENOSYS => false,
-            libc::EINVAL => true,
-            num => {
-                panic!("WTF? {}", num)
-            }
-        }
-    }
+/// Reads an optional build-time override from the environment
+fn env_override(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed={}", name);
+    env::var(name).ok()
 }
 
-static IDX_BIT: usize = 32;
-
-fn calculate_val(pg: usize, tar: usize) -> (usize, usize, usize) {
-    let mut num_in_pg = pg / tar;
-    if num_in_pg > IDX_BIT {
-        ((num_in_pg + IDX_BIT - 1) / IDX_BIT, tar, 1)
-    } else {
-        let mut ratio = 1_usize;
-        let mut pa_size = pg;
-        while ratio < 8 && num_in_pg <= 32 {
-            ratio += 1;
-            pa_size <<= 1;
-            num_in_pg = pa_size / tar;
-        }
-        pa_size >>= 1;
-        num_in_pg = pa_size / tar;
-        ((num_in_pg + IDX_BIT - 1) / IDX_BIT, tar, pa_size / pg)
-    }
+fn page_size() -> usize {
+    let size = env_override("UNIALLOC_PAGE_SIZE")
+        .map(|v| v.parse().expect("UNIALLOC_PAGE_SIZE must be a number"))
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    assert!(
+        size.is_power_of_two(),
+        "UNIALLOC_PAGE_SIZE must be a power of two"
+    );
+    size
 }
 
 fn main() {
@@ -56,69 +38,272 @@ fn main() {
         macos: { target_os = "macos" },
         linux: { target_os = "linux" },
         rseq: { all(target_os = "linux", feature = "rseq") },
+        // the per-CPU frontend, its critical sections are written for x86_64
+        percpu: { all(rseq, x64, not(feature = "fixed_heap"), not(feature = "page_heap")) },
+        // the frontend of thread owned pages, which go back to the zone at thread exit
+        page_heap: { all(feature = "page_heap", not(feature = "fixed_heap")) },
+        // the histogram of requested sizes, written out with libc at exit
+        size_histogram: { all(feature = "size_histogram", linux, not(feature = "fixed_heap")) },
     }
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("consts.rs");
+    let content = format!("pub const PAGE_SIZE: usize = {};\n", page_size());
+    fs::write(&dest_path, content).unwrap();
 
-    #[cfg(not(rseq))]
-    let has_rseq = false;
-
-    #[cfg(rseq)]
-    let has_rseq = rseq_available();
-
+    // Everything else about the system is probed at runtime, see
+    // `pal::sysinfo`. These only override the probed values.
+    let ncpu = env_override("UNIALLOC_NCPU")
+        .map(|v| v.parse::<usize>().expect("UNIALLOC_NCPU must be a number"));
+    let os_page_size = env_override("UNIALLOC_OS_PAGE_SIZE").map(|v| {
+        v.parse::<usize>()
+            .expect("UNIALLOC_OS_PAGE_SIZE must be a number")
+    });
+    let has_rseq = env_override("UNIALLOC_RSEQ").map(|v| {
+        v.parse::<bool>()
+            .expect("UNIALLOC_RSEQ must be true or false")
+    });
+    let dest_path = Path::new(&out_dir).join("overrides.rs");
     let content = format!(
-        "pub const NCPU: usize = {};\n
-         pub const PAGE_SIZE: usize = {};\n
-         pub const HAS_RSEQ: bool = {};\n\n",
-        num_cpus::get(),
-        page_size::get(),
-        has_rseq
+        "const NCPU_OVERRIDE: Option<usize> = {:?};\n\
+         const OS_PAGE_SIZE_OVERRIDE: Option<usize> = {:?};\n\
+         const HAS_RSEQ_OVERRIDE: Option<bool> = {:?};\n",
+        ncpu, os_page_size, has_rseq
     );
+    fs::write(&dest_path, content).unwrap();
 
-    generate_sizeclass();
+    generate_size_classes();
+}
 
-    fs::write(&dest_path, content).unwrap();
+/// Spec of the size class tables, see the file for its format
+const SIZE_CLASS_SPEC: &str = "size_classes.txt";
+
+/// Requests up to it are looked up by steps of 8 bytes, larger ones by
+/// steps of 128
+const LOOKUP_SMALL_MAX: usize = 1024;
+/// Offset of the entries by steps of 128 in the lookup table
+const LOOKUP_OFFSET: usize = (LOOKUP_SMALL_MAX >> 3) - (LOOKUP_SMALL_MAX >> 7);
+/// Alignments from `1 << MIN_ALIGN_SHIFT` up to a page get a row in the
+/// table of aligned classes, smaller ones are met by every class
+const MIN_ALIGN_SHIFT: usize = 4;
+
+struct SizeClassTable {
+    name: String,
+    /// Percent of an object or a slab a class may waste
+    max_waste: f64,
+    /// Size and pages per slab of the classes, the zero-sized one included
+    classes: Vec<(usize, usize)>,
 }
 
-static SIZE_ARRAY: [usize; 63] = [
-    8, 16, 24, 32, 40, 48, 56, 64, 72, 80, 88, 96, 104, 112, 120, 128, 144, 160, 176, 192, 208,
-    224, 240, 256, 280, 304, 352, 384, 424, 480, 512, 576, 640, 704, 832, 896, 1024, 1152, 1280,
-    1408, 1536, 1792, 2048, 2176, 2304, 2432, 2944, 3200, 3584, 4096, 4608, 5376, 6528, 8192, 9344,
-    10880, 13056, 13952, 16384, 19072, 21760, 24576, 28032,
-];
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
 
-fn generate_sizeclass() {
+fn lcm(a: usize, b: usize) -> usize {
+    a / gcd(a, b) * b
+}
+
+fn spec_error(line: usize, msg: &str) -> ! {
+    panic!("{}:{}: {}", SIZE_CLASS_SPEC, line + 1, msg)
+}
+
+fn parse_size_classes(spec: &str, page_size: usize) -> Vec<SizeClassTable> {
+    let mut tables: Vec<SizeClassTable> = Vec::new();
+    // `None` takes the fewest pages holding a whole number of objects
+    let mut pages = Some(1);
+    for (no, line) in spec.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            tables.push(SizeClassTable {
+                name: name.trim().to_string(),
+                max_waste: 100.0,
+                classes: vec![(0, 0)],
+            });
+            pages = Some(1);
+            continue;
+        }
+        let table = match tables.last_mut() {
+            Some(table) => table,
+            None => spec_error(no, "expected a `[name]` line first"),
+        };
+        if let Some((key, value)) = line.split_once('=') {
+            match (key.trim(), value.trim()) {
+                ("pages", "lcm") => pages = None,
+                ("pages", n) => {
+                    let n = n
+                        .parse()
+                        .unwrap_or_else(|_| spec_error(no, "bad page count"));
+                    pages = Some(n);
+                }
+                ("max_waste", p) => {
+                    table.max_waste = p.parse().unwrap_or_else(|_| spec_error(no, "bad percent"))
+                }
+                (key, _) => spec_error(no, &format!("unknown setting `{}`", key)),
+            }
+            continue;
+        }
+        for word in line.split_whitespace() {
+            let size: usize = word
+                .parse()
+                .unwrap_or_else(|_| spec_error(no, &format!("bad size `{}`", word)));
+            let prev = table.classes.last().unwrap().0;
+            let granule = if size <= LOOKUP_SMALL_MAX { 8 } else { 128 };
+            if size <= prev || size % granule != 0 {
+                let msg = format!("{} is not a multiple of {} past {}", size, granule, prev);
+                spec_error(no, &msg);
+            }
+            let pages = pages.unwrap_or_else(|| lcm(size, page_size) / page_size);
+            if pages * page_size < size {
+                spec_error(no, &format!("a slab of {} bytes holds no object", size));
+            }
+            table.classes.push((size, pages));
+        }
+    }
+    for table in tables.iter() {
+        let total = table.classes.len();
+        assert!(total <= 64, "too many classes in [{}]", table.name);
+        assert!(total > 1, "no classes in [{}]", table.name);
+    }
+    tables
+}
+
+/// Index of `req` bytes in the lookup table
+fn lookup_index(req: usize) -> usize {
+    if req <= LOOKUP_SMALL_MAX {
+        (req + 7) >> 3
+    } else {
+        ((req + 127) >> 7) + LOOKUP_OFFSET
+    }
+}
+
+/// Largest request of the entry `idx` of the lookup table
+fn lookup_bound(idx: usize) -> usize {
+    if idx <= LOOKUP_SMALL_MAX >> 3 {
+        idx << 3
+    } else {
+        (idx - LOOKUP_OFFSET) << 7
+    }
+}
+
+fn format_array<T: std::fmt::Display>(vals: impl Iterator<Item = T>) -> String {
+    vals.map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Writes the waste of every class of `table` to `report`
+///
+/// A class wastes of its objects what the largest request rounded up to 8
+/// bytes it serves leaves unused, and of its slabs the tail too small for an
+/// object. Returns the classes wasting more than `max_waste` percent.
+fn report_waste(
+    table: &SizeClassTable,
+    page_size: usize,
+    max_waste: f64,
+    report: &mut String,
+) -> Vec<usize> {
+    let mut over = Vec::new();
+    for idx in 1..table.classes.len() {
+        let (size, pages) = table.classes[idx];
+        let prev = table.classes[idx - 1].0;
+        let slab = pages * page_size;
+        let objects = slab / size;
+        let internal = (size - prev - 8) as f64 * 100.0 / size as f64;
+        let tail = (slab - objects * size) as f64 * 100.0 / slab as f64;
+        report.push_str(&format!(
+            "[{}] class {:2}: size {:6}, pages {:3}, objects {:4}, internal {:5.2}%, tail {:5.2}%\n",
+            table.name, idx, size, pages, objects, internal, tail
+        ));
+        if internal > max_waste || tail > max_waste {
+            over.push(size);
+        }
+    }
+    over
+}
+
+/// Generates the tables of `size_classes.txt` along with a report of their
+/// waste, and fails when a class wastes too much
+fn generate_size_classes() {
+    println!("cargo:rerun-if-changed={}", SIZE_CLASS_SPEC);
     let out_dir = env::var_os("OUT_DIR").unwrap();
-    let dest_path = Path::new(&out_dir).join("sizeclass_consts.rs");
-    let mut content = format!("const IDX_NP: [u16;{}] = [ ", SIZE_ARRAY.len());
-    let mut offset_arr = format!("const OFFSET_ARRAY: [u16;{}] = [ ", SIZE_ARRAY.len());
-    let mut offset_limit = format!("const OFFSET_LIMIT: [u16;{}] = [ ", SIZE_ARRAY.len());
-    let mut start = SIZE_ARRAY.len().next_power_of_two() / 4;
-    for (idx, val) in SIZE_ARRAY.iter().enumerate() {
-        let template: (usize, usize, usize) = calculate_val(page_size::get(), *val);
-        let num = template.2 * page_size::get() / *val;
-
-        content.push_str(&*format!("{}", num));
-        offset_arr.push_str(&*format!("{}", start));
-        if num.is_power_of_two() {
-            start += 2 * num;
-        } else {
-            start += 2 * num.next_power_of_two();
+    let page_size = page_size();
+    let spec = fs::read_to_string(SIZE_CLASS_SPEC).expect("cannot read size_classes.txt");
+    let tables = parse_size_classes(&spec, page_size);
+    let max_waste = env_override("UNIALLOC_MAX_CLASS_WASTE").map(|v| {
+        v.parse::<f64>()
+            .expect("UNIALLOC_MAX_CLASS_WASTE must be a percent")
+    });
+
+    let mut report = String::new();
+    let mut failures = Vec::new();
+    for table in tables.iter() {
+        let max_waste = max_waste.unwrap_or(table.max_waste);
+        let over = report_waste(table, page_size, max_waste, &mut report);
+        if !over.is_empty() {
+            failures.push(format!(
+                "[{}] wastes more than {}% in classes {:?}",
+                table.name, max_waste, over
+            ));
         }
 
-        offset_limit.push_str(&*format!("{}", start));
-        if idx != SIZE_ARRAY.len() - 1 {
-            content.push_str(", ");
-            offset_arr.push_str(", ");
-            offset_limit.push_str(", ");
+        let classes = &table.classes;
+        let max_size = classes.last().unwrap().0;
+        let lookup_len = lookup_index(max_size) + 1;
+        let lookup = (0..lookup_len).map(|idx| {
+            let bound = lookup_bound(idx);
+            classes.iter().position(|&(size, _)| size >= bound).unwrap()
+        });
+        let objects = classes
+            .iter()
+            .map(|&(size, pages)| (pages * page_size).checked_div(size).unwrap_or(0));
+        // row by row, the first class from each one on whose size is a
+        // multiple of the alignment of the row, or the number of classes
+        let aligned = (MIN_ALIGN_SHIFT..=page_size.trailing_zeros() as usize).flat_map(|shift| {
+            (0..classes.len()).map(move |idx| {
+                classes[idx..]
+                    .iter()
+                    .position(|&(size, _)| size % (1 << shift) == 0)
+                    .map_or(classes.len(), |n| idx + n)
+            })
+        });
+        let mut content = format!("pub const TOTAL_SIZE_CLASS: usize = {};\n", classes.len());
+        content.push_str(&format!("pub const MAX_SIZE: usize = {};\n", max_size));
+        content.push_str(&format!(
+            "const SIZE_CLASSES: [usize; TOTAL_SIZE_CLASS] = [{}];\n",
+            format_array(classes.iter().map(|&(size, _)| size))
+        ));
+        content.push_str(&format!(
+            "const SIZE_CLASS_PAGES: [usize; TOTAL_SIZE_CLASS] = [{}];\n",
+            format_array(classes.iter().map(|&(_, pages)| pages))
+        ));
+        content.push_str(&format!(
+            "const OBJECTS_PER_SLAB: [usize; TOTAL_SIZE_CLASS] = [{}];\n",
+            format_array(objects)
+        ));
+        content.push_str(&format!(
+            "const CLASS_LOOKUP: [u8; {}] = [{}];\n",
+            lookup_len,
+            format_array(lookup)
+        ));
+        content.push_str(&format!(
+            "const ALIGNED_CLASSES: [u8; {}] = [{}];\n",
+            aligned.clone().count(),
+            format_array(aligned)
+        ));
+        let dest_path = Path::new(&out_dir).join(format!("size_classes_{}.rs", table.name));
+        fs::write(&dest_path, content).unwrap();
+    }
+
+    fs::write(Path::new(&out_dir).join("size_classes_report.txt"), &report).unwrap();
+    if env_override("UNIALLOC_SIZE_CLASS_REPORT").is_some() {
+        for line in report.lines() {
+            println!("cargo:warning={}", line);
         }
     }
-    assert!(start * 8 <= 14 * page_size::get());
-    content.push_str("];\n");
-    offset_arr.push_str("];\n");
-    offset_limit.push_str("];\n");
-    content.push_str(&*offset_arr);
-    content.push_str(&*offset_limit);
-    fs::write(&dest_path, content).unwrap();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
 }
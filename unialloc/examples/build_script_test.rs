@@ -1,5 +1,9 @@
 include!(concat!(env!("OUT_DIR"), "/consts.rs"));
+use unialloc::sysinfo;
 
 fn main() {
-    println!("has rseq: {}", HAS_RSEQ);
+    println!("page size: {}", PAGE_SIZE);
+    println!("os page size: {}", sysinfo::os_page_size());
+    println!("ncpu: {}", sysinfo::ncpu());
+    println!("has rseq: {}", sysinfo::has_rseq());
 }
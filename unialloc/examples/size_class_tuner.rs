@@ -0,0 +1,368 @@
+//! Tunes a table of `size_classes.txt` to a histogram of requested sizes
+//!
+//! ```bash
+//! $ cargo run --features size_histogram --example <app>   # writes unialloc_histogram.txt
+//! $ cargo run --example size_class_tuner -- unialloc_histogram.txt [options]
+//! ```
+//!
+//! Options:
+//!
+//! - `--classes <n>`: classes of the table, the zero-sized one aside (63)
+//! - `--max-size <bytes>`: size of the largest class (28032)
+//! - `--max-waste <percent>`: waste limit of every class, as in
+//!   `size_classes.txt` (25)
+//! - `--page-size <bytes>`: page size of the allocator (4096)
+//! - `--table <name>`: name of the table (tc)
+//! - `--write <path>`: replaces the table in the spec at `path` instead of
+//!   printing it
+//!
+//! The table minimizes the bytes the requests of the histogram waste: the
+//! unused tail of their objects, plus their share of the slab tails. Classes
+//! stay within the waste limit for any request, so sizes the histogram missed
+//! are still served well. Sizes follow the rules of the spec, multiples of 8
+//! up to 1024 and of 128 past it.
+use std::env;
+use std::fs;
+use std::process;
+
+/// Largest number of pages in a slab
+const MAX_PAGES: usize = 64;
+/// Slabs hold at least that many objects, if `MAX_PAGES` allows
+const MIN_OBJECTS: usize = 16;
+
+struct Options {
+    histogram: String,
+    classes: usize,
+    max_size: usize,
+    max_waste: f64,
+    page_size: usize,
+    table: String,
+    write: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: size_class_tuner <histogram> [--classes n] [--max-size bytes] \
+         [--max-waste percent] [--page-size bytes] [--table name] [--write spec]"
+    );
+    process::exit(2)
+}
+
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        histogram: args.next().unwrap_or_else(|| usage()),
+        classes: 63,
+        max_size: 28032,
+        max_waste: 25.0,
+        page_size: 4096,
+        table: String::from("tc"),
+        write: None,
+    };
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let number = || value.parse::<usize>().unwrap_or_else(|_| usage());
+        match flag.as_str() {
+            "--classes" => options.classes = number(),
+            "--max-size" => options.max_size = number(),
+            "--max-waste" => options.max_waste = value.parse().unwrap_or_else(|_| usage()),
+            "--page-size" => options.page_size = number(),
+            "--table" => options.table = value,
+            "--write" => options.write = Some(value),
+            _ => usage(),
+        }
+    }
+    if options.classes == 0 || options.classes > 63 {
+        eprintln!("a table holds 1 to 63 classes");
+        process::exit(2);
+    }
+    options
+}
+
+/// Reads the `size count` lines of a histogram, up to `max_size`
+fn read_histogram(path: &str, max_size: usize) -> Vec<u64> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("cannot read {}: {}", path, e);
+        process::exit(1)
+    });
+    let mut counts = vec![0; max_size + 1];
+    for line in text.lines().filter(|l| !l.starts_with('#')) {
+        let mut words = line.split_whitespace().map(|w| w.parse::<u64>());
+        match (words.next(), words.next()) {
+            (Some(Ok(size)), Some(Ok(count))) => {
+                if (size as usize) <= max_size {
+                    counts[size as usize] += count;
+                }
+            }
+            _ => {
+                eprintln!("bad histogram line: {}", line);
+                process::exit(1);
+            }
+        }
+    }
+    counts
+}
+
+/// Sizes a class may take up to `max_size`, see the rules of the spec
+fn candidates(max_size: usize) -> Vec<usize> {
+    let mut sizes: Vec<usize> = (8..=max_size.min(1024)).step_by(8).collect();
+    sizes.extend((1152..=max_size).step_by(128));
+    sizes
+}
+
+/// Pages of a slab of `size` bytes
+///
+/// The fewest pages holding `MIN_OBJECTS`, or twice as many if that wastes
+/// less per object.
+fn pages_for(size: usize, page_size: usize) -> usize {
+    let mut pages = 1;
+    while pages < MAX_PAGES && pages * page_size < MIN_OBJECTS * size {
+        pages *= 2;
+    }
+    if pages < MAX_PAGES
+        && tail_per_object(size, 2 * pages, page_size) < tail_per_object(size, pages, page_size)
+    {
+        pages *= 2;
+    }
+    pages
+}
+
+fn tail_per_object(size: usize, pages: usize, page_size: usize) -> f64 {
+    let slab = pages * page_size;
+    (slab % size) as f64 / (slab / size) as f64
+}
+
+/// Worst waste of a class of `size` bytes after one of `prev` bytes, in the
+/// terms of `max_waste` in the spec
+fn worst_waste(prev: usize, size: usize, pages: usize, page_size: usize) -> f64 {
+    let internal = (size - prev - 8) as f64 * 100.0 / size as f64;
+    let slab = pages * page_size;
+    let tail = (slab % size) as f64 * 100.0 / slab as f64;
+    internal.max(tail)
+}
+
+/// Prefix sums of the counts and of the requested bytes
+struct Prefix {
+    counts: Vec<f64>,
+    bytes: Vec<f64>,
+}
+
+impl Prefix {
+    fn new(histogram: &[u64]) -> Self {
+        let mut counts = vec![0.0; histogram.len() + 1];
+        let mut bytes = vec![0.0; histogram.len() + 1];
+        for (size, &count) in histogram.iter().enumerate() {
+            counts[size + 1] = counts[size] + count as f64;
+            bytes[size + 1] = bytes[size] + (count as f64) * size as f64;
+        }
+        Self { counts, bytes }
+    }
+
+    /// Bytes wasted by the requests of `prev + 1..=size` bytes in a class of
+    /// `size` bytes
+    fn waste(&self, prev: usize, size: usize, pages: usize, page_size: usize) -> f64 {
+        // the zero-sized requests have their own class
+        let from = prev + 1;
+        let count = self.counts[size + 1] - self.counts[from];
+        let bytes = self.bytes[size + 1] - self.bytes[from];
+        count * size as f64 - bytes + count * tail_per_object(size, pages, page_size)
+    }
+}
+
+/// Picks at most `n` classes minimizing the waste of the histogram, the
+/// largest one of `max_size` bytes
+///
+/// `best[k][j]` is the least waste of `k` classes covering up to the
+/// candidate `j`, and the candidate before it is kept in `from[k][j]`.
+fn tune(histogram: &[u64], options: &Options) -> Option<(Vec<(usize, usize)>, f64)> {
+    let page_size = options.page_size;
+    let prefix = Prefix::new(histogram);
+    // the zero-sized class leads
+    let mut sizes = vec![0];
+    sizes.extend(candidates(options.max_size));
+    let pages: Vec<usize> = sizes
+        .iter()
+        .map(|&s| pages_for(s.max(8), page_size))
+        .collect();
+    let last = sizes.len() - 1;
+    if sizes[last] != options.max_size {
+        eprintln!("the largest class must be a valid size, see size_classes.txt");
+        process::exit(2);
+    }
+
+    let n = options.classes;
+    let mut best = vec![vec![f64::INFINITY; sizes.len()]; n + 1];
+    let mut from = vec![vec![0; sizes.len()]; n + 1];
+    best[0][0] = 0.0;
+    for k in 1..=n {
+        for j in 1..sizes.len() {
+            for i in (0..j).rev() {
+                if worst_waste(sizes[i], sizes[j], pages[j], page_size) > options.max_waste {
+                    // the earlier candidates are further away
+                    break;
+                }
+                if best[k - 1][i].is_infinite() {
+                    continue;
+                }
+                let cost = best[k - 1][i] + prefix.waste(sizes[i], sizes[j], pages[j], page_size);
+                if cost < best[k][j] {
+                    best[k][j] = cost;
+                    from[k][j] = i;
+                }
+            }
+        }
+    }
+
+    let k = (1..=n).min_by(|&a, &b| best[a][last].partial_cmp(&best[b][last]).unwrap())?;
+    let waste = best[k][last];
+    if waste.is_infinite() {
+        return None;
+    }
+    let mut classes = Vec::new();
+    let (mut k, mut j) = (k, last);
+    while j != 0 {
+        classes.push((sizes[j], pages[j]));
+        j = from[k][j];
+        k -= 1;
+    }
+    classes.reverse();
+    Some((classes, waste))
+}
+
+/// Waste of the histogram under `classes`, as `tune` counts it
+fn waste_of(histogram: &[u64], classes: &[(usize, usize)], page_size: usize) -> f64 {
+    let prefix = Prefix::new(histogram);
+    let mut prev = 0;
+    let mut waste = 0.0;
+    for &(size, pages) in classes.iter().filter(|&&(size, _)| size < histogram.len()) {
+        waste += prefix.waste(prev, size, pages, page_size);
+        prev = size;
+    }
+    waste
+}
+
+/// Reads the classes of the table `name` of a spec, if any
+fn read_table(spec: &str, name: &str, page_size: usize) -> Option<Vec<(usize, usize)>> {
+    let header = format!("[{}]", name);
+    let mut lines = spec.lines().map(|l| l.split('#').next().unwrap().trim());
+    lines.find(|&l| l == header)?;
+    let mut classes = Vec::new();
+    let mut pages = Some(1);
+    for line in lines.take_while(|l| !l.starts_with('[')) {
+        match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+            Some(("pages", "lcm")) => pages = None,
+            Some(("pages", n)) => pages = n.parse().ok(),
+            Some(_) => {}
+            None => {
+                for size in line
+                    .split_whitespace()
+                    .filter_map(|w| w.parse::<usize>().ok())
+                {
+                    let lcm = (1..)
+                        .map(|p| p * page_size)
+                        .find(|s| s % size == 0)
+                        .unwrap();
+                    classes.push((size, pages.unwrap_or(lcm / page_size)));
+                }
+            }
+        }
+    }
+    Some(classes)
+}
+
+/// Writes `classes` in the format of the spec
+fn format_table(options: &Options, classes: &[(usize, usize)]) -> String {
+    let mut out = format!("[{}]\nmax_waste = {}\n", options.table, options.max_waste);
+    let mut pages = 0;
+    let mut on_line = 0;
+    for &(size, p) in classes {
+        if p != pages {
+            if on_line > 0 {
+                out.push('\n');
+            }
+            out.push_str(&format!("pages = {}\n", p));
+            pages = p;
+            on_line = 0;
+        } else if on_line == 12 {
+            out.push('\n');
+            on_line = 0;
+        }
+        if on_line > 0 {
+            out.push(' ');
+        }
+        out.push_str(&size.to_string());
+        on_line += 1;
+    }
+    out.push('\n');
+    out
+}
+
+/// Replaces the table of `table` in `spec`, or appends it
+///
+/// The table runs from its header to the comments leading the next one.
+fn replace_table(spec: &str, name: &str, table: &str) -> String {
+    let lines: Vec<&str> = spec.lines().collect();
+    let header = format!("[{}]", name);
+    let start = match lines.iter().position(|l| l.trim() == header) {
+        Some(start) => start,
+        None => return format!("{}\n\n{}", spec.trim_end(), table),
+    };
+    let mut end = lines[start + 1..]
+        .iter()
+        .position(|l| l.trim().starts_with('['))
+        .map_or(lines.len(), |i| start + 1 + i);
+    while end > start + 1 && (lines[end - 1].trim().is_empty() || lines[end - 1].starts_with('#')) {
+        end -= 1;
+    }
+    let mut out = String::new();
+    for line in &lines[..start] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(table);
+    for line in &lines[end..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+fn main() {
+    let options = parse_options();
+    let histogram = read_histogram(&options.histogram, options.max_size);
+    let (classes, waste) = tune(&histogram, &options).unwrap_or_else(|| {
+        eprintln!(
+            "no table of {} classes up to {} bytes stays within {}%",
+            options.classes, options.max_size, options.max_waste
+        );
+        process::exit(1)
+    });
+    let requests: f64 = histogram.iter().map(|&c| c as f64).sum();
+    eprintln!(
+        "{} classes waste {:.0} bytes, {:.2} per request",
+        classes.len(),
+        waste,
+        waste / requests.max(1.0)
+    );
+
+    let table = format_table(&options, &classes);
+    match &options.write {
+        Some(path) => {
+            let spec = fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("cannot read {}: {}", path, e);
+                process::exit(1)
+            });
+            if let Some(old) = read_table(&spec, &options.table, options.page_size) {
+                let old_waste = waste_of(&histogram, &old, options.page_size);
+                eprintln!(
+                    "[{}] wasted {:.0} bytes, {:.2} per request",
+                    options.table,
+                    old_waste,
+                    old_waste / requests.max(1.0)
+                );
+            }
+            fs::write(path, replace_table(&spec, &options.table, &table)).unwrap();
+        }
+        None => print!("{}", table),
+    }
+}
@@ -1,24 +1,218 @@
-use crate::mpmc::{Q512, Q64};
-#[cfg(target_os = "linux")]
-use crate::pal::thread::linux::thread;
-use core::ptr;
-use core::sync::atomic::AtomicPtr;
-use core::time::Duration;
+//! Background maintenance thread
+//!
+//! The thread is opt-in: [`start`] spawns it and [`stop`] joins it. At every
+//! tick it runs a decay pass over the free pages of the backend, asks the
+//! thread caches left idle to flush, flushes the caches of exited threads
+//! nobody took over, returns the idle batches of the transfer caches to their
+//! slabs, runs the tasks posted with [`post`] and refreshes [`stats`].
+//!
+//! The thread is a raw pthread, starting it never goes through the allocator.
+//! It is joined at process exit. A child created by `fork` starts without it,
+//! [`start`] can be called again there.
+use crate::mpmc::Q512;
+use crate::pal::limit;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
 
-const initial_wakeup_time: u64 = 1000; // microsecs
+const IDLE: u8 = 0;
+const RUNNING: u8 = 1;
+const STOPPING: u8 = 2;
 
-static GLOBAL_BG_THREAD: AtomicPtr<Q512<usize>> = AtomicPtr::<Q512<usize>>::new(ptr::null_mut());
-// static WORKER_QUEUE:
+static STATE: AtomicU8 = AtomicU8::new(IDLE);
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(1000);
+/// Whether the exit and fork hooks are registered
+static HOOKS: AtomicBool = AtomicBool::new(false);
+static TASKS: Q512<Task> = Q512::new();
+static STATS: Mutex<Stats> = Mutex::new(Stats::new());
 
-struct Task {}
+// `LOCK` guards `STATE` and `THREAD`, `WAKE` interrupts the sleep of the
+// thread. `TICK` is held during a tick, and by `fork` so that the child never
+// inherits the allocator in the middle of one.
+static mut THREAD: libc::pthread_t = 0;
+static mut LOCK: libc::pthread_mutex_t = libc::PTHREAD_MUTEX_INITIALIZER;
+static mut TICK: libc::pthread_mutex_t = libc::PTHREAD_MUTEX_INITIALIZER;
+static mut WAKE: libc::pthread_cond_t = libc::PTHREAD_COND_INITIALIZER;
 
-// currently it is linux only
-pub fn start_background_thread() {
-    let wakeup_time = initial_wakeup_time;
+/// Work deferred to the background thread, `run` is called with `arg`
+#[derive(Copy, Clone)]
+pub struct Task {
+    pub run: fn(usize),
+    pub arg: usize,
+}
+
+/// Snapshot of the allocator, refreshed at every tick
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes mapped by the allocator
+    pub committed: usize,
+    /// Bytes of dirty free pages in the backend
+    pub dirty: usize,
+    /// Bytes of muzzy free pages in the backend
+    pub muzzy: usize,
+    /// Ticks run so far
+    pub ticks: u64,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            committed: 0,
+            dirty: 0,
+            muzzy: 0,
+            ticks: 0,
+        }
+    }
+}
+
+/// Starts the thread, ticking every `interval_ms`
+///
+/// Returns false if it is already running or cannot be created.
+pub fn start(interval_ms: u64) -> bool {
+    INTERVAL_MS.store(interval_ms.max(1), Ordering::Relaxed);
+    unsafe {
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+        if STATE.load(Ordering::Relaxed) != IDLE {
+            libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+            return false;
+        }
+        if !HOOKS.swap(true, Ordering::Relaxed) {
+            libc::atexit(at_exit);
+            libc::pthread_atfork(Some(before_fork), Some(after_fork), Some(in_child));
+        }
+        let created =
+            libc::pthread_create(ptr::addr_of_mut!(THREAD), ptr::null(), worker, null_mut()) == 0;
+        if created {
+            STATE.store(RUNNING, Ordering::Relaxed);
+        }
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+        created
+    }
+}
+
+/// Stops the thread and waits for it to exit
+///
+/// Returns false if it was not running.
+pub fn stop() -> bool {
+    unsafe {
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+        if STATE.load(Ordering::Relaxed) != RUNNING {
+            libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+            return false;
+        }
+        STATE.store(STOPPING, Ordering::Relaxed);
+        libc::pthread_cond_signal(ptr::addr_of_mut!(WAKE));
+        let thread = THREAD;
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+
+        // a task calling `exit` must not wait for itself
+        if libc::pthread_equal(thread, libc::pthread_self()) == 0 {
+            libc::pthread_join(thread, null_mut());
+        }
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+        STATE.store(IDLE, Ordering::Relaxed);
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+    }
+    true
+}
+
+pub fn is_running() -> bool {
+    STATE.load(Ordering::Relaxed) == RUNNING
+}
+
+/// Posts `task` to run at the next tick
+///
+/// Returns back the `task` if the queue is full. Tasks posted while the
+/// thread is stopped wait for it to start.
+pub fn post(task: Task) -> Result<(), Task> {
+    TASKS.enqueue(task)?;
+    unsafe { libc::pthread_cond_signal(ptr::addr_of_mut!(WAKE)) };
+    Ok(())
+}
+
+/// The statistics of the last tick
+pub fn stats() -> Stats {
+    *STATS.lock()
+}
+
+extern "C" fn worker(_: *mut libc::c_void) -> *mut libc::c_void {
+    unsafe {
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+        while STATE.load(Ordering::Relaxed) == RUNNING {
+            let deadline = deadline(INTERVAL_MS.load(Ordering::Relaxed));
+            libc::pthread_cond_timedwait(
+                ptr::addr_of_mut!(WAKE),
+                ptr::addr_of_mut!(LOCK),
+                &deadline,
+            );
+            if STATE.load(Ordering::Relaxed) != RUNNING {
+                break;
+            }
+            libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+            libc::pthread_mutex_lock(ptr::addr_of_mut!(TICK));
+            tick();
+            libc::pthread_mutex_unlock(ptr::addr_of_mut!(TICK));
+            libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+        }
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+    }
+    null_mut()
+}
+
+fn tick() {
+    crate::freelist::decay::decay();
+    crate::cache::trim_idle();
+    crate::cache::release_detached();
+    (*crate::zone::GLOBAL_ZONE).release_idle_transfers();
+    while let Some(task) = TASKS.dequeue() {
+        (task.run)(task.arg);
+    }
+    let mut stats = STATS.lock();
+    stats.committed = limit::committed();
+    stats.dirty = crate::freelist::decay::dirty_bytes();
+    stats.muzzy = crate::freelist::decay::muzzy_bytes();
+    stats.ticks += 1;
+}
+
+/// The realtime clock `ms` milliseconds from now, as `pthread_cond_timedwait`
+/// takes it
+fn deadline(ms: u64) -> libc::timespec {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+    let nsec = ts.tv_nsec as u64 + (ms % 1000) * 1_000_000;
+    ts.tv_sec += (ms / 1000 + nsec / 1_000_000_000).min(i32::MAX as u64) as libc::time_t;
+    ts.tv_nsec = (nsec % 1_000_000_000) as _;
+    ts
+}
+
+extern "C" fn at_exit() {
+    stop();
+}
+
+extern "C" fn before_fork() {
+    unsafe {
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(TICK));
+        libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK));
+    }
+}
+
+extern "C" fn after_fork() {
+    unsafe {
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK));
+        libc::pthread_mutex_unlock(ptr::addr_of_mut!(TICK));
+    }
+}
 
-    loop {
-        thread::sleep(Duration::from_micros(wakeup_time));
+/// The thread does not exist in the child
+extern "C" fn in_child() {
+    unsafe {
+        WAKE = libc::PTHREAD_COND_INITIALIZER;
+        STATE.store(IDLE, Ordering::Relaxed);
     }
+    after_fork();
 }
 
 #[cfg(test)]
@@ -0,0 +1,244 @@
+//! The malloc family of libc on top of [`UniAlloc`]
+//!
+//! With the `c_api` feature the `cdylib` of this crate exports these symbols,
+//! so C and C++ programs can use the allocator through `LD_PRELOAD`. `free`
+//! and the other functions taking only a pointer find the size class of the
+//! block through the page map (see [`UniAlloc::size_class_of`]).
+//!
+//! Blocks missing from the page map were not handed out by us, e.g. the ones
+//! libc allocates before the library is loaded. `free`, `realloc` and
+//! `malloc_usable_size` forward them to the allocator loaded after us, found
+//! with `dlsym(RTLD_NEXT, ...)`. The process aborts if there is none, as the
+//! block cannot be given back anywhere.
+use crate::pal::sysinfo::os_page_size;
+use crate::prelude::*;
+use crate::zone::GLOBAL_ZONE;
+use crate::UniAlloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::transmute;
+use core::ptr::{self, null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use libc::{c_char, c_int, c_void, size_t};
+
+/// Alignment of `malloc`, the one of `max_align_t`
+const MIN_ALIGN: usize = 16;
+
+/// Functions of the next allocator, resolved on first use
+static NEXT_FREE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_REALLOC: AtomicUsize = AtomicUsize::new(0);
+static NEXT_USABLE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+fn set_errno(err: c_int) {
+    unsafe { *libc::__errno_location() = err };
+}
+
+/// Address of the function `name` of the next allocator, cached in `slot`
+///
+/// `name` ends with a NUL. Aborts if no library loaded after us defines it.
+unsafe fn next_fn(slot: &AtomicUsize, name: &[u8]) -> usize {
+    let mut addr = slot.load(Ordering::Relaxed);
+    if addr == 0 {
+        addr = libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const c_char) as usize;
+        if addr == 0 {
+            // nothing to allocate with here, `write` it is
+            let msg: [&[u8]; 3] = [
+                b"unialloc: no next allocator for the foreign block passed to ",
+                &name[..name.len() - 1],
+                b"\n",
+            ];
+            for part in msg {
+                libc::write(2, part.as_ptr() as *const c_void, part.len());
+            }
+            libc::abort();
+        }
+        slot.store(addr, Ordering::Relaxed);
+    }
+    addr
+}
+
+/// Allocates `size` bytes aligned to `align`, setting `errno` on failure
+unsafe fn alloc(size: usize, align: usize, zeroed: bool) -> *mut c_void {
+    // every `malloc` returns a unique block, even an empty one
+    let layout = match Layout::from_size_align(size.max(1), align.max(MIN_ALIGN)) {
+        Ok(layout) => layout,
+        Err(_) => {
+            set_errno(libc::ENOMEM);
+            return null_mut();
+        }
+    };
+    let ptr = if zeroed {
+        UniAlloc.alloc_zeroed(layout)
+    } else {
+        UniAlloc.alloc(layout)
+    };
+    if ptr.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    ptr as *mut c_void
+}
+
+/// Usable size of a block of class `cls`
+fn usable_size(cls: SizeClass) -> usize {
+    match cls {
+        SizeClass::Base(idx) => get_rounded_size_by_idx(idx),
+        SizeClass::Large(size) => size,
+    }
+}
+
+/// Frees `ptr`, a block of class `cls`
+///
+/// Large blocks go to the zone directly, a layout could take them for small
+/// ones.
+unsafe fn dealloc(ptr: *mut c_void, cls: SizeClass) {
+    let layout = Layout::from_size_align_unchecked(usable_size(cls), 1);
+    match cls {
+        SizeClass::Base(_) => UniAlloc.dealloc(ptr as *mut u8, layout),
+        SizeClass::Large(_) => {
+            (*GLOBAL_ZONE).deallocate_large(NonNull::new_unchecked(ptr as *mut u8), layout)
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    alloc(size, MIN_ALIGN, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(nmemb: size_t, size: size_t) -> *mut c_void {
+    match nmemb.checked_mul(size) {
+        Some(total) => alloc(total, MIN_ALIGN, true),
+        None => {
+            set_errno(libc::ENOMEM);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    match UniAlloc.size_class_of(ptr as *const u8) {
+        Some(cls) => dealloc(ptr, cls),
+        None => {
+            let next: unsafe extern "C" fn(*mut c_void) = transmute(next_fn(&NEXT_FREE, b"free\0"));
+            next(ptr)
+        }
+    }
+}
+
+/// Resizes `ptr`, in place if the block stays between half and all of its
+/// usable size
+///
+/// A large block staying large is resized by the zone, which avoids the copy
+/// when it can.
+///
+/// A block we did not hand out is resized by the next allocator, and stays
+/// there.
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    if size == 0 {
+        free(ptr);
+        return null_mut();
+    }
+    let cls = match UniAlloc.size_class_of(ptr as *const u8) {
+        Some(cls) => cls,
+        None => {
+            let next: unsafe extern "C" fn(*mut c_void, size_t) -> *mut c_void =
+                transmute(next_fn(&NEXT_REALLOC, b"realloc\0"));
+            return next(ptr, size);
+        }
+    };
+    let old_size = usable_size(cls);
+    if size <= old_size && size > old_size / 2 {
+        return ptr;
+    }
+    if let (SizeClass::Large(_), Ok(new_layout)) = (cls, Layout::from_size_align(size, MIN_ALIGN)) {
+        let old_layout = Layout::from_size_align_unchecked(old_size, MIN_ALIGN);
+        let resized = UniAlloc::reallocate_large(ptr as *mut u8, old_layout, new_layout);
+        if let Some(new_ptr) = resized {
+            return new_ptr.as_ptr() as *mut c_void;
+        }
+    }
+    let new_ptr = malloc(size);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old_size.min(size));
+        dealloc(ptr, cls);
+    }
+    new_ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || align % core::mem::size_of::<usize>() != 0 {
+        return libc::EINVAL;
+    }
+    let ptr = alloc(size, align, false);
+    if ptr.is_null() {
+        return libc::ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_errno(libc::EINVAL);
+        return null_mut();
+    }
+    alloc(size, align, false)
+}
+
+/// Like [`aligned_alloc`], but rounds `align` up to a power of two as glibc
+/// does
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    match align.checked_next_power_of_two() {
+        Some(align) => alloc(size, align, false),
+        None => {
+            set_errno(libc::EINVAL);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void {
+    alloc(size, os_page_size(), false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    match size.checked_add(os_page_size() - 1) {
+        Some(rounded) => alloc(rounded & !(os_page_size() - 1), os_page_size(), false),
+        None => {
+            set_errno(libc::ENOMEM);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    if ptr.is_null() {
+        return 0;
+    }
+    match UniAlloc.usable_size(ptr as *const u8) {
+        Some(size) => size,
+        None => {
+            let next: unsafe extern "C" fn(*mut c_void) -> size_t =
+                transmute(next_fn(&NEXT_USABLE_SIZE, b"malloc_usable_size\0"));
+            next(ptr)
+        }
+    }
+}
@@ -5,58 +5,37 @@
 //!
 //! For linux-kernelspace, we directly utilize the Per-CPU variable along with
 //! manipulation of preempt (e.g., preempt_disable() and preempt_enable)
+//!
+//! The frontend is selected when the process allocates for the first time:
+//! if the rseq area of that thread can be registered, every thread goes
+//! through the per-CPU cache. Threads failing to register their own area,
+//! and requests the cache cannot serve (e.g., over-aligned ones), fall back
+//! to the thread cache.
 
-use crate::collections::concurrent::PerCpuArray;
+use super::remote::NO_OWNER;
 use crate::error::{AllocError, Result};
 use crate::pal::os::rseq::*;
+use crate::pal::sys_alloc as system_alloc;
+use crate::pal::sysinfo;
 use crate::prelude::*;
-use crate::zone::ZoneAllocator;
 use crate::zone::GLOBAL_ZONE;
 use crate::*;
-use alloc::alloc::{Allocator, GlobalAlloc, Layout};
-use buddy_system::BuddySystemAllocator as Arena;
+use alloc::alloc::Layout;
+use core::arch::asm;
 use core::mem;
-use core::ptr::{self, write_bytes, NonNull};
-use core::slice;
-use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
-const NUM_CLASSES: usize = 63;
-const CCACHE_SIZE: usize = 512;
+const NUM_CLASSES: usize = TOTAL_SIZE_CLASS;
 const SHIFT: usize = 18;
 const SLAB_SIZE: usize = 1usize << SHIFT;
 const HDR_SIZE: usize = mem::size_of::<[AtomicUsize; NUM_CLASSES]>();
-#[thread_local]
-static mut CWND: usize = 64;
-
-/// Initializes a non-copy array utilizing `MaybeUninit`.
-///
-/// # Examples
-///
-/// ```rust,no_run
-/// array_init!{AtomicUsize, NUM_CLASSES, AtomicUsize::new(0usize)}
-/// ```
-macro_rules! array_init {
-    ($ty:ty, $len:expr, $constructor:expr) => {{
-        // Create an uninitialized array of `MaybeUninit`. The `assume_init` is
-        // safe because the type we are claiming to have initialized here is a
-        // bunch of `MaybeUninit`s, which do not require initialization.
-        let mut data: [core::mem::MaybeUninit<$ty>; $len] =
-            unsafe { core::mem::MaybeUninit::uninit().assume_init() };
-
-        // Dropping a `MaybeUninit` does nothing. Thus using raw pointer
-        // assignment instead of `ptr::write` does not cause the old
-        // uninitialized value to be dropped. Also if there is a panic during
-        // this loop, we have a memory leak, but there is no memory safety
-        // issue.
-        for elem in &mut data[..] {
-            *elem = core::mem::MaybeUninit::new($constructor);
-        }
+/// Maximum number of objects one CPU caches for a size class
+const MAX_CAPACITY: usize = 500;
 
-        // Everything is initialized. Transmute the array to the
-        // initialized type.
-        unsafe { core::mem::transmute::<_, [$ty; $len]>(data) }
-    }};
-}
+// Headers keep 16 bits offsets, and the slots of all classes share one slab
+const _: () = assert!(HDR_SIZE + MAX_CAPACITY * NUM_CLASSES * mem::size_of::<usize>() <= SLAB_SIZE);
+const _: () = assert!(SLAB_SIZE / mem::size_of::<usize>() <= u16::MAX as usize);
 
 /// # Note
 ///
@@ -138,7 +117,7 @@ impl core::convert::From<Header> for usize {
 }
 
 /// A Per-CPU slab for one CPU
-/// We gonna have an array of PerCPUSlabs with NCPU members
+/// We gonna have an array of PerCPUSlabs, one per possible CPU
 /// size: 1 << SHIFT
 #[repr(C, align(16))]
 struct CPUSlab {
@@ -146,56 +125,75 @@ struct CPUSlab {
     mem: [usize; (SLAB_SIZE - HDR_SIZE) / mem::size_of::<usize>()],
 }
 
-impl CPUSlab {
-    pub fn new() -> Self {
-        Self {
-            header: array_init! {AtomicUsize, NUM_CLASSES, AtomicUsize::new(0usize)},
-            mem: [0usize; (SLAB_SIZE - HDR_SIZE) / mem::size_of::<usize>()],
-        }
-    }
-}
-
-#[repr(C, align(16))]
 pub struct PerCpuSlabs {
-    slabs: [CPUSlab; NCPU],
+    /// `ncpu` consecutive slabs, indexed by the cpu id of rseq
+    slabs: *mut CPUSlab,
+    ncpu: usize,
 }
 
 impl PerCpuSlabs {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            slabs: array_init! {CPUSlab, NCPU, CPUSlab::new()},
+            slabs: null_mut(),
+            ncpu: 0,
         }
     }
 
-    pub fn init(&self) {
-        for i in 0..NCPU {
+    /// Maps the slabs of all possible CPUs
+    ///
+    /// Returns false if the memory cannot be mapped
+    pub fn init(&mut self) -> bool {
+        let ncpu = sysinfo::ncpu();
+        let size = ncpu * SLAB_SIZE;
+        let prot = system_alloc::prots::get_prot(true, true, false);
+        let ptr = unsafe { system_alloc::mmap(size, prot) };
+        // mmap returns MAP_FAILED (-1) on failure
+        if ptr as usize == usize::MAX || ptr.is_null() {
+            return false;
+        }
+        self.slabs = ptr as *mut CPUSlab;
+        self.ncpu = ncpu;
+        for i in 0..self.ncpu {
             self.init_cpu(i);
         }
+        true
     }
 
+    fn slab(&self, cpu: usize) -> &CPUSlab {
+        assert!(cpu < self.ncpu);
+        unsafe { &*self.slabs.add(cpu) }
+    }
+
+    /// The number of objects cached per CPU for size class `cl`
+    ///
+    /// Like the thread cache, it is two slabs worth of objects
     #[inline]
-    pub fn get_capacity(_cl: usize) -> usize {
-        500
+    pub fn get_capacity(cl: usize) -> usize {
+        if cl == 0 {
+            return 0;
+        }
+        let per_slab = get_objects_per_slab_by_idx(cl);
+        core::cmp::min(MAX_CAPACITY, per_slab * 2)
     }
 
     /// init_cpu will be called upon updating capacity
     pub fn init_cpu(&self, cpu: usize) {
+        let slab = self.slab(cpu);
         // 1. stop concurrent mutation
         for cl in 0..NUM_CLASSES {
             // check whether the current size class is locked
-            let mut hdr: Header = self.slabs[cpu].header[cl].load(Ordering::Relaxed).into();
+            let mut hdr: Header = slab.header[cl].load(Ordering::Relaxed).into();
             if hdr.is_locked() {
                 panic!("CPU[{}] is locked", cpu);
             }
             // locking the current size class
             hdr.lock();
-            self.slabs[cpu].header[cl].store(hdr.into(), Ordering::Relaxed);
+            slab.header[cl].store(hdr.into(), Ordering::Relaxed);
         }
 
         // calculate the header's size
-        let slabs = &self.slabs as *const _ as usize;
-        let slab_addr = slabs + (cpu << SHIFT);
-        let mut byte_used = mem::size_of::<[AtomicUsize; NUM_CLASSES]>();
+        let slab_addr = self.slabs as usize + (cpu << SHIFT);
+        let mut byte_used = HDR_SIZE;
 
         // 2. initialize prefetch targets
         for cl in 0..NUM_CLASSES {
@@ -229,35 +227,24 @@ impl PerCpuSlabs {
             // update header
             let hdr = Header::new(current, end_copy, begin, end);
             // 3. allowing access the current cache
-            self.slabs[cpu].header[cl].store(hdr.into(), Ordering::Relaxed);
+            slab.header[cl].store(hdr.into(), Ordering::Relaxed);
         }
         assert!(
             byte_used <= (1 << SHIFT),
             "[INIT_PER_CPU]: size overflowed! byte_used: 0x{:x}, max: 0x{:x}, header: 0x{:x}",
             byte_used,
             1 << SHIFT,
-            mem::size_of::<[AtomicUsize; NUM_CLASSES]>(),
+            HDR_SIZE,
         );
     }
 
-    #[cfg(not(rseq))]
-    pub fn pop(&self, _cl: usize) -> Result<NonNull<u8>, usize> {
-        panic!("rseq on non-linux platform is not supported yet");
-    }
-
-    #[cfg(not(rseq))]
-    pub fn push(&self, _ptr: NonNull<u8>, _cl: usize) -> Result<(), usize> {
-        panic!("rseq on non-linux platform is not supported yet");
-    }
-
-    #[cfg(rseq)]
     #[inline(never)]
     pub fn pop(&self, cl: usize) -> core::result::Result<NonNull<u8>, usize> {
         let scratch: usize;
         let before: usize;
         let current: usize; //after
         let result: *mut u8;
-        let slab_addr = &self.slabs as *const _ as usize;
+        let slab_addr = self.slabs as usize;
 
         unsafe {
             asm!(
@@ -323,7 +310,7 @@ impl PerCpuSlabs {
                 // commit
                 "5:",
                 signature = const 0x53053053,
-                rseq = in(reg) &RSEQ_ABI as *const _ as usize,
+                rseq = in(reg) rseq_area() as usize,
                 cs_offset = const 8,
                 cpu_id_offset = const 4,
                 shift = const SHIFT,
@@ -348,7 +335,7 @@ impl PerCpuSlabs {
             // we do not want to wrongly handle another cpu cache
             let offset = scratch - slab_addr;
             let cpu_id = offset / SLAB_SIZE;
-            debug_assert!(cpu_id <= NCPU);
+            debug_assert!(cpu_id < self.ncpu);
             Err(cpu_id)
         } else {
             // println!("0x{:x}", result as usize);
@@ -357,12 +344,11 @@ impl PerCpuSlabs {
         }
     }
 
-    #[cfg(rseq)]
     pub fn push(&self, ptr: NonNull<u8>, cl: usize) -> core::result::Result<(), usize> {
         let r11: usize;
         let before: usize;
         let scratch: usize;
-        let slab_addr = &self.slabs as *const _ as usize;
+        let slab_addr = self.slabs as usize;
         unsafe {
             asm!(
                 // building the rseq cs table
@@ -447,11 +433,11 @@ impl PerCpuSlabs {
                 "5:", // overflow handler
                 // "movzx r10d, word ptr [r10 + {cl}*8 + 6]",
                 signature = const 0x53053053,
-                rseq = in(reg) &RSEQ_ABI as *const _ as usize,
+                rseq = in(reg) rseq_area() as usize,
                 cs_offset = const 8,
                 cpu_id_offset = const 4,
                 shift = const SHIFT,
-                slabs = in(reg) &self.slabs as *const _ as usize,
+                slabs = in(reg) slab_addr,
                 cl = in(reg) cl, // size class index
                 item = in(reg) ptr.as_ptr() as usize, // pointer
                 before = out(reg) before,
@@ -470,47 +456,6 @@ impl PerCpuSlabs {
         }
     }
 
-    /// Refilling the CpuCache by allocating a batch of objects from central
-    /// freelist
-    ///
-    /// Returns the next idx
-    pub fn push_batch(
-        &self,
-        batch: &mut [usize],
-        cl: usize,
-        len: usize,
-        start_idx: usize,
-    ) -> usize {
-        // n: start index
-        //change, new impl will ret the ans as the end of the array
-        let mut n = start_idx;
-        while n < len {
-            let ptr = unsafe { NonNull::new_unchecked(batch[n - start_idx] as *mut u8) };
-            let res = self.push(ptr, cl);
-            if res.is_ok() {
-                n += 1;
-            } else {
-                break;
-            }
-        }
-        n
-    }
-
-    /// Returns a batch of objects to central freelist
-    pub fn pop_batch(&self, batch: &mut [usize], cl: usize, len: usize, start_idx: usize) -> usize {
-        let mut n = start_idx;
-        while n < len {
-            let ptr = self.pop(cl);
-            if let Ok(p) = ptr {
-                batch[n] = p.as_ptr() as usize;
-                n += 1;
-            } else {
-                break;
-            }
-        }
-        n
-    }
-
     fn get_cpu_id() -> usize {
         let id = cpu_id();
         assert!(id >= 0);
@@ -519,269 +464,207 @@ impl PerCpuSlabs {
 }
 
 impl PerCpuSlabs {
+    /// Allocates from the cache of the current CPU
+    ///
+    /// The current thread must have registered its rseq area. Returns
+    /// `ELAYOUT` if the cached objects do not meet the alignment.
     pub fn allocate(&self, layout: Layout) -> Result<NonNull<u8>> {
-        // Step 0: check and register the rseq
-        register_current_thread_checked();
-
         // Step 1: get size class
-        let cls = get_size_class(layout.size());
-        let align = layout.align();
+        let cls = get_size_class_by_layout(layout);
 
         if let SizeClass::Base(idx) = cls {
-            // Step 2: try to allocate from cpu cache
-            let result = self.pop(cls.index());
-
-            // Step 3: check underflow
-            if let Err(cpu) = result {
-                // Underflow
-                // There is no pointer in the requested cpu cache
-
-                // refill the corresponding cpu cache
-                if let Some(ptr) = self.refill_cache(cpu, idx) {
-                    return Ok(NonNull::new(ptr as *mut _).expect("The refill process has error"));
-                } else {
-                    return Self::alloc_from_zone(layout.size(), align);
-                }
+            if unlikely(idx == 0) {
+                return Ok(super::zero_sized(layout));
             }
-
-            // Step 4: check alignment
-            let inner_ptr = result.expect("cannot get result");
-            if (inner_ptr.as_ptr() as usize) & (layout.align() - 1) != 0 {
-                // Stage 0: push the unaligned chunk back
-                //
-                // TODO: Refactoring the push and pop function for allowing
-                // a error handler get passed into it.
-                self.deallocate(inner_ptr, layout);
-
-                return Self::alloc_from_zone(layout.size(), align);
+            // Step 2: try to allocate from cpu cache, refill it on underflow
+            let ptr = match self.pop(idx) {
+                Ok(ptr) => ptr,
+                Err(_) => self.refill_cache(idx)?,
+            };
+
+            // Step 3: check alignment
+            if unlikely(ptr.as_ptr() as usize & (layout.align() - 1) != 0) {
+                self.deallocate(ptr, layout)?;
+                return Err(AllocError::ELAYOUT);
             }
-
-            return Ok(inner_ptr);
-        } else if let SizeClass::Large(_) = cls {
-            return Self::alloc_from_zone(layout.size(), align);
+            Ok(ptr)
+        } else {
+            (*GLOBAL_ZONE).allocate_large(layout)
         }
-
-        println!("{}", layout.size());
-
-        Err(AllocError::EFATAL)
     }
 
-    pub fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        // Step 0: check the register the rseq
-        register_current_thread_checked();
-
+    /// Frees to the cache of the current CPU
+    ///
+    /// Fails only if the zone refuses the objects evicted on overflow.
+    pub fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<()> {
         // Step 1: get size class
-        let cls = get_size_class(layout.size());
+        let cls = get_size_class_by_layout(layout);
 
         if let SizeClass::Base(idx) = cls {
-            // Step 2: try to deallocate to the cpu c
-            let result = self.push(ptr, idx);
-
-            // Step 3: check overflow
-            if let Err(cpu) = result {
-                // Overflow!
-                // TODO: we need to return a batch of chunk to the zone
-
-                let evict_res = self.evict_cache(ptr, cpu, idx);
-                if evict_res.is_none() {
-                    Self::dealloc_to_zone(ptr, layout.size());
-                }
+            // a zero-sized block, see `zero_sized`
+            if unlikely(idx == 0) {
+                return Ok(());
             }
-        } else if let SizeClass::Large(_) = cls {
-            Self::dealloc_to_zone(ptr, layout.size());
+            // Step 2: try to deallocate to the cpu cache, evict half of it
+            // on overflow
+            if self.push(ptr, idx).is_err() {
+                return self.evict_cache(ptr, idx);
+            }
+        } else {
+            (*GLOBAL_ZONE).deallocate_large(ptr, layout);
         }
+        Ok(())
     }
 
-    /// Refills the cache for specific cpu for size class cl
+    /// Refills the cache of the current cpu for size class `idx` with a
+    /// batch from the zone
     ///
-    /// The tricky part is that we cannot guarantee whether we are
-    /// migrated to a different CPU
+    /// The thread may migrate while pushing, so the batch can be split
+    /// between two CPUs. What does not fit goes back to the zone.
     ///
     /// Upon success, return the first one for immediate usage
-    pub fn refill_cache(&self, cpu: usize, idx: usize) -> Option<usize> {
-        let mut batch = [0usize; 512];
-        let batch_size = unsafe { CWND };
-        let n: usize;
-
-        // We cannot precisely control which cache we are pushing
-        if cpu_id() as usize == cpu {
-            let ans = (*GLOBAL_ZONE).allocate_batch_from_slab(idx, &mut batch, batch_size);
-            if ans.is_ok() {
-                n = self.push_batch(&mut batch, idx, batch_size, 1);
-                if n < batch_size {
-                    (*GLOBAL_ZONE)
-                        .deallocate_batch_to_slab(idx, &mut batch[n..], batch_size - n)
-                        .expect("free to slab failed");
+    pub fn refill_cache(&self, idx: usize) -> Result<NonNull<u8>> {
+        let (first, count, stride, _) = (*GLOBAL_ZONE).allocate_batch_from_slab(idx, NO_OWNER)?;
+        if let Some(stride) = stride {
+            let mut n = 1;
+            while n < count {
+                let ptr = unsafe { NonNull::new_unchecked(first.add(n * stride)) };
+                if self.push(ptr, idx).is_err() {
+                    break;
                 }
-                return Some(batch[0]);
-            } else {
-                return None;
+                n += 1;
+            }
+            if n < count {
+                // link the rest for the zone
+                for i in n..count {
+                    let next = if i + 1 < count {
+                        first as usize + (i + 1) * stride
+                    } else {
+                        0
+                    };
+                    unsafe { *(first.add(i * stride) as *mut usize) = next };
+                }
+                Self::return_rest(idx, first, unsafe { first.add(n * stride) }, count - n)?;
             }
-        }
-        None
-    }
-
-    pub fn evict_cache(&self, ptr: NonNull<u8>, cpu: usize, idx: usize) -> Option<()> {
-        let mut batch = [0usize; 512];
-        let batch_size = unsafe { CWND };
-        let n: usize;
-
-        if cpu_id() as usize == cpu {
-            batch[0] = ptr.as_ptr() as usize;
-            n = self.pop_batch(&mut batch, idx, batch_size - 1, 1);
-            (*GLOBAL_ZONE)
-                .deallocate_batch_to_slab(idx, &mut batch, n)
-                .expect("free to slab failed");
-            Some(())
         } else {
-            None
+            let mut cur = unsafe { *(first as *mut usize) };
+            let mut n = 1;
+            while cur != 0 {
+                let next = unsafe { *(cur as *mut usize) };
+                if self
+                    .push(unsafe { NonNull::new_unchecked(cur as *mut u8) }, idx)
+                    .is_err()
+                {
+                    break;
+                }
+                cur = next;
+                n += 1;
+            }
+            if cur != 0 {
+                Self::return_rest(idx, first, cur as *mut u8, count - n)?;
+            }
         }
+        Ok(NonNull::new(first).expect("err"))
     }
 
-    /// A simple trampoline to zone
-    #[inline]
-    fn alloc_from_zone(sz: usize, align: usize) -> Result<NonNull<u8>> {
-        (*GLOBAL_ZONE).allocate(sz, align)
-    }
-
-    /// A simple trampoline to zone
-    #[inline]
-    fn dealloc_to_zone(ptr: NonNull<u8>, sz: usize) {
-        (*GLOBAL_ZONE).deallocate(ptr, sz);
+    /// Gives the list `rest` of `count` objects of a refill back to the zone
+    ///
+    /// If the zone refuses it, the refill fails and `first`, which the
+    /// caller will not get, is handed back together with the rest.
+    fn return_rest(idx: usize, first: *mut u8, rest: *mut u8, count: usize) -> Result<()> {
+        (*GLOBAL_ZONE)
+            .deallocate_batch_to_slab(idx, rest, count)
+            .map_err(|e| {
+                unsafe { *(first as *mut usize) = rest as usize };
+                // refused twice, the objects stay leaked
+                let _ = (*GLOBAL_ZONE).deallocate_batch_to_slab(idx, first, count + 1);
+                e
+            })
+    }
+
+    /// Returns `ptr` and half of the cache of the current cpu for size class
+    /// `idx` to the zone
+    pub fn evict_cache(&self, ptr: NonNull<u8>, idx: usize) -> Result<()> {
+        let mut head = ptr.as_ptr() as usize;
+        let mut count = 1;
+        unsafe { *(head as *mut usize) = 0 };
+        for _ in 0..Self::get_capacity(idx) / 2 {
+            if let Ok(p) = self.pop(idx) {
+                unsafe { *(p.as_ptr() as *mut usize) = head };
+                head = p.as_ptr() as usize;
+                count += 1;
+            } else {
+                break;
+            }
+        }
+        (*GLOBAL_ZONE).deallocate_batch_to_slab(idx, head as *mut u8, count)
     }
 }
 
-// atomic_static! {
-//     pub static ref GLOBAL_CCACHE: PerCpuSlabs = {
-//         let slab = Box::new_in(PerCpuSlabs::new(), GlobalBackend);
-//         slab.init();
-//         slab
-//     };
-// }
-
-static GLOBAL_CCACHE_VALUE: core::sync::atomic::AtomicPtr<PerCpuSlabs> =
-    core::sync::atomic::AtomicPtr::new(core::ptr::null_mut());
+/// The frontend has not been selected yet
+const UNDECIDED: u8 = 0;
+/// A thread is selecting the frontend
+const SELECTING: u8 = 1;
+/// The per-CPU cache is used
+const PER_CPU: u8 = 2;
+/// The thread cache is used
+const PER_THREAD: u8 = 3;
 
-#[allow(non_camel_case_types)]
-pub struct GLOBAL_CCACHE;
+static FRONTEND: AtomicU8 = AtomicU8::new(UNDECIDED);
 
-impl core::ops::Deref for GLOBAL_CCACHE {
-    type Target = PerCpuSlabs;
-
-    fn deref(&self) -> &PerCpuSlabs {
-        extern crate alloc;
-        use alloc::alloc::GlobalAlloc;
-        use alloc::boxed::Box;
+/// Frontend of the current thread, `UNDECIDED` until its first request
+#[thread_local]
+static mut THREAD_FRONTEND: u8 = UNDECIDED;
 
-        let ptr: *mut PerCpuSlabs = GLOBAL_CCACHE_VALUE.load(core::sync::atomic::Ordering::Acquire);
-        if !ptr.is_null() {
-            return unsafe { ptr.as_ref().unwrap() };
-        }
+/// Set up once the per-CPU frontend is selected, read-only afterwards
+static mut GLOBAL_CCACHE: PerCpuSlabs = PerCpuSlabs::new();
 
-        // let boxed_ptr:Box<PerCpuSlabs, GlobalBackend> =
-        //                 Box::new_in(
-        //                     PerCpuSlabs::new()
-        //                     , GlobalBackend);
-        // boxed_ptr.init();
-        // let init_ptr = Box::into_raw(boxed_ptr);
-
-        let layout = alloc::alloc::Layout::new::<PerCpuSlabs>();
-        let init_ptr = unsafe { GlobalBackend.alloc(layout) as *mut PerCpuSlabs };
-        let slab: &PerCpuSlabs = unsafe { &*(init_ptr as *const _) };
-        slab.init();
-
-        if let Err(p) = GLOBAL_CCACHE_VALUE.compare_exchange(
-            core::ptr::null_mut(),
-            init_ptr,
-            core::sync::atomic::Ordering::AcqRel,
-            core::sync::atomic::Ordering::Relaxed,
-        ) {
-            if !p.is_null() {
-                unsafe {
-                    // Box::from_raw_in(init_ptr as *mut PerCpuSlabs, GlobalBackend);
-                    GlobalBackend.dealloc(init_ptr as *mut u8, layout);
-                    return p.as_ref().unwrap();
-                }
-            }
-        }
-        unsafe { init_ptr.as_ref().unwrap() }
+/// Returns the per-CPU cache if the current thread can use it
+#[inline]
+pub fn get_ccache() -> Option<&'static PerCpuSlabs> {
+    match unsafe { THREAD_FRONTEND } {
+        PER_CPU => Some(unsafe { &GLOBAL_CCACHE }),
+        PER_THREAD => None,
+        _ => select_slow(),
     }
 }
 
-impl core::ops::DerefMut for GLOBAL_CCACHE {
-    fn deref_mut(&mut self) -> &mut PerCpuSlabs {
-        extern crate alloc;
-        use alloc::alloc::GlobalAlloc;
-        use alloc::boxed::Box;
+fn register_checked() -> bool {
+    is_registered() || try_register_current_thread()
+}
 
-        let ptr: *mut PerCpuSlabs = GLOBAL_CCACHE_VALUE.load(core::sync::atomic::Ordering::Acquire);
-        if !ptr.is_null() {
-            return unsafe { ptr.as_mut().unwrap() };
-        }
-        // let boxed_ptr: Box<PerCpuSlabs, GlobalBackend> =
-        //     Box::new_in(PerCpuSlabs::new(), GlobalBackend);
-        // boxed_ptr.init();
-        // let init_ptr = Box::into_raw(boxed_ptr);
-
-        let layout = alloc::alloc::Layout::new::<PerCpuSlabs>();
-        let init_ptr = unsafe { GlobalBackend.alloc(layout) as *mut PerCpuSlabs };
-        let slab: &PerCpuSlabs = unsafe { &*(init_ptr as *const _) };
-        slab.init();
-
-        if let Err(p) = GLOBAL_CCACHE_VALUE.compare_exchange(
-            core::ptr::null_mut(),
-            init_ptr,
-            core::sync::atomic::Ordering::AcqRel,
-            core::sync::atomic::Ordering::Relaxed,
-        ) {
-            if !p.is_null() {
-                unsafe {
-                    // Box::from_raw_in(init_ptr as *mut PerCpuSlabs, GlobalBackend);
-                    GlobalBackend.dealloc(init_ptr as *mut u8, layout);
-                    return p.as_mut().unwrap();
+#[cold]
+fn select_slow() -> Option<&'static PerCpuSlabs> {
+    let selected = loop {
+        match FRONTEND.load(Ordering::Acquire) {
+            PER_CPU => break register_checked(),
+            PER_THREAD => break false,
+            UNDECIDED => {
+                if FRONTEND
+                    .compare_exchange(UNDECIDED, SELECTING, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    let selected = sysinfo::has_rseq()
+                        && register_checked()
+                        && unsafe { GLOBAL_CCACHE.init() };
+                    let frontend = if selected { PER_CPU } else { PER_THREAD };
+                    FRONTEND.store(frontend, Ordering::Release);
+                    break selected;
                 }
             }
+            _ => core::hint::spin_loop(),
         }
-        unsafe { init_ptr.as_mut().unwrap() }
-    }
-}
-
-#[derive(Copy, Clone)]
-pub struct CpuCache;
-
-unsafe impl GlobalAlloc for CpuCache {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Ok(result) = (*GLOBAL_CCACHE).allocate(layout) {
-            result.as_ptr()
-        } else {
-            ptr::null_mut()
-        }
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let ptr = NonNull::new(ptr).expect("ptr is null!");
-        (*GLOBAL_CCACHE).deallocate(ptr, layout);
-    }
-}
-
-unsafe impl Allocator for CpuCache {
-    fn allocate(
-        &self,
-        layout: Layout,
-    ) -> core::result::Result<NonNull<[u8]>, alloc::alloc::AllocError> {
-        unsafe {
-            let p = self.alloc(layout);
-            Ok(NonNull::new(slice::from_raw_parts_mut(p, layout.size()))
-                .expect("CPU Cache cannot allocate"))
-        }
-    }
-
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        (*GLOBAL_CCACHE).deallocate(ptr, layout);
+    };
+    if selected {
+        unsafe { THREAD_FRONTEND = PER_CPU };
+        Some(unsafe { &GLOBAL_CCACHE })
+    } else {
+        unsafe { THREAD_FRONTEND = PER_THREAD };
+        None
     }
 }
 
-#[cfg(all(test, rseq))]
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::*;
@@ -791,6 +674,24 @@ mod tests {
     use std::time;
     use std::vec::Vec;
 
+    /// Returns the per-CPU cache, or `None` with a note that `test` is
+    /// skipped
+    ///
+    /// Only skips if the kernel lacks rseq or it is turned off, fails if the
+    /// per-CPU frontend was not selected anyway.
+    fn ccache_or_skip(test: &str) -> Option<&'static PerCpuSlabs> {
+        let ccache = get_ccache();
+        if ccache.is_none() {
+            assert!(
+                !crate::pal::sysinfo::rseq_expected(),
+                "{}: the kernel has rseq but the per-CPU frontend is not in use",
+                test
+            );
+            std::eprintln!("{}: skipped, rseq is unavailable", test);
+        }
+        ccache
+    }
+
     #[test]
     fn size_tests() {
         assert_eq!(mem::size_of::<AtomicUsize>(), 8);
@@ -810,53 +711,69 @@ mod tests {
     }
 
     #[test]
-    fn get_cpu_slab_test() {
-        register_current_thread_checked();
+    fn select_frontend_test() {
+        if ccache_or_skip("select_frontend_test").is_some() {
+            assert!(is_registered());
+            assert_eq!(FRONTEND.load(Ordering::Relaxed), PER_CPU);
+            assert!(PerCpuSlabs::get_cpu_id() < unsafe { GLOBAL_CCACHE.ncpu });
+        }
     }
 
-    // #[test]
-    // fn cpucache_push_test() {
-    //     let layout = Layout::from_size_align(2usize.pow(13), 8).expect("It does not work");
-    //     let ptr0 = unsafe { Arena.alloc(layout) };
-    //     let ptr = NonNull::new(ptr0).expect("cannot create");
-    //     let before = ptr0 as usize;
-
-    //     register_current_thread_checked();
-    //     let res = (*GLOBAL_CCACHE).push(ptr, 49);
-    //     assert!(res.is_ok(), "Overflow occurred after Push!");
-    //     // for i in 0..NCPU {
-    //     // slab.check_header(i, 49);
-    //     // }
-    //     let res2 = slab.pop(49).expect("Pop underflowed");
-    //     let after = res2.as_ptr() as usize;
-    //     assert_eq!(before, after);
-    // }
-
     #[test]
     fn ccache_alloc_test() {
+        let ccache = match ccache_or_skip("ccache_alloc_test") {
+            Some(ccache) => ccache,
+            None => return,
+        };
         let mut v = Vec::new();
         for i in 0..20 {
             let layout = Layout::from_size_align(8 * i, 8).expect("");
-            let ptr = (*GLOBAL_CCACHE).allocate(layout).expect("");
+            let ptr = ccache.allocate(layout).expect("");
             v.push((ptr, 8 * i));
         }
 
         for i in v {
             let layout = Layout::from_size_align(i.1, 8).expect("");
-            (*GLOBAL_CCACHE).deallocate(i.0, layout);
+            ccache.deallocate(i.0, layout).expect("");
+        }
+    }
+
+    #[test]
+    fn ccache_refill_evict_test() {
+        let ccache = match ccache_or_skip("ccache_refill_evict_test") {
+            Some(ccache) => ccache,
+            None => return,
+        };
+        let layout = Layout::from_size_align(64, 8).expect("");
+        let v: Vec<_> = (0..10000)
+            .map(|_| ccache.allocate(layout).expect(""))
+            .collect();
+        let mut sorted: Vec<_> = v.iter().map(|p| p.as_ptr() as usize).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), v.len());
+        for ptr in v {
+            ccache.deallocate(ptr, layout).expect("");
         }
     }
 
     #[test]
     fn cacache_alloc_multithread_test() {
+        if ccache_or_skip("cacache_alloc_multithread_test").is_none() {
+            return;
+        }
         let mut v = Vec::new();
         for _ in 0..1000 {
             let handle = thread::spawn(move || {
+                let ccache = match get_ccache() {
+                    Some(ccache) => ccache,
+                    None => return,
+                };
                 let layout = Layout::from_size_align(8, 8).expect("");
-                let res = (*GLOBAL_CCACHE).allocate(layout).expect("");
+                let res = ccache.allocate(layout).expect("");
                 let t = time::Duration::from_nanos(3);
                 thread::sleep(t);
-                (*GLOBAL_CCACHE).deallocate(res, layout);
+                ccache.deallocate(res, layout).expect("");
             });
             v.push(handle);
         }
@@ -868,16 +785,23 @@ mod tests {
 
     #[test]
     fn cacache_alloc_multithread_random_test() {
+        if ccache_or_skip("cacache_alloc_multithread_random_test").is_none() {
+            return;
+        }
         let mut v = Vec::new();
         for _ in 0..1000 {
             let handle = thread::spawn(move || {
+                let ccache = match get_ccache() {
+                    Some(ccache) => ccache,
+                    None => return,
+                };
                 let sz = rand::thread_rng().gen::<usize>() % 8192;
                 let layout = Layout::from_size_align(sz, 8).expect("");
                 let sec = rand::thread_rng().gen::<usize>() % 10;
-                let res = (*GLOBAL_CCACHE).allocate(layout).expect("");
+                let res = ccache.allocate(layout).expect("");
                 let t = time::Duration::from_nanos(sec as u64);
                 thread::sleep(t);
-                (*GLOBAL_CCACHE).deallocate(res, layout);
+                ccache.deallocate(res, layout).expect("");
             });
             v.push(handle);
         }
@@ -5,19 +5,23 @@ use alloc::boxed::Box;
 use core::ptr::{self, write_bytes, NonNull};
 use core::slice;
 use prelude::*;
-// #[cfg(target_os = "linux")]
-// pub mod cpu_cache;
-// #[cfg(target_os = "linux")]
-// mod thread_array_cache;
+#[cfg(percpu)]
+pub mod cpu_cache;
 // #[cfg(target_os = "linux")]
 // pub mod thread_cache;
 // mod thread_mem_cache;
+#[cfg(page_heap)]
+pub mod page_heap;
+pub(crate) mod remote;
 mod thread_cache;
 
-// #[cfg(target_os = "linux")]
-// use cpu_cache::*;
 use crate::page::{PageBumpAlloc, PG_BUMP};
 use crate::sc::META_BUMP;
+use crate::zone::{round_large, GLOBAL_ZONE};
+#[cfg(percpu)]
+use cpu_cache::get_ccache;
+#[cfg(page_heap)]
+use page_heap::GlobalHeap;
 pub use thread_cache::*;
 
 #[derive(Copy, Clone)]
@@ -43,17 +47,48 @@ impl RustAllocator {
     pub unsafe fn extend(&self, size: usize, page_size: usize) {
         META_BUMP.lock().extend(size, page_size);
     }
+
+    /// Returns the size class of the block at `ptr`
+    ///
+    /// `None` means the allocator did not hand the block out. Small objects
+    /// are found from any address inside their slab page, live or not. Large
+    /// blocks only from their first address, and their class holds their
+    /// size in bytes.
+    pub fn size_class_of(&self, ptr: *const u8) -> Option<SizeClass> {
+        (*GLOBAL_ZONE).class_of(ptr)
+    }
+
+    /// Whether `ptr` is a block of this allocator, see [`Self::size_class_of`]
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        self.size_class_of(ptr).is_some()
+    }
+
+    /// Returns how many bytes the block at `ptr` can hold
+    pub fn usable_size(&self, ptr: *const u8) -> Option<usize> {
+        match self.size_class_of(ptr)? {
+            SizeClass::Base(idx) => Some(get_rounded_size_by_idx(idx)),
+            SizeClass::Large(size) => Some(size),
+        }
+    }
 }
 
-unsafe impl GlobalAlloc for RustAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // if HAS_RSEQ {
-        //     if let Ok(result) = (*GLOBAL_CCACHE).allocate(layout) {
-        //         result.as_ptr()
-        //     } else {
-        //         ptr::null_mut()
-        //     }
-        // } else {
+impl RustAllocator {
+    /// Allocates a block for `layout`, leaving the out-of-memory handler to
+    /// the caller
+    unsafe fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        record_size(layout.size());
+        #[cfg(percpu)]
+        if let Some(ccache) = get_ccache() {
+            match ccache.allocate(layout) {
+                Ok(r) => return r.as_ptr(),
+                // the thread cache serves what the cpu cache cannot align
+                Err(e) if e == crate::error::AllocError::ELAYOUT => {}
+                Err(_) => return core::ptr::null_mut(),
+            }
+        }
+        #[cfg(page_heap)]
+        let alloc = &mut (*GlobalHeap);
+        #[cfg(not(page_heap))]
         let alloc = &mut (*GlobalTcache);
         match alloc.allocate(layout) {
             Ok(r) => r.as_ptr(),
@@ -61,23 +96,82 @@ unsafe impl GlobalAlloc for RustAllocator {
         }
     }
 
+    /// Like [`Self::try_alloc`], with the block zeroed
+    ///
+    /// Large blocks come from the backend, which only clears the pages that
+    /// may not be zero. The thread cache skips the objects of fresh slab
+    /// pages, the usable size of other small objects is cleared.
+    unsafe fn try_alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if let SizeClass::Large(_) = get_size_class_by_layout(layout) {
+            record_size(layout.size());
+            return match (*GLOBAL_ZONE).allocate_large_zeroed(layout) {
+                Ok(ptr) => ptr.as_ptr(),
+                Err(_) => core::ptr::null_mut(),
+            };
+        }
+        #[cfg(not(page_heap))]
+        {
+            #[cfg(percpu)]
+            let tcache = get_ccache().is_none();
+            #[cfg(not(percpu))]
+            let tcache = true;
+            if tcache {
+                record_size(layout.size());
+                return match (*GlobalTcache).allocate_zeroed(layout) {
+                    Ok(r) => r.as_ptr(),
+                    Err(_) => core::ptr::null_mut(),
+                };
+            }
+        }
+        let ptr = self.try_alloc(layout);
+        if !ptr.is_null() {
+            write_bytes(ptr, 0, usable_size_of(layout));
+        }
+        ptr
+    }
+}
+
+/// Runs `alloc`, then the out-of-memory handler if needed, see
+/// [`crate::limit`]
+#[inline(always)]
+fn with_oom_handler(alloc: impl Fn() -> *mut u8) -> *mut u8 {
+    // a fixed heap maps nothing
+    #[cfg(feature = "fixed_heap")]
+    return alloc();
+    #[cfg(not(feature = "fixed_heap"))]
+    crate::pal::limit::with_handler(alloc)
+}
+
+unsafe impl GlobalAlloc for RustAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        with_oom_handler(|| self.try_alloc(layout))
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // if HAS_RSEQ {
-        //     let ptr = NonNull::new(ptr).expect("ptr is null!");
-        // //     (*GLOBAL_CCACHE).deallocate(ptr, layout);
-        // } else {
+        #[cfg(percpu)]
+        if let Some(ccache) = get_ccache() {
+            // nothing to report to, what the zone refuses stays leaked
+            let _ = ccache.deallocate(NonNull::new_unchecked(ptr), layout);
+            return;
+        }
+        #[cfg(page_heap)]
+        let alloc = &mut (*GlobalHeap);
+        #[cfg(not(page_heap))]
         let alloc = &mut (*GlobalTcache);
         alloc.deallocate(NonNull::new_unchecked(ptr), layout)
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let old_idx = get_size_class(layout.size()).index();
-        let new_idx = get_size_class(new_size).index();
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let old_cls = get_size_class_by_layout(layout);
+        let new_cls = get_size_class_by_layout(new_layout);
 
-        if old_idx == new_idx {
+        if old_cls == new_cls {
+            record_size(new_size);
             ptr
+        } else if let Some(new_ptr) = Self::reallocate_large(ptr, layout, new_layout) {
+            new_ptr.as_ptr()
         } else {
-            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
             // SAFETY: the caller must ensure that `new_layout` is greater than zero.
             let new_ptr = self.alloc(new_layout);
             if !new_ptr.is_null() {
@@ -89,23 +183,189 @@ unsafe impl GlobalAlloc for RustAllocator {
             new_ptr
         }
     }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        with_oom_handler(|| self.try_alloc_zeroed(layout))
+    }
+}
+
+/// Returns the block of a zero-sized `layout`, which takes the class 0
+///
+/// That class only serves alignments up to a 4K page, so the dangling
+/// pointer of the layout lies in the low pages of the address space, which
+/// the OS never maps. It is thus aligned, never a block of ours nor of anyone
+/// else, and deallocating it does nothing.
+#[inline]
+pub(crate) fn zero_sized(layout: Layout) -> NonNull<u8> {
+    debug_assert!(layout.size() == 0 && layout.align() <= 4096);
+    layout.dangling()
+}
+
+/// Counts a request of `size` bytes in the size histogram, see
+/// [`crate::histogram`]
+#[inline(always)]
+fn record_size(size: usize) {
+    #[cfg(size_histogram)]
+    crate::histogram::record(size);
+}
+
+/// Bytes a block allocated for `layout` can hold
+fn usable_size_of(layout: Layout) -> usize {
+    match get_size_class_by_layout(layout) {
+        SizeClass::Base(idx) => get_rounded_size_by_idx(idx),
+        SizeClass::Large(size) => round_large(size),
+    }
+}
+
+impl RustAllocator {
+    /// Returns the usable size of `ptr` under `new_layout`, if the block
+    /// allocated for `old_layout` serves it as is
+    fn fits_in_place(ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Option<usize> {
+        match (
+            get_size_class_by_layout(old_layout),
+            get_size_class_by_layout(new_layout),
+        ) {
+            (SizeClass::Base(old), SizeClass::Base(new)) if old == new => {
+                Some(get_rounded_size_by_idx(new))
+            }
+            (SizeClass::Large(old), SizeClass::Large(new))
+                if round_large(old) == round_large(new)
+                    && ptr.as_ptr() as usize % new_layout.align() == 0 =>
+            {
+                Some(round_large(new))
+            }
+            _ => None,
+        }
+    }
+
+    /// Resizes a large block for a large `new_layout` without copying it, see
+    /// [`ZoneAllocator::reallocate_large`]
+    ///
+    /// [`ZoneAllocator::reallocate_large`]: crate::zone::ZoneAllocator::reallocate_large
+    pub(crate) unsafe fn reallocate_large(
+        ptr: *mut u8,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<u8>> {
+        match (
+            get_size_class_by_layout(old_layout),
+            get_size_class_by_layout(new_layout),
+        ) {
+            (SizeClass::Large(_), SizeClass::Large(new))
+                if ptr as usize % new_layout.align() == 0 =>
+            {
+                let new_ptr = (*GLOBAL_ZONE)
+                    .reallocate_large(NonNull::new_unchecked(ptr), old_layout, new)
+                    .ok()?;
+                record_size(new);
+                Some(new_ptr)
+            }
+            _ => None,
+        }
+    }
+
+    /// Moves the block to one allocated for `new_layout`
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = if zeroed {
+            self.allocate_zeroed(new_layout)?
+        } else {
+            self.allocate(new_layout)?
+        };
+        let len = old_layout.size().min(new_layout.size());
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), len);
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
 }
 
+/// Blocks are handed out whole, so they can be larger than requested. Resizing
+/// within the usable size of a block keeps it in place.
 unsafe impl Allocator for RustAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        unsafe {
-            let p = self.alloc(layout);
-            // TODO: eliminate redundant overhead
-            // can we avoid using get_index function?
-            // let (_, alloc_size) = get_index_and_size(layout.size());
-            Ok(NonNull::new_unchecked(slice::from_raw_parts_mut(
-                p,
-                layout.size(),
-            )))
-        }
+        let ptr = NonNull::new(unsafe { self.alloc(layout) }).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, usable_size_of(layout)))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = NonNull::new(unsafe { self.alloc_zeroed(layout) }).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, usable_size_of(layout)))
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         self.dealloc(ptr.as_ptr() as *mut u8, layout);
     }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(len) = Self::fits_in_place(ptr, old_layout, new_layout) {
+            record_size(new_layout.size());
+            return Ok(NonNull::slice_from_raw_parts(ptr, len));
+        }
+        match Self::reallocate_large(ptr.as_ptr(), old_layout, new_layout) {
+            Some(new_ptr) => Ok(NonNull::slice_from_raw_parts(
+                new_ptr,
+                usable_size_of(new_layout),
+            )),
+            None => self.reallocate(ptr, old_layout, new_layout, false),
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let (new_ptr, len) = match Self::fits_in_place(ptr, old_layout, new_layout) {
+            Some(len) => {
+                record_size(new_layout.size());
+                (ptr, len)
+            }
+            None => match Self::reallocate_large(ptr.as_ptr(), old_layout, new_layout) {
+                Some(new_ptr) => (new_ptr, usable_size_of(new_layout)),
+                None => return self.reallocate(ptr, old_layout, new_layout, true),
+            },
+        };
+        let old_size = old_layout.size();
+        write_bytes(new_ptr.as_ptr().add(old_size), 0, len - old_size);
+        Ok(NonNull::slice_from_raw_parts(new_ptr, len))
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(len) = Self::fits_in_place(ptr, old_layout, new_layout) {
+            record_size(new_layout.size());
+            return Ok(NonNull::slice_from_raw_parts(ptr, len));
+        }
+        match (
+            get_size_class_by_layout(old_layout),
+            get_size_class_by_layout(new_layout),
+        ) {
+            // the tail pages of a large block go back to the backend
+            (SizeClass::Large(_), SizeClass::Large(new))
+                if ptr.as_ptr() as usize % new_layout.align() == 0 =>
+            {
+                (*GLOBAL_ZONE)
+                    .shrink_large(ptr, old_layout, new)
+                    .map_err(|_| AllocError)?;
+                record_size(new);
+                Ok(NonNull::slice_from_raw_parts(ptr, round_large(new)))
+            }
+            _ => self.reallocate(ptr, old_layout, new_layout, false),
+        }
+    }
 }
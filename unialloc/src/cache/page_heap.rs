@@ -0,0 +1,374 @@
+//! Thread owned slab pages, in the way of mimalloc
+//!
+//! Instead of caching objects, a [`PageHeap`] owns whole slab pages of the
+//! zone. Every page keeps its own free lists: the owner allocates from one
+//! and frees to another without any lock, while other threads push their
+//! frees to an atomic list of the page. Pages go back to the zone only when
+//! the heap is abandoned, at thread exit.
+//!
+//! The pages of a size class form two rings. Allocation takes from the head
+//! of the first ring and, once that page runs out, collects the frees of the
+//! page or moves it to the ring of full pages. The latter is only looked at a
+//! few pages at a time, so the owner notices the frees to its full pages
+//! lazily, without other threads telling it.
+//!
+//! The `page_heap` feature puts it in place of the thread and per-CPU caches.
+use super::remote::{MAX_OWNERS, NO_OWNER};
+use crate::error::Result;
+use crate::page::EfObjectPage;
+use crate::pal::sync::general_thread_local::{register_tls_key, save_tls};
+use crate::sc::{MetadataAllocator, META_BUMP};
+use crate::zone::{ZoneAllocator, GLOBAL_ZONE};
+use crate::*;
+use alloc::boxed::Box;
+use alloc_macros::tls_static;
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+/// Pages of the full ring looked at before adopting a new page
+const FULL_SCAN: usize = 4;
+
+/// Ids of abandoned heaps kept for reuse at most
+const RECYCLED_IDS: usize = 256;
+
+/// Next new id handed to a page heap
+///
+/// Ids start past the ones of the thread caches, see [`super::remote`].
+static NEXT_ID: AtomicU32 = AtomicU32::new(MAX_OWNERS as u32 + 1);
+
+/// Ids of abandoned heaps, handed out before new ones
+static RECYCLED: Mutex<IdPool> = Mutex::new(IdPool::new());
+
+/// A stack of ids, dropping the ones past its capacity
+struct IdPool {
+    ids: [u32; RECYCLED_IDS],
+    len: usize,
+}
+
+impl IdPool {
+    const fn new() -> Self {
+        Self {
+            ids: [NO_OWNER; RECYCLED_IDS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, id: u32) {
+        if self.len < RECYCLED_IDS {
+            self.ids[self.len] = id;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.ids[self.len])
+    }
+}
+
+/// Takes an id for a heap, reusing the one of an abandoned heap if any
+///
+/// Panics once new ids run out rather than wrap to `NO_OWNER`.
+fn claim_id() -> u32 {
+    if let Some(id) = RECYCLED.lock().pop() {
+        return id;
+    }
+    NEXT_ID
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(1))
+        .expect("page heap ids ran out")
+}
+
+/// Links `page` at the tail of the ring `head`
+fn link(head: &mut *mut EfObjectPage, page: &mut EfObjectPage) {
+    if head.is_null() {
+        page.set_prev(page as *const _ as usize);
+        page.set_next(page as *const _ as usize);
+        *head = page;
+    } else {
+        let first = unsafe { &mut **head };
+        let last = first.get_prev();
+        page.set_prev(last as *const _ as usize);
+        page.set_next(first as *const _ as usize);
+        last.set_next(page as *const _ as usize);
+        first.set_prev(page as *const _ as usize);
+    }
+}
+
+/// Unlinks `page` from the ring `head`
+fn unlink(head: &mut *mut EfObjectPage, page: &mut EfObjectPage) {
+    if core::ptr::eq(page.get_next(), page) {
+        *head = null_mut();
+    } else {
+        let prev = page.get_prev();
+        let next = page.get_next();
+        prev.set_next(next as *const _ as usize);
+        next.set_prev(prev as *const _ as usize);
+        if core::ptr::eq(*head, page) {
+            *head = next;
+        }
+    }
+}
+
+/// A thread local heap of slab pages of a zone of size class policy `P`
+pub struct PageHeap<P = DefaultSizeClass> {
+    /// Pages to allocate from, a ring per size class
+    pages: [*mut EfObjectPage; MAX_CLASSES],
+    /// Pages found without free objects, a ring per size class
+    full: [*mut EfObjectPage; MAX_CLASSES],
+    /// The zone of the pages, null for [`GLOBAL_ZONE`]
+    zone: *mut ZoneAllocator<P>,
+    /// Id stamped on the pages of the heap, `NO_OWNER` until it adopts one
+    id: u32,
+    policy: PhantomData<P>,
+}
+
+impl PageHeap {
+    /// Creates a heap of the default size classes over [`GLOBAL_ZONE`]
+    pub const fn new() -> Self {
+        Self::with_zone(null_mut())
+    }
+}
+
+impl<P: SizeClassPolicy> PageHeap<P> {
+    /// Creates a heap over `zone`, which must use the same size classes
+    pub fn new_in(zone: &'static mut ZoneAllocator<P>) -> Self {
+        Self::with_zone(zone)
+    }
+
+    const fn with_zone(zone: *mut ZoneAllocator<P>) -> Self {
+        Self {
+            pages: [null_mut(); MAX_CLASSES],
+            full: [null_mut(); MAX_CLASSES],
+            zone,
+            id: NO_OWNER,
+            policy: PhantomData,
+        }
+    }
+
+    /// The zone of the pages
+    fn zone(&self) -> &'static mut ZoneAllocator<P> {
+        if self.zone.is_null() {
+            // only `PageHeap::new` leaves it null, so `P` is the default
+            unsafe { &mut *(&mut *GLOBAL_ZONE as *mut ZoneAllocator as *mut ZoneAllocator<P>) }
+        } else {
+            unsafe { &mut *self.zone }
+        }
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        let idx = match P::size_class_by_layout(layout) {
+            SizeClass::Base(idx) => idx,
+            SizeClass::Large(_) => return self.zone().allocate_large(layout),
+        };
+        if unlikely(idx == 0) {
+            return Ok(super::zero_sized(layout));
+        }
+        if let Some(page) = unsafe { self.pages[idx].as_mut() } {
+            if let Some(obj) = page.pop() {
+                return Ok(obj);
+            }
+        }
+        self.allocate_slow(idx)
+    }
+
+    /// Finds a page of class `idx` with free objects, adopting one from the
+    /// zone as a last resort
+    fn allocate_slow(&mut self, idx: usize) -> Result<NonNull<u8>> {
+        // every page runs out once before it moves to the full ring
+        while let Some(page) = unsafe { self.pages[idx].as_mut() } {
+            if page.collect() {
+                return Ok(page.pop().expect("err"));
+            }
+            unlink(&mut self.pages[idx], page);
+            link(&mut self.full[idx], page);
+        }
+        for _ in 0..FULL_SCAN {
+            let page = match unsafe { self.full[idx].as_mut() } {
+                Some(page) => page,
+                None => break,
+            };
+            if page.collect() {
+                unlink(&mut self.full[idx], page);
+                link(&mut self.pages[idx], page);
+                return Ok(page.pop().expect("err"));
+            }
+            self.full[idx] = page.get_next();
+        }
+        if unlikely(self.id == NO_OWNER) {
+            self.id = claim_id();
+        }
+        let page = self.zone().adopt_page(idx, self.id)?;
+        link(&mut self.pages[idx], page);
+        Ok(page.pop().expect("err"))
+    }
+
+    pub fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let idx = match P::size_class_by_layout(layout) {
+            SizeClass::Base(idx) => idx,
+            SizeClass::Large(_) => return self.zone().deallocate_large(ptr, layout),
+        };
+        // a zero-sized block, see `zero_sized`
+        if unlikely(idx == 0) {
+            return;
+        }
+        let zone = self.zone();
+        let page = zone.page_of(ptr);
+        let owner = page.owner();
+        if likely(owner == self.id && owner != NO_OWNER) {
+            page.free_local(ptr.as_ptr());
+        } else if owner == NO_OWNER || !page.free_remote(ptr.as_ptr()) {
+            zone.deallocate_abandoned(idx, ptr).expect("dealloc err");
+        }
+    }
+
+    /// Gives every page back to the zone
+    ///
+    /// Objects still in use stay valid, and are freed to the zone. No page
+    /// holds the id of the heap anymore, so it goes to the next heap.
+    pub fn abandon(&mut self) {
+        let zone = self.zone();
+        for idx in 1..P::TOTAL {
+            Self::abandon_ring(zone, idx, &mut self.pages[idx]);
+            Self::abandon_ring(zone, idx, &mut self.full[idx]);
+        }
+        let id = core::mem::replace(&mut self.id, NO_OWNER);
+        if id != NO_OWNER {
+            RECYCLED.lock().push(id);
+        }
+    }
+
+    fn abandon_ring(zone: &mut ZoneAllocator<P>, idx: usize, ring: &mut *mut EfObjectPage) {
+        while let Some(page) = unsafe { ring.as_mut() } {
+            unlink(ring, page);
+            zone.abandon_page(idx, page);
+        }
+    }
+}
+
+unsafe extern "C" fn free_page_heap(ptr: *mut libc::c_void) {
+    let heap = (ptr as *mut PageHeap).as_mut().expect("err");
+    heap.abandon();
+    META_BUMP.lock().dealloc(ptr as *mut usize);
+}
+
+tls_static! {
+    PageHeap GlobalHeap, free_page_heap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Creates a zone of its own for the heaps of a test
+    fn new_zone() -> *mut ZoneAllocator {
+        Box::leak(Box::new(ZoneAllocator::new()))
+    }
+
+    fn new_heap(zone: *mut ZoneAllocator) -> PageHeap {
+        PageHeap::new_in(unsafe { &mut *zone })
+    }
+
+    #[test]
+    fn local_reuse_test() {
+        let mut heap = new_heap(new_zone());
+        let layout = Layout::from_size_align(48, 8).expect("err");
+        // four pages worth of objects
+        let count = DefaultSizeClass::objects_per_slab(get_size_class(48).index()) * 4;
+        let ptrs: Vec<_> = (0..count)
+            .map(|_| heap.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs.iter() {
+            heap.deallocate(*ptr, layout);
+        }
+        // the pages of the heap serve it again, full or not
+        for _ in 0..count {
+            let ptr = heap.allocate(layout).expect("err");
+            assert!(ptrs.contains(&ptr));
+        }
+        heap.abandon();
+    }
+
+    #[test]
+    fn remote_free_test() {
+        let zone = new_zone();
+        let mut owner = new_heap(zone);
+        let mut other = new_heap(zone);
+        let layout = Layout::from_size_align(48, 8).expect("err");
+        let per_slab = DefaultSizeClass::objects_per_slab(get_size_class(48).index());
+        let ptrs: Vec<_> = (0..per_slab / 2)
+            .map(|_| owner.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs.iter() {
+            other.deallocate(*ptr, layout);
+        }
+
+        // the frees of the other heap are collected once the page runs out
+        let rest: Vec<_> = (per_slab / 2..per_slab)
+            .map(|_| owner.allocate(layout).expect("err"))
+            .collect();
+        let ptr = owner.allocate(layout).expect("err");
+        assert!(ptrs.contains(&ptr));
+
+        owner.deallocate(ptr, layout);
+        for ptr in rest {
+            owner.deallocate(ptr, layout);
+        }
+        owner.abandon();
+        other.abandon();
+    }
+
+    #[test]
+    fn id_pool_test() {
+        let mut pool = IdPool::new();
+        assert_eq!(pool.pop(), None);
+        let first = MAX_OWNERS as u32 + 1;
+        for id in first..=first + RECYCLED_IDS as u32 {
+            pool.push(id);
+        }
+        // the id past the capacity is dropped
+        assert_eq!(pool.pop(), Some(first + RECYCLED_IDS as u32 - 1));
+
+        let mut heap = new_heap(new_zone());
+        let layout = Layout::from_size_align(48, 8).expect("err");
+        let ptr = heap.allocate(layout).expect("err");
+        assert_ne!(heap.id, NO_OWNER);
+        heap.deallocate(ptr, layout);
+        heap.abandon();
+        assert_eq!(heap.id, NO_OWNER);
+    }
+
+    #[test]
+    fn abandon_test() {
+        let zone = new_zone();
+        let layout = Layout::from_size_align(48, 8).expect("err");
+        let mut first = new_heap(zone);
+        let ptrs: Vec<_> = (0..10)
+            .map(|_| first.allocate(layout).expect("err"))
+            .collect();
+        first.abandon();
+
+        // the next heap adopts the partial page, and gets the frees of
+        // the objects still in use through it
+        let mut second = new_heap(zone);
+        let ptr = second.allocate(layout).expect("err");
+        assert!(!ptrs.contains(&ptr));
+        let mut third = new_heap(zone);
+        for ptr in ptrs.iter() {
+            third.deallocate(*ptr, layout);
+        }
+        second.deallocate(ptr, layout);
+        second.abandon();
+
+        // the page is empty again, and carved anew
+        let mut fourth = new_heap(zone);
+        assert_eq!(fourth.allocate(layout).expect("err"), ptrs[0]);
+        fourth.deallocate(ptrs[0], layout);
+        fourth.abandon();
+        third.abandon();
+    }
+}
@@ -0,0 +1,173 @@
+//! Remote free lists of thread caches
+//!
+//! Every thread cache that pulls objects from the zone claims an owner slot.
+//! The id of the slot is stamped on the slab pages handed to the thread, so a
+//! thread freeing an object it does not own pushes it to the list of the
+//! owner instead of its own cache. The owner takes the whole list back on its
+//! refill path.
+//!
+//! Lists are lock-free stacks: pushers CAS the head and the owner swaps it
+//! out, which makes them free of ABA issues. The lists of a slot nobody owns
+//! are sealed, pushes to them fail and leave the object to the caller. The
+//! slot table is static and never freed, so a stale owner id read from a page
+//! always points to valid memory.
+use crate::error::{AllocError, Result};
+use crate::prelude::*;
+use crate::zone::GLOBAL_ZONE;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maximum number of thread caches owning slab pages at the same time.
+/// Threads failing to claim a slot keep the old behaviour and cache
+/// whatever they free.
+pub const MAX_OWNERS: usize = 1024;
+
+/// Id of pages which are not owned by any thread cache
+pub const NO_OWNER: u32 = 0;
+
+/// Head of the lists of a released slot
+///
+/// Objects are at least 8 bytes aligned, so no list starts there.
+const SEALED: usize = 1;
+
+struct RemoteSlot {
+    used: AtomicBool,
+    lists: [AtomicUsize; TOTAL_SIZE_CLASS],
+}
+
+impl RemoteSlot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicUsize = AtomicUsize::new(SEALED);
+
+    const fn new() -> Self {
+        Self {
+            used: AtomicBool::new(false),
+            lists: [Self::EMPTY; TOTAL_SIZE_CLASS],
+        }
+    }
+
+    /// Lets objects be pushed to the lists, once the slot is claimed
+    fn open(&self) {
+        for list in self.lists.iter() {
+            list.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Seals the list of class `idx`, returns what was queued on it
+    fn seal(&self, idx: usize) -> usize {
+        match self.lists[idx].swap(SEALED, Ordering::Acquire) {
+            SEALED => 0,
+            head => head,
+        }
+    }
+
+    fn push(&self, idx: usize, ptr: *mut u8) -> Result<()> {
+        let list = &self.lists[idx];
+        let mut head = list.load(Ordering::Relaxed);
+        loop {
+            if unlikely(head == SEALED) {
+                return Err(AllocError::EOWNER);
+            }
+            unsafe { *(ptr as *mut usize) = head };
+            match list.compare_exchange_weak(
+                head,
+                ptr as usize,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(cur) => head = cur,
+            }
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SLOT: RemoteSlot = RemoteSlot::new();
+
+static SLOTS: [RemoteSlot; MAX_OWNERS] = [SLOT; MAX_OWNERS];
+
+fn slot(owner: u32) -> &'static RemoteSlot {
+    debug_assert_ne!(owner, NO_OWNER);
+    &SLOTS[owner as usize - 1]
+}
+
+/// Claims a free owner slot
+///
+/// Returns `NO_OWNER` when all slots are taken
+pub fn claim() -> u32 {
+    for (i, slot) in SLOTS.iter().enumerate() {
+        if !slot.used.load(Ordering::Relaxed)
+            && slot
+                .used
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            slot.open();
+            return i as u32 + 1;
+        }
+    }
+    NO_OWNER
+}
+
+/// Gives the slot of `owner` back
+///
+/// Objects still queued for the owner are returned to the zone, and frees
+/// racing with the release fail. The slot is released even if the zone
+/// fails to take some list back, the first error is returned.
+pub fn release(owner: u32) -> Result<()> {
+    let slot = slot(owner);
+    let mut res = Ok(());
+    for idx in 0..TOTAL_SIZE_CLASS {
+        let head = slot.seal(idx);
+        if head != 0 {
+            // the length of the list is not known, it goes to the slab
+            res = res.and((*GLOBAL_ZONE).deallocate_batch_to_slab(idx, head as *mut u8, 0));
+        }
+    }
+    slot.used.store(false, Ordering::Release);
+    res
+}
+
+/// Queues `ptr` of size class `idx` on the remote list of `owner`
+///
+/// Fails with `EOWNER` if the owner has gone, or is not a thread cache, and
+/// the object is left to the caller.
+pub fn push(owner: u32, idx: usize, ptr: *mut u8) -> Result<()> {
+    if unlikely(owner == NO_OWNER || owner as usize > MAX_OWNERS) {
+        return Err(AllocError::EOWNER);
+    }
+    slot(owner).push(idx, ptr)
+}
+
+/// Takes all the objects of size class `idx` queued for `owner`
+///
+/// Returns the head of a null terminated list, or 0 if nothing is queued
+pub fn take(owner: u32, idx: usize) -> usize {
+    let list = &slot(owner).lists[idx];
+    if list.load(Ordering::Relaxed) == 0 {
+        return 0;
+    }
+    list.swap(0, Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_slot_test() {
+        let slot = RemoteSlot::new();
+        let mut objects = [0usize; 2];
+        let (first, second) = (&mut objects[0] as *mut usize, &mut objects[1] as *mut usize);
+        assert_eq!(slot.push(1, first as *mut u8), Err(AllocError::EOWNER));
+
+        slot.open();
+        slot.push(1, first as *mut u8).expect("err");
+        slot.push(1, second as *mut u8).expect("err");
+        assert_eq!(slot.seal(1), second as usize);
+        assert_eq!(objects[1], first as usize);
+        // frees racing with the release keep their object
+        assert_eq!(slot.push(1, first as *mut u8), Err(AllocError::EOWNER));
+        assert_eq!(slot.seal(1), 0);
+    }
+}
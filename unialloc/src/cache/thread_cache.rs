@@ -1,20 +1,55 @@
 //! Linklist based thread local cache
+use super::remote::{self, NO_OWNER};
 use crate::error::{AllocError, Result};
+use crate::freelist::decay::now_ms;
 use crate::mm::linklist::Linklist;
 use crate::sc::MetadataAllocator;
 use crate::sc::META_BUMP;
 use crate::size_class::*;
-use crate::zone::GLOBAL_ZONE;
+use crate::zone::{ZoneAllocator, GLOBAL_ZONE};
 use crate::*;
 use alloc::boxed::Box;
 use core::cell::RefCell;
+use core::marker::PhantomData;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::{
     alloc::{Allocator, GlobalAlloc, Layout},
     ptr::NonNull,
 };
+use spin::Mutex;
 
-include!(concat!(env!("OUT_DIR"), "/sizeclass_consts.rs"));
+/// Upper bound of bytes cached by one thread across all size classes.
+/// Going beyond it makes the thread return objects to the zone.
+const TCACHE_MAX_BYTES: usize = 4 << 20;
+
+/// How many slabs worth of objects one size class may keep before it
+/// returns the surplus to the zone.
+const TCACHE_MAX_SLABS: usize = 2;
+
+/// Bumped to make every thread cache trim, see [`ThreadCache::trim`]
+static TRIM_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Asks every thread cache to trim on its next deallocation
+pub fn request_trim() {
+    TRIM_EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Milliseconds a thread cache stays unused before the background thread
+/// flushes it, see [`trim_idle`]
+const TCACHE_IDLE_MS: u64 = 1000;
+
+/// Caches of live threads over [`GLOBAL_ZONE`], by owner slot
+static LIVE: Mutex<LiveCaches> = Mutex::new(LiveCaches::new());
+
+/// How many caches of exited threads are kept for the next threads
+const TCACHE_POOL_CAP: usize = 8;
+
+/// Milliseconds a detached cache waits for a thread before it is flushed
+const TCACHE_POOL_AGE_MS: u64 = 1000;
+
+/// Caches of exited threads, still populated, see [`ThreadCache::take_over`]
+static DETACHED: Mutex<DetachedPool> = Mutex::new(DetachedPool::new(TCACHE_POOL_AGE_MS));
 
 #[derive(Clone, Copy)]
 struct ThreadCacheUnit {
@@ -22,6 +57,10 @@ struct ThreadCacheUnit {
     bump_ptr: usize,
     bump_count: i32,
     bump_unit: i32,
+    // whether the objects of the bump run read as zero
+    bump_zeroed: bool,
+    // high-water mark of `list`, lazily set by `high_water`
+    max_length: usize,
 }
 
 impl ThreadCacheUnit {
@@ -31,18 +70,19 @@ impl ThreadCacheUnit {
             bump_ptr: 0,
             bump_count: 0,
             bump_unit: 0,
+            bump_zeroed: false,
+            max_length: 0,
         }
     }
 
-    pub fn clean_up(&mut self, idx: usize) {
+    pub fn clean_up<P: SizeClassPolicy>(&mut self, idx: usize, zone: &mut ZoneAllocator<P>) {
         while self.bump_count > 0 {
             self.free(self.bump_ptr as *mut u8);
             self.bump_ptr += self.bump_unit as usize;
             self.bump_count -= 1;
         }
         if self.list.length() > 0 {
-            (*GLOBAL_ZONE)
-                .deallocate_batch_to_slab(idx, self.list.link as *mut u8)
+            zone.deallocate_batch_to_slab(idx, self.list.link as *mut u8, self.list.length())
                 .expect("dealloc err");
         }
         *self = Self::new();
@@ -62,76 +102,193 @@ impl ThreadCacheUnit {
         self.list.push_unchecked(ptr);
     }
 
-    pub fn deallocate(&mut self, idx: usize, ptr: NonNull<u8>, size: usize) {
-        self.list.push_unchecked(ptr.as_ptr());
+    /// The maximum number of objects kept in the list of size class `idx`
+    ///
+    /// It equals to the number of objects in `TCACHE_MAX_SLABS` slabs, so a
+    /// thread can always hold a full batch from the zone plus its frees.
+    fn high_water<P: SizeClassPolicy>(&mut self, idx: usize) -> usize {
+        if unlikely(self.max_length == 0) {
+            self.max_length = P::objects_per_slab(idx) * TCACHE_MAX_SLABS;
+        }
+        self.max_length
+    }
 
-        // a test
-        return;
+    /// Returns objects of the list to the zone until `keep` objects are left
+    ///
+    /// Returns the number of released objects
+    fn release<P: SizeClassPolicy>(
+        &mut self,
+        idx: usize,
+        keep: usize,
+        zone: &mut ZoneAllocator<P>,
+    ) -> usize {
+        if self.list.length <= keep {
+            return 0;
+        }
+        if keep == 0 {
+            let released = self.list.length;
+            zone.deallocate_batch_to_slab(idx, self.list.link as *mut u8, released)
+                .expect("dealloc err");
+            self.list = Linklist::new();
+            return released;
+        }
+        // the most recently freed objects (the hot ones) stay in the cache
+        let mut counter = 1usize;
+        let mut cur = self.list.link;
+        while counter < keep {
+            cur = unsafe { *(cur as *mut usize) };
+            counter += 1;
+        }
+        let to_free = unsafe { *(cur as *mut usize) };
+        unsafe { *(cur as *mut usize) = 0 };
+        let released = self.list.length - counter;
+        self.list.length = counter;
+        //self.validate();
+        zone.deallocate_batch_to_slab(idx, to_free as *mut u8, released)
+            .expect("dealloc err");
+        released
+    }
+
+    /// Pushes `ptr` back to the list
+    ///
+    /// Returns the number of objects given back to the zone when the list
+    /// goes beyond its high-water mark
+    pub fn deallocate<P: SizeClassPolicy>(
+        &mut self,
+        idx: usize,
+        ptr: NonNull<u8>,
+        zone: &mut ZoneAllocator<P>,
+    ) -> usize {
+        self.list.push_unchecked(ptr.as_ptr());
 
-        if self.list.length * size > (1usize << 28) {
+        let max_length = self.high_water::<P>(idx);
+        if unlikely(self.list.length > max_length) {
             //return half to back
-            let half = self.list.length / 2;
-            let mut counter = 1usize;
-            let mut cur = self.list.link;
-            while counter < half {
-                cur = unsafe { *(cur as *mut usize) };
+            self.release(idx, max_length / 2, zone)
+        } else {
+            0
+        }
+    }
+
+    /// Number of objects held by this unit
+    fn count(&self) -> usize {
+        self.list.length + self.bump_count as usize
+    }
+
+    /// Moves the objects other threads freed for `owner` to the list
+    ///
+    /// Returns false if nothing was queued
+    fn drain_remote<P: SizeClassPolicy>(
+        &mut self,
+        idx: usize,
+        owner: u32,
+        zone: &mut ZoneAllocator<P>,
+    ) -> bool {
+        if owner == NO_OWNER {
+            return false;
+        }
+        let head = remote::take(owner, idx);
+        if head == 0 {
+            return false;
+        }
+        let mut tail = head;
+        let mut counter = 1;
+        unsafe {
+            while *(tail as *mut usize) != 0 {
+                tail = *(tail as *mut usize);
                 counter += 1;
             }
-            let to_free = unsafe { *(cur as *mut usize) };
-            unsafe { *(cur as *mut usize) = 0 };
-            self.list.length = counter;
-            //self.validate();
-            (*GLOBAL_ZONE)
-                .deallocate_batch_to_slab(idx, to_free as *mut u8)
-                .expect("dealloc err");
+            *(tail as *mut usize) = self.list.link;
         }
+        self.list.link = head;
+        self.list.length += counter;
+
+        let max_length = self.high_water::<P>(idx);
+        if self.list.length > max_length {
+            self.release(idx, max_length / 2, zone);
+        }
+        true
     }
 
-    pub fn allocate(&mut self, idx: usize, align: usize) -> NonNull<u8> {
+    /// Allocates an object of class `idx`, also telling whether it reads as
+    /// zero
+    ///
+    /// The class already meets the alignment of the request, so any cached
+    /// object will do. Fails with `ENOMEM` when the zone cannot refill.
+    pub fn allocate<P: SizeClassPolicy>(
+        &mut self,
+        idx: usize,
+        owner: u32,
+        zone: &mut ZoneAllocator<P>,
+    ) -> Result<(NonNull<u8>, bool)> {
         //case 1: we can reuse previous
         if self.list.length > 0 {
-            let ans = self.list.pop_unchecked_aligned(align);
-            if !ans.is_null() {
-                return NonNull::new(ans).expect("err");
-            }
+            let ans = self.list.pop_unchecked();
+            return Ok((NonNull::new(ans).expect("err"), false));
         }
         //case 2: if we have bump
-        let mut ans = 0usize;
-        while self.bump_count > 0 && ans == 0 {
-            if self.bump_ptr & (align - 1) == 0 {
-                ans = self.bump_ptr;
-            } else {
-                //else we will consume all the bump allocation and then fall into backend if needed
-                self.free(self.bump_ptr as *mut u8);
-            }
+        if self.bump_count > 0 {
+            let ans = self.bump_ptr;
             self.bump_ptr += self.bump_unit as usize;
             self.bump_count -= 1;
+            return Ok((NonNull::new(ans as *mut u8).expect("err"), self.bump_zeroed));
+        }
+        //case 3: objects of ours freed by other threads
+        if self.drain_remote(idx, owner, zone) {
+            let ans = self.list.pop_unchecked();
+            return Ok((NonNull::new(ans).expect("err"), false));
         }
-        if ans != 0 {
-            NonNull::new(ans as *mut u8).expect("err")
+        //allocate from back
+        let back_alloc = zone.allocate_batch_from_slab(idx, owner)?;
+        let ans = back_alloc.0 as usize;
+        if let Some(bump) = back_alloc.2 {
+            self.bump_count = (back_alloc.1 - 1) as i32;
+            self.bump_unit = bump as i32;
+            self.bump_ptr = ans + bump;
+            self.bump_zeroed = back_alloc.3;
         } else {
-            //allocate from back
-            let alloc_res = (*GLOBAL_ZONE).allocate_batch_from_slab(idx, align);
-
-            if let Ok(back_alloc) = alloc_res {
-                if let Some(bump) = back_alloc.2 {
-                    self.bump_count = (back_alloc.1 - 1) as i32;
-                    self.bump_unit = bump as i32;
-                    self.bump_ptr = back_alloc.0 as usize + bump;
-                    ans = back_alloc.0 as usize;
-                } else {
-                    assert_eq!(self.list.length, 0);
-                    ans = back_alloc.0 as usize;
-                    let head = unsafe { *(ans as *mut usize) };
-                    self.list.link = head;
-                    self.list.length = back_alloc.1 - 1;
-                    //self.validate();
-                }
-                NonNull::new(ans as *mut u8).expect("err")
-            } else {
-                panic!();
-            }
+            assert_eq!(self.list.length, 0);
+            let head = unsafe { *(ans as *mut usize) };
+            self.list.link = head;
+            self.list.length = back_alloc.1 - 1;
+            //self.validate();
+        }
+        Ok((NonNull::new(ans as *mut u8).expect("err"), back_alloc.3))
+    }
+}
+
+/// Number of slab pages a [`PageOwners`] remembers
+const OWNER_SLOTS: usize = 16;
+
+/// Owners of the slab pages a thread recently freed objects to
+///
+/// Frees look the owner up in the page map once per page instead of once
+/// per object. An entry may outlive the owner it records, when the page goes
+/// back to the zone and on to another cache; the object then goes to the
+/// wrong cache of its class, which is as good as any.
+#[derive(Clone, Copy)]
+struct PageOwners {
+    pages: [usize; OWNER_SLOTS],
+    owners: [u32; OWNER_SLOTS],
+}
+
+impl PageOwners {
+    const fn new() -> Self {
+        Self {
+            pages: [0; OWNER_SLOTS],
+            owners: [NO_OWNER; OWNER_SLOTS],
+        }
+    }
+
+    /// Returns the owner of the slab object `ptr`
+    fn owner_of<P: SizeClassPolicy>(&mut self, ptr: NonNull<u8>, zone: &ZoneAllocator<P>) -> u32 {
+        let page = ptr.as_ptr() as usize / PAGE_SIZE;
+        let slot = page % OWNER_SLOTS;
+        if unlikely(self.pages[slot] != page) {
+            self.pages[slot] = page;
+            self.owners[slot] = zone.owner_of(ptr);
         }
+        self.owners[slot]
     }
 }
 
@@ -147,73 +304,487 @@ fn has_upper_bits(ptr: usize) -> bool {
     ptr >> 48 > 0
 }
 
+/// A thread local cache in front of a zone of size class policy `P`
 #[repr(align(8))]
-pub struct ThreadCache {
-    list: [ThreadCacheUnit; TOTAL_SIZE_CLASS],
+pub struct ThreadCache<P = DefaultSizeClass> {
+    list: [ThreadCacheUnit; MAX_CLASSES],
+    /// The zone refilling this cache, null for [`GLOBAL_ZONE`]
+    zone: *mut ZoneAllocator<P>,
+    /// Bytes of objects held by all units
+    bytes: usize,
+    /// Id of the remote free lists of this cache, see [`remote`]
+    owner: u32,
+    /// Whether `owner` has been claimed
+    claimed: bool,
+    /// Owners of the pages last freed to
+    pages: PageOwners,
+    /// Last `TRIM_EPOCH` this cache trimmed at
+    epoch: usize,
+    /// Operations done so far, only stored by the owner thread
+    ops: AtomicUsize,
+    /// Set by the background thread once the cache is idle, the owner thread
+    /// flushes the cache on its next slow path
+    flush_requested: AtomicBool,
+    policy: PhantomData<P>,
     // queue: usize,
 }
 
 impl ThreadCache {
+    /// Creates a cache of the default size classes over [`GLOBAL_ZONE`]
     pub const fn new() -> Self {
+        Self::with_zone(null_mut())
+    }
+}
+
+impl<P: SizeClassPolicy> ThreadCache<P> {
+    /// Creates a cache over `zone`, which must use the same size classes
+    ///
+    /// Objects of such a cache are not stamped with an owner, so frees
+    /// from other threads stay in the cache of the freeing thread.
+    pub fn new_in(zone: &'static mut ZoneAllocator<P>) -> Self {
+        Self::with_zone(zone)
+    }
+
+    const fn with_zone(zone: *mut ZoneAllocator<P>) -> Self {
         Self {
-            list: [ThreadCacheUnit::new(); TOTAL_SIZE_CLASS],
+            list: [ThreadCacheUnit::new(); MAX_CLASSES],
+            zone,
+            bytes: 0,
+            owner: NO_OWNER,
+            claimed: false,
+            pages: PageOwners::new(),
+            epoch: 0,
+            ops: AtomicUsize::new(0),
+            flush_requested: AtomicBool::new(false),
+            policy: PhantomData,
         }
     }
+
+    /// The zone refilling this cache
+    fn zone(&self) -> &'static mut ZoneAllocator<P> {
+        if self.zone.is_null() {
+            // only `ThreadCache::new` leaves it null, so `P` is the default
+            unsafe { &mut *(&mut *GLOBAL_ZONE as *mut ZoneAllocator as *mut ZoneAllocator<P>) }
+        } else {
+            unsafe { &mut *self.zone }
+        }
+    }
+
     pub fn init(&mut self) {}
     //todo dealloc batch size array might be too large
-    pub fn cleanup_cache_unchecked(&mut self) {
-        for idx in 1..self.list.len() {
+    /// Gives every object back to the zone and releases the owner slot
+    ///
+    /// Fails if the zone cannot take back the objects queued for the owner
+    /// slot, which is released all the same.
+    pub fn cleanup_cache_unchecked(&mut self) -> Result<()> {
+        self.unregister();
+        self.flush();
+        let owner = core::mem::replace(&mut self.owner, NO_OWNER);
+        self.pages = PageOwners::new();
+        self.claimed = false;
+        if owner != NO_OWNER {
+            remote::release(owner)?;
+        }
+        Ok(())
+    }
+
+    /// Gives every object of the cache back to the zone
+    fn flush(&mut self) {
+        let zone = self.zone();
+        for idx in 1..P::TOTAL {
             let list: &mut ThreadCacheUnit = &mut self.list[idx];
-            list.clean_up(idx);
+            list.clean_up(idx, zone);
+        }
+        self.bytes = 0;
+    }
+
+    /// Flushes the cache if the background thread found it idle, see
+    /// [`trim_idle`]
+    fn flush_if_requested(&mut self) {
+        if unlikely(self.flush_requested.load(Ordering::Relaxed)) {
+            self.flush_requested.store(false, Ordering::Relaxed);
+            self.flush();
+        }
+    }
+
+    fn count_op(&mut self) {
+        // the owner is the only writer, no read-modify-write needed
+        let ops = self.ops.load(Ordering::Relaxed);
+        self.ops.store(ops.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// Bytes of objects currently cached by this thread
+    pub fn cached_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Brings the cache back under its byte budget
+    ///
+    /// Starting from the largest size class, each free list gives half of
+    /// its objects back to the zone until half of the budget is used.
+    fn scavenge(&mut self) {
+        let zone = self.zone();
+        for idx in (1..P::TOTAL).rev() {
+            if self.bytes <= TCACHE_MAX_BYTES / 2 {
+                break;
+            }
+            let unit: &mut ThreadCacheUnit = &mut self.list[idx];
+            let keep = unit.list.length / 2;
+            let released = unit.release(idx, keep, zone);
+            self.bytes -= released * P::rounded_size(idx);
+        }
+    }
+
+    /// Gives half of the objects of every size class back to the zone
+    ///
+    /// It runs once per trim request, so the cache of a thread that stopped
+    /// allocating shrinks by half at each request.
+    fn trim(&mut self, epoch: usize) {
+        self.epoch = epoch;
+        let zone = self.zone();
+        for idx in 1..P::TOTAL {
+            let unit: &mut ThreadCacheUnit = &mut self.list[idx];
+            let keep = unit.list.length / 2;
+            let released = unit.release(idx, keep, zone);
+            self.bytes -= released * P::rounded_size(idx);
         }
     }
 
     pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        self.count_op();
+        self.allocate_object(layout).map(|(ptr, _)| ptr)
+    }
+
+    /// Like [`Self::allocate`], with the object zeroed
+    ///
+    /// Objects of a slab page fresh from the backend read as zero already, the
+    /// others are cleared up to their usable size.
+    pub fn allocate_zeroed(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        let idx = match P::size_class_by_layout(layout) {
+            SizeClass::Base(idx) => idx,
+            SizeClass::Large(_) => return self.zone().allocate_large_zeroed(layout),
+        };
+        self.count_op();
+        let (ptr, zeroed) = self.allocate_object(layout)?;
+        if !zeroed {
+            unsafe { ptr.as_ptr().write_bytes(0, P::rounded_size(idx)) };
+        }
+        Ok(ptr)
+    }
+
+    /// Allocates a block for `layout`, also telling whether it reads as zero
+    fn allocate_object(&mut self, layout: Layout) -> Result<(NonNull<u8>, bool)> {
         // 1. round the size up to next size class
-        let cls = get_size_class(layout.size());
+        let cls = P::size_class_by_layout(layout);
 
         // 2. try to pop one from freelist
         if let SizeClass::Base(idx) = cls {
             if unlikely(idx == 0) {
-                return Ok(NonNull::new(0x100000000000usize as *mut u8).expect("err"));
+                return Ok((super::zero_sized(layout), true));
             }
+            if unlikely(!self.claimed) {
+                self.claim();
+            }
+            // the unit refills from the zone, a slow path
+            if unlikely(self.list[idx].count() == 0) {
+                self.flush_if_requested();
+            }
+            let owner = self.owner;
+            let zone = self.zone();
             let size_cache: &mut ThreadCacheUnit = &mut self.list[idx];
-            let ans = size_cache.allocate(idx, layout.align());
+            let before = size_cache.count();
+            let ans = size_cache.allocate(idx, owner, zone)?;
+            // objects pulled from the zone minus the returned one
+            let after = size_cache.count();
+            self.bytes = (self.bytes + after * P::rounded_size(idx))
+                .wrapping_sub(before * P::rounded_size(idx));
             Ok(ans)
         } else {
             // 3. Large size class goes to zone directly
-            //todo return Self::alloc_from_zone(new_layout, Some((cls, size))).or(Err(AllocError));
-            if let Ok(ans) = (*GLOBAL_ZONE).allocate_large(layout) {
-                Ok(ans)
-            } else {
-                Err(AllocError::ENOMEM)
-            }
+            Ok((self.zone().allocate_large(layout)?, false))
         }
     }
 
     pub fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.count_op();
         // self.handle_delay_case(ptr.as_ptr() as usize, layout.size());
         // return;
 
         // 1. round the size up to next size class
-        let cls = get_size_class(layout.size());
+        let cls = P::size_class_by_layout(layout);
 
         // 2. try to push the ptr to freelist
         if let SizeClass::Base(idx) = cls {
+            // a zero-sized block, see `zero_sized`
             if unlikely(idx == 0) {
                 return;
             }
-            // The thread local free list is full
+            // objects of other threads go back to their owners, unless
+            // they have gone
+            let zone = self.zone();
+            let owner = self.pages.owner_of(ptr, zone);
+            if owner != self.owner
+                && owner != NO_OWNER
+                && remote::push(owner, idx, ptr.as_ptr()).is_ok()
+            {
+                return;
+            }
+            let size = P::rounded_size(idx);
             let size_cache: &mut ThreadCacheUnit = &mut self.list[idx];
-            size_cache.deallocate(idx, ptr, get_rounded_size_by_idx(idx));
+            let released = size_cache.deallocate(idx, ptr, zone);
+            self.bytes = self.bytes + size - released * size;
+            // The thread local cache is full
+            if unlikely(self.bytes > TCACHE_MAX_BYTES) {
+                self.scavenge();
+            }
+            let epoch = TRIM_EPOCH.load(Ordering::Relaxed);
+            if unlikely(epoch != self.epoch) {
+                self.trim(epoch);
+            }
+            self.flush_if_requested();
         } else {
             // 3. for large chunks, the deallocation directly goes to zone
             //todo
-            (*GLOBAL_ZONE).deallocate_large(ptr, layout);
+            self.zone().deallocate_large(ptr, layout);
         }
     }
 
     pub fn handle_delay_case(&mut self, ptr: usize, size: usize) {}
+
+    /// Claims an owner slot, on the first allocation
+    ///
+    /// A cache over [`GLOBAL_ZONE`] holding nothing yet takes over a detached
+    /// cache instead, if any not too old.
+    fn claim(&mut self) {
+        // remote lists give objects back to the global zone only
+        if self.zone.is_null() {
+            let detached = if self.bytes == 0 {
+                let (detached, expired) = DETACHED.lock().take(now_ms());
+                unsafe { expired.destroy() };
+                detached
+            } else {
+                None
+            };
+            match detached {
+                Some(cache) => unsafe { self.take_over(cache) },
+                None => self.owner = remote::claim(),
+            }
+            self.register();
+        }
+        self.claimed = true;
+    }
+
+    /// Lets the background thread request a flush of this cache once idle,
+    /// see [`trim_idle`]
+    ///
+    /// Only caches over [`GLOBAL_ZONE`] holding an owner slot are tracked.
+    fn register(&mut self) {
+        if self.zone.is_null() && self.owner != NO_OWNER {
+            let slot = self.owner as usize - 1;
+            let mut live = LIVE.lock();
+            live.caches[slot] = self as *mut Self as *mut ThreadCache;
+            live.seen[slot] = Seen::new();
+        }
+    }
+
+    /// Stops tracking this cache, no flush is requested past this point
+    fn unregister(&mut self) {
+        if self.owner != NO_OWNER {
+            let mut live = LIVE.lock();
+            let slot = &mut live.caches[self.owner as usize - 1];
+            if *slot == self as *mut Self as *mut ThreadCache {
+                *slot = null_mut();
+            }
+        }
+    }
+
+    /// Takes the objects and the owner slot of the detached cache `cache`,
+    /// which is freed
+    ///
+    /// # Safety
+    /// `cache` must come from `META_BUMP`, and this cache hold nothing.
+    unsafe fn take_over(&mut self, cache: *mut ThreadCache) {
+        debug_assert!(self.zone.is_null() && self.bytes == 0);
+        // only `ThreadCache::new` leaves the zone null, so `P` is the default
+        core::ptr::copy_nonoverlapping(cache as *const Self, self, 1);
+        self.flush_requested = AtomicBool::new(false);
+        META_BUMP.lock().dealloc(cache as *mut usize);
+    }
+}
+
+/// Slots of the live caches, indexed by owner - 1
+struct LiveCaches {
+    caches: [*mut ThreadCache; remote::MAX_OWNERS],
+    seen: [Seen; remote::MAX_OWNERS],
+}
+
+unsafe impl Send for LiveCaches {}
+
+impl LiveCaches {
+    const fn new() -> Self {
+        Self {
+            caches: [null_mut(); remote::MAX_OWNERS],
+            seen: [Seen::new(); remote::MAX_OWNERS],
+        }
+    }
+}
+
+/// The operations of a live cache at the last idle check, and when they
+/// last changed
+#[derive(Clone, Copy)]
+struct Seen {
+    ops: usize,
+    since: u64,
+}
+
+impl Seen {
+    const fn new() -> Self {
+        Self {
+            ops: usize::MAX,
+            since: 0,
+        }
+    }
+
+    /// Whether a cache at `ops` operations has not been used for
+    /// `TCACHE_IDLE_MS` at `now`
+    ///
+    /// Idleness is seen from `ops`, so the first check after some use only
+    /// records the time.
+    fn idle(&mut self, ops: usize, now: u64) -> bool {
+        if ops != self.ops {
+            self.ops = ops;
+            self.since = now;
+            false
+        } else {
+            now.saturating_sub(self.since) >= TCACHE_IDLE_MS
+        }
+    }
+}
+
+/// Asks the caches of live threads that have not allocated nor freed for
+/// `TCACHE_IDLE_MS` to flush
+///
+/// Each owner thread flushes its cache on its next slow path, the caches are
+/// never touched from here.
+pub fn trim_idle() {
+    let now = now_ms();
+    let mut live = LIVE.lock();
+    let live = &mut *live;
+    for (&cache, seen) in live.caches.iter().zip(live.seen.iter_mut()) {
+        if cache.is_null() {
+            continue;
+        }
+        // the slot is cleared under `LIVE` before the cache goes away, and
+        // only its atomics are shared with the owner thread
+        let (ops, requested) = unsafe {
+            (
+                &*core::ptr::addr_of!((*cache).ops),
+                &*core::ptr::addr_of!((*cache).flush_requested),
+            )
+        };
+        if seen.idle(ops.load(Ordering::Relaxed), now) {
+            requested.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stack of detached caches, the most recently detached on top
+///
+/// Caches detached for `max_age` ms are taken out by every operation, and
+/// handed back to the caller to destroy once the lock of the pool is
+/// released.
+struct DetachedPool {
+    caches: [*mut ThreadCache; TCACHE_POOL_CAP],
+    /// When each cache was detached
+    since: [u64; TCACHE_POOL_CAP],
+    len: usize,
+    max_age: u64,
+}
+
+unsafe impl Send for DetachedPool {}
+
+impl DetachedPool {
+    const fn new(max_age: u64) -> Self {
+        Self {
+            caches: [null_mut(); TCACHE_POOL_CAP],
+            since: [0; TCACHE_POOL_CAP],
+            len: 0,
+            max_age,
+        }
+    }
+
+    /// Keeps `cache`, detached at `now`
+    ///
+    /// Returns false if the pool is full, and the caches found expired.
+    fn put(&mut self, cache: *mut ThreadCache, now: u64) -> (bool, Expired) {
+        let expired = self.take_expired(now);
+        if self.len == TCACHE_POOL_CAP {
+            return (false, expired);
+        }
+        self.caches[self.len] = cache;
+        self.since[self.len] = now;
+        self.len += 1;
+        (true, expired)
+    }
+
+    /// Takes the most recently detached cache, the warmest one, also
+    /// returning the caches found expired
+    fn take(&mut self, now: u64) -> (Option<*mut ThreadCache>, Expired) {
+        let expired = self.take_expired(now);
+        if self.len == 0 {
+            return (None, expired);
+        }
+        self.len -= 1;
+        (Some(self.caches[self.len]), expired)
+    }
+
+    /// Takes the caches detached for `max_age` ms at `now`
+    fn take_expired(&mut self, now: u64) -> Expired {
+        let expired = self.since[..self.len]
+            .iter()
+            .take_while(|&&since| now.saturating_sub(since) >= self.max_age)
+            .count();
+        let mut taken = Expired {
+            caches: [null_mut(); TCACHE_POOL_CAP],
+            len: expired,
+        };
+        taken.caches[..expired].copy_from_slice(&self.caches[..expired]);
+        self.caches.copy_within(expired..self.len, 0);
+        self.since.copy_within(expired..self.len, 0);
+        self.len -= expired;
+        taken
+    }
+}
+
+/// Caches taken out of a [`DetachedPool`] for their age
+#[must_use]
+struct Expired {
+    caches: [*mut ThreadCache; TCACHE_POOL_CAP],
+    len: usize,
+}
+
+impl Expired {
+    /// Destroys the caches, out of the lock of the pool
+    unsafe fn destroy(self) {
+        for &cache in self.caches[..self.len].iter() {
+            destroy(cache);
+        }
+    }
+}
+
+/// Gives the objects of the detached cache `cache` back to the zone, and
+/// frees it
+unsafe fn destroy(cache: *mut ThreadCache) {
+    // nothing is left to report to, what the zone refuses stays leaked
+    let _ = cache.as_mut().expect("err").cleanup_cache_unchecked();
+    META_BUMP.lock().dealloc(cache as *mut usize);
+}
+
+/// Flushes the caches detached for longer than `TCACHE_POOL_AGE_MS` to the
+/// zone
+pub fn release_detached() {
+    let expired = DETACHED.lock().take_expired(now_ms());
+    unsafe { expired.destroy() };
 }
 
 use super::*;
@@ -224,9 +795,17 @@ use alloc_macros::tls_static;
 unsafe extern "C" fn free_thread_cache(ptr: *mut libc::c_void) {
     // println!("dtor triggered! {:x}", ptr as usize);
     let ptr = ptr as *mut ThreadCache;
-    let tcache = ptr.as_mut().expect("err");
-    tcache.cleanup_cache_unchecked();
-    META_BUMP.lock().dealloc(ptr as *mut usize);
+    (*ptr).unregister();
+    // the cache stays populated for the next thread, unless the pool is full
+    if (*ptr).bytes == 0 {
+        destroy(ptr);
+        return;
+    }
+    let (kept, expired) = DETACHED.lock().put(ptr, now_ms());
+    expired.destroy();
+    if !kept {
+        destroy(ptr);
+    }
 }
 #[cfg(not(feature = "fixed_heap"))]
 tls_static! {
@@ -309,3 +888,225 @@ pub use Fixed_TCache::*;
 //         .unwrap();
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    #[test]
+    fn bounded_free_list_test() {
+        let mut tcache = ThreadCache::new();
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let idx = get_size_class(64).index();
+        let ptrs: Vec<_> = (0..100_000)
+            .map(|_| tcache.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs {
+            tcache.deallocate(ptr, layout);
+        }
+        let max_length = tcache.list[idx].high_water::<DefaultSizeClass>(idx);
+        assert!(tcache.list[idx].list.length() <= max_length);
+        assert!(tcache.cached_bytes() <= TCACHE_MAX_BYTES);
+        tcache.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn byte_budget_test() {
+        let mut tcache = ThreadCache::new();
+        let mut ptrs = Vec::new();
+        for size in (1024..MAX_SIZE).step_by(512) {
+            let layout = Layout::from_size_align(size, 8).expect("err");
+            for _ in 0..64 {
+                ptrs.push((tcache.allocate(layout).expect("err"), layout));
+            }
+        }
+        for (ptr, layout) in ptrs {
+            tcache.deallocate(ptr, layout);
+            assert!(tcache.cached_bytes() <= TCACHE_MAX_BYTES);
+        }
+        tcache.cleanup_cache_unchecked().expect("err");
+        assert_eq!(tcache.cached_bytes(), 0);
+    }
+
+    #[test]
+    fn trim_test() {
+        let mut tcache = ThreadCache::new();
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let idx = get_size_class(64).index();
+        let ptrs: Vec<_> = (0..1000)
+            .map(|_| tcache.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs {
+            tcache.deallocate(ptr, layout);
+        }
+        let before = tcache.list[idx].list.length();
+        request_trim();
+        let ptr = tcache.allocate(layout).expect("err");
+        tcache.deallocate(ptr, layout);
+        assert!(tcache.list[idx].list.length() <= before / 2 + 1);
+        tcache.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn idle_trim_test() {
+        let mut tcache = ThreadCache::new();
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let ptrs: Vec<_> = (0..1000)
+            .map(|_| tcache.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs {
+            tcache.deallocate(ptr, layout);
+        }
+        let bytes = tcache.cached_bytes();
+        assert!(bytes > 0);
+
+        // a cache in use is not idle
+        let mut seen = Seen::new();
+        assert!(!seen.idle(tcache.ops.load(Ordering::Relaxed), 0));
+        assert!(!seen.idle(tcache.ops.load(Ordering::Relaxed), TCACHE_IDLE_MS - 1));
+        let ptr = tcache.allocate(layout).expect("err");
+        tcache.deallocate(ptr, layout);
+        assert!(!seen.idle(tcache.ops.load(Ordering::Relaxed), TCACHE_IDLE_MS));
+        assert!(!seen.idle(tcache.ops.load(Ordering::Relaxed), 2 * TCACHE_IDLE_MS - 1));
+        assert!(seen.idle(tcache.ops.load(Ordering::Relaxed), 2 * TCACHE_IDLE_MS));
+
+        // the owner flushes on request, on its next slow path
+        tcache.flush_requested.store(true, Ordering::Relaxed);
+        assert_eq!(tcache.cached_bytes(), bytes);
+        let ptr = tcache.allocate(layout).expect("err");
+        tcache.deallocate(ptr, layout);
+        assert_eq!(tcache.cached_bytes(), 0);
+        tcache.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn allocate_zeroed_test() {
+        let mut tcache = ThreadCache::new();
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let ptrs: Vec<_> = (0..1000)
+            .map(|_| tcache.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs {
+            unsafe { ptr.as_ptr().write_bytes(0xa5, 64) };
+            tcache.deallocate(ptr, layout);
+        }
+        // reused objects are cleared, fresh ones read as zero
+        let ptrs: Vec<_> = (0..4000)
+            .map(|_| tcache.allocate_zeroed(layout).expect("err"))
+            .collect();
+        for ptr in ptrs {
+            let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 64) };
+            assert!(bytes.iter().all(|&b| b == 0));
+            tcache.deallocate(ptr, layout);
+        }
+        tcache.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn remote_free_test() {
+        let mut producer = ThreadCache::new();
+        let mut consumer = ThreadCache::new();
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let idx = get_size_class(64).index();
+        let ptrs: Vec<_> = (0..1000)
+            .map(|_| producer.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs.iter() {
+            consumer.deallocate(*ptr, layout);
+        }
+        // nothing is kept by the thread freeing the objects
+        assert_eq!(consumer.cached_bytes(), 0);
+
+        // once its own objects run out, the producer reuses the freed ones
+        let local: Vec<_> = (0..producer.list[idx].count())
+            .map(|_| producer.allocate(layout).expect("err"))
+            .collect();
+        let ptr = producer.allocate(layout).expect("err");
+        assert!(ptrs.contains(&ptr));
+
+        producer.deallocate(ptr, layout);
+        for ptr in local {
+            producer.deallocate(ptr, layout);
+        }
+        producer.cleanup_cache_unchecked().expect("err");
+        consumer.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn detached_pool_test() {
+        let mut pool = DetachedPool::new(25);
+        let caches: Vec<_> = (1..=TCACHE_POOL_CAP + 2)
+            .map(|i| (i * 8) as *mut ThreadCache)
+            .collect();
+        for (i, &cache) in caches[..TCACHE_POOL_CAP].iter().enumerate() {
+            let (kept, expired) = pool.put(cache, i as u64 * 10);
+            assert!(kept && expired.len == 0);
+        }
+        // the pool is full, and the first caches are 25 ms old at 35
+        let (kept, expired) = pool.put(caches[TCACHE_POOL_CAP], 35);
+        assert!(kept);
+        assert_eq!(&expired.caches[..expired.len], &caches[..2]);
+        assert_eq!(pool.len, TCACHE_POOL_CAP - 1);
+
+        // the warmest cache goes first
+        let (cache, expired) = pool.take(35);
+        assert_eq!(cache, Some(caches[TCACHE_POOL_CAP]));
+        assert_eq!(expired.len, 0);
+
+        // no stale cache is taken over
+        let (cache, expired) = pool.take(TCACHE_POOL_CAP as u64 * 10 + 15);
+        assert_eq!(cache, None);
+        assert_eq!(&expired.caches[..expired.len], &caches[2..TCACHE_POOL_CAP]);
+        assert_eq!(pool.take_expired(1000).len, 0);
+    }
+
+    #[test]
+    fn take_over_test() {
+        let layout = Layout::from_size_align(64, 8).expect("err");
+        let idx = get_size_class(64).index();
+        let detached = Box::into_raw(Box::new_in(ThreadCache::new(), MetadataAllocator {}));
+        let cache = unsafe { &mut *detached };
+        let ptrs: Vec<_> = (0..100)
+            .map(|_| cache.allocate(layout).expect("err"))
+            .collect();
+        for ptr in ptrs.iter() {
+            cache.deallocate(*ptr, layout);
+        }
+        let (owner, bytes) = (cache.owner, cache.cached_bytes());
+
+        // a new thread starts with the objects of the exited one
+        let mut tcache = ThreadCache::new();
+        unsafe { tcache.take_over(detached) };
+        assert_eq!((tcache.owner, tcache.cached_bytes()), (owner, bytes));
+        let ptr = tcache.allocate(layout).expect("err");
+        assert!(ptrs.contains(&ptr));
+        assert!(tcache.list[idx].count() > 0);
+        tcache.deallocate(ptr, layout);
+        tcache.cleanup_cache_unchecked().expect("err");
+    }
+
+    #[test]
+    fn policy_test() {
+        let zone = Box::leak(Box::new(ZoneAllocator::<MiSizeClass>::new()));
+        let mut tcache = ThreadCache::new_in(zone);
+        let mut ptrs = Vec::new();
+        for size in [1, 24, 72, 1000, 40960, 50_000] {
+            let layout = Layout::from_size_align(size, 8).expect("err");
+            for _ in 0..100 {
+                let ptr = tcache.allocate(layout).expect("err");
+                unsafe { ptr.as_ptr().write_bytes(1, size) };
+                ptrs.push((ptr, layout));
+            }
+        }
+        let idx = MiSizeClass::size_class(72).index();
+        assert_eq!(MiSizeClass::rounded_size(idx), 80);
+        assert!(tcache.list[idx].count() > 0);
+        for (ptr, layout) in ptrs {
+            tcache.deallocate(ptr, layout);
+        }
+        tcache.cleanup_cache_unchecked().expect("err");
+        assert_eq!(tcache.cached_bytes(), 0);
+    }
+}
@@ -352,7 +352,7 @@ impl<const NCPU: usize> PerCpuArray<NCPU> {
                 "5:", // overflow handler
                 // "movzx r10d, word ptr [r10 + {cl}*8 + 6]",
                 signature = const 0x53053053,
-                rseq = in(reg) &RSEQ_ABI as *const _ as usize,
+                rseq = in(reg) rseq_area() as usize,
                 cs_offset = const 8,
                 cpu_id_offset = const 4,
                 shift = const SHIFT,
@@ -460,7 +460,7 @@ impl<const NCPU: usize> PerCpuArray<NCPU> {
                 // commit
                 "5:",
                 signature = const 0x53053053,
-                rseq = in(reg) &RSEQ_ABI as *const _ as usize,
+                rseq = in(reg) rseq_area() as usize,
                 cs_offset = const 8,
                 cpu_id_offset = const 4,
                 shift = const SHIFT,
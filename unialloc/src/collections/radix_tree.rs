@@ -31,17 +31,20 @@ where
     nodes: [AtomicPtr<V>; 1 << 18],
 }
 
+/// Allocates a zeroed node, returns null when out of memory
 pub fn allocate_node<V: TreeNode>() -> *mut V {
     #[cfg(not(feature = "fixed_heap"))]
     {
         let prot = system_alloc::prots::get_prot(true, true, false);
         #[cfg(feature = "hugepage")]
-        unsafe {
-            system_alloc::mmap_huge(core::mem::size_of::<V>(), prot) as *mut V
-        }
+        let ptr = unsafe { system_alloc::mmap_huge(core::mem::size_of::<V>(), prot) };
         #[cfg(not(feature = "hugepage"))]
-        unsafe {
-            system_alloc::mmap(core::mem::size_of::<V>(), prot) as *mut V
+        let ptr = unsafe { system_alloc::mmap(core::mem::size_of::<V>(), prot) };
+        // mmap returns -1 on failure
+        if ptr as usize == usize::MAX {
+            null_mut()
+        } else {
+            ptr as *mut V
         }
     }
     #[cfg(feature = "fixed_heap")]
@@ -49,7 +52,7 @@ pub fn allocate_node<V: TreeNode>() -> *mut V {
         META_BUMP
             .lock()
             .alloc(core::mem::size_of::<V>())
-            .expect("err") as *mut V
+            .unwrap_or(null_mut()) as *mut V
     }
 }
 pub fn deallocate_node<V: TreeNode>(ptr: usize) {
@@ -71,6 +74,9 @@ where
 
         if node_ptr.is_null() {
             node_ptr = allocate_node::<V>();
+            if node_ptr.is_null() {
+                return Err("out of memory");
+            }
             let res = node_ptr_ref.compare_exchange(
                 core::ptr::null_mut(),
                 node_ptr,
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct AllocError(i32);
 
 impl AllocError {
@@ -26,6 +26,9 @@ impl AllocError {
     /// Fatal errors
     pub const EFATAL: Self = AllocError(-8i32);
 
+    /// No such owner (thread cache gone)
+    pub const EOWNER: Self = AllocError(-9i32);
+
     pub fn to_raw_errno(&self) -> i32 {
         self.0
     }
@@ -1,10 +1,14 @@
 use crate::error::AllocError;
 #[cfg(not(feature = "fixed_heap"))]
+use crate::pal::limit;
+#[cfg(not(feature = "fixed_heap"))]
 use crate::pal::sync::general_lock::{
     dynamic_initialize, lock, unlock, OsLock, STATIC_INITIALIZER,
 };
 #[cfg(not(feature = "fixed_heap"))]
 use crate::pal::sys_alloc as system_alloc;
+#[cfg(not(feature = "fixed_heap"))]
+use crate::pal::sysinfo::{os_page_align_down, os_page_align_up};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::{null, null_mut};
 use core::result::Result::{Err, Ok};
@@ -16,6 +20,8 @@ const PG_SIZE: usize = 4096;
 pub struct BumpAlloc {
     check_point: usize,
     current: usize,
+    /// Unused pages of the previous reserve, see [`Self::take_remainder`]
+    remainder: (usize, usize),
 }
 
 impl Default for BumpAlloc {
@@ -26,35 +32,53 @@ impl Default for BumpAlloc {
 
 impl BumpAlloc {
     const DEFAULT_SIZE: usize = 0x100000000;
+    /// Size of the reserves under a hard limit, so that one does not take
+    /// more address space than the limit can ever commit
+    const LIMITED_SIZE: usize = 1 << 26;
+    /// Requests beyond this size are mapped on their own instead of wasting
+    /// most of a reserve
+    const HUGE_SIZE: usize = Self::DEFAULT_SIZE / 4;
     pub const fn new() -> Self {
         Self {
             check_point: 0,
             current: 0,
+            remainder: (0, 0),
         }
     }
 
-    fn init(&mut self) {
+    /// Maps a fresh reserve of at least `min_size` bytes, the current one is
+    /// kept if mapping fails
+    ///
+    /// The pages left of the previous reserve become the remainder. Reserves
+    /// do not count against the limits, their pages do once carved.
+    fn init(&mut self, min_size: usize) -> Result<(), AllocError> {
         #[cfg(not(feature = "fixed_heap"))]
         {
-            //clean up previous
-            if self.check_point != self.current {
-                let size = self.current - self.check_point;
-                unsafe { system_alloc::munmap(self.check_point as *mut u8, size) };
-            }
-            //init now
-            let prot = system_alloc::prots::get_prot(true, true, false);
-            let start = unsafe {
-                let mut ptr = system_alloc::mmap(Self::DEFAULT_SIZE, prot) as *mut u8;
-                // When fail, mmap return -1, which is 0xffffffffffff
-                // So need to use i64 to identify if it fails and return null
-                if ptr as usize == usize::MAX {
-                    ptr = core::ptr::null_mut();
-                }
-                ptr
+            let size = if limit::hard_limit() == 0 {
+                Self::DEFAULT_SIZE
+            } else {
+                Self::LIMITED_SIZE
             };
-            let end = (start as usize + Self::DEFAULT_SIZE) as *mut u8;
-            self.check_point = start as usize;
-            self.current = end as usize;
+            let size = size.max(min_size);
+            let prot = system_alloc::prots::get_prot(true, true, false);
+            let start = unsafe { system_alloc::mmap_uncommitted(size, prot) } as usize;
+            // When fail, mmap return -1, which is 0xffffffffffff
+            if start == usize::MAX {
+                return Err(AllocError::ENOMEM);
+            }
+            // a remainder nobody took goes back to the OS, in whole OS pages,
+            // which may be larger than ours
+            let (low, high) = (
+                os_page_align_up(self.remainder.0),
+                os_page_align_down(self.remainder.1),
+            );
+            if low < high {
+                unsafe { system_alloc::munmap_committed(low as *mut u8, high - low, 0) };
+            }
+            self.remainder = (self.check_point, self.current);
+            self.check_point = start;
+            self.current = start + size;
+            Ok(())
         }
         #[cfg(feature = "fixed_heap")]
         unimplemented!()
@@ -70,12 +94,31 @@ impl BumpAlloc {
         self.check_point
     }
 
+    /// Takes the pages left of the previous reserve when a new one was
+    /// mapped, as `(start, end)`
+    ///
+    /// They were never carved, so they do not count against the limits.
+    pub fn take_remainder(&mut self) -> Option<(usize, usize)> {
+        let (start, end) = core::mem::replace(&mut self.remainder, (0, 0));
+        (start < end).then(|| (start, end))
+    }
+
     pub fn alloc(&mut self, size: usize) -> Result<*mut u8, AllocError> {
         let alloc_size = (size + PG_SIZE - 1) / PG_SIZE * PG_SIZE;
+        #[cfg(not(feature = "fixed_heap"))]
+        if alloc_size > Self::HUGE_SIZE {
+            let prot = system_alloc::prots::get_prot(true, true, false);
+            let ptr = unsafe { system_alloc::mmap(alloc_size, prot) };
+            // mmap returns MAP_FAILED (-1) on failure
+            if ptr as usize == usize::MAX {
+                return Err(AllocError::ENOMEM);
+            }
+            return Ok(ptr);
+        }
         let current_ptr = self.current as usize;
         #[cfg(not(feature = "fixed_heap"))]
         if self.check_point + alloc_size > current_ptr {
-            self.init();
+            self.init(alloc_size)?;
         }
         #[cfg(feature = "fixed_heap")]
         if self.check_point - alloc_size < current_ptr {
@@ -84,6 +127,9 @@ impl BumpAlloc {
         let ptr = self.current as usize;
         #[cfg(not(feature = "fixed_heap"))]
         if let Some(new_ptr) = ptr.checked_sub(alloc_size) {
+            if !limit::commit(alloc_size) {
+                return Err(AllocError::ENOMEM);
+            }
             // Round down to the requested alignment.
             // let new_ptr = new_ptr & !(align - 1);
             self.current = new_ptr;
@@ -0,0 +1,178 @@
+//! Decay of the free pages of the backend
+//!
+//! Pages freed to the backend stay resident, dirty, in its per-CPU caches or
+//! its free lists. Once the dirty decay time has passed they are given to the
+//! OS with `MADV_FREE`, which reclaims them lazily, and become muzzy. Once the
+//! muzzy decay time has passed as well they are dropped with `MADV_DONTNEED`
+//! and become clean. Clean pages do not count against the limits of
+//! `limit` until they are handed out again.
+//! A decay time of zero skips the step, `u64::MAX` never takes it.
+//!
+//! Decay passes run from the slow path of the backend, and from the
+//! background thread once started with [`crate::bg_thread::start`]. [`purge`]
+//! drops every free page at once. Times are read from a coarse clock, which
+//! spares the hot paths the system call.
+use super::FREELIST;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const DEFAULT_DECAY_MS: u64 = 10_000;
+/// A decay time is covered in that many passes
+const PASSES_PER_DECAY: u64 = 10;
+const MAX_PASS_INTERVAL_MS: u64 = 1000;
+
+static DIRTY_DECAY_MS: AtomicU64 = AtomicU64::new(DEFAULT_DECAY_MS);
+static MUZZY_DECAY_MS: AtomicU64 = AtomicU64::new(DEFAULT_DECAY_MS);
+static NEXT_PASS: AtomicU64 = AtomicU64::new(0);
+static DIRTY_BYTES: AtomicUsize = AtomicUsize::new(0);
+static MUZZY_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The coarse clock, see [`now_ms`]
+static CLOCK_MS: AtomicU64 = AtomicU64::new(0);
+/// A thread reads the monotonic clock once in that many backend operations
+const OPS_PER_TICK: u32 = 64;
+/// Backend operations of the thread, see [`op_now_ms`]
+#[cfg(not(feature = "fixed_heap"))]
+#[thread_local]
+static mut OPS: u32 = 0;
+
+/// State of the pages of a free block, from the least to the most resident
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum PageState {
+    Clean,
+    Muzzy,
+    Dirty,
+}
+
+/// Sets how long freed pages stay dirty, in milliseconds
+pub fn set_dirty_decay_ms(ms: u64) {
+    DIRTY_DECAY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Sets how long pages stay muzzy before being dropped, in milliseconds
+pub fn set_muzzy_decay_ms(ms: u64) {
+    MUZZY_DECAY_MS.store(ms, Ordering::Relaxed);
+}
+
+pub fn dirty_decay_ms() -> u64 {
+    DIRTY_DECAY_MS.load(Ordering::Relaxed)
+}
+
+pub fn muzzy_decay_ms() -> u64 {
+    MUZZY_DECAY_MS.load(Ordering::Relaxed)
+}
+
+/// Bytes of dirty free blocks in the backend
+pub fn dirty_bytes() -> usize {
+    DIRTY_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes of muzzy free blocks in the backend
+pub fn muzzy_bytes() -> usize {
+    MUZZY_BYTES.load(Ordering::Relaxed)
+}
+
+/// Runs a decay pass now
+pub fn decay() {
+    unsafe { FREELIST.decay(tick(), false) };
+}
+
+/// Drops every free page of the backend
+pub fn purge() {
+    unsafe { FREELIST.decay(tick(), true) };
+}
+
+/// Milliseconds on the coarse clock
+///
+/// The clock only moves on [`tick`], which the background thread and the
+/// decay passes call, and every thread once in `OPS_PER_TICK` backend
+/// operations. A fixed heap has no clock, so its coarse clock stays at zero.
+#[inline]
+pub(crate) fn now_ms() -> u64 {
+    CLOCK_MS.load(Ordering::Relaxed)
+}
+
+/// Like [`now_ms`], counting a backend operation of the thread
+#[inline]
+pub(crate) fn op_now_ms() -> u64 {
+    #[cfg(not(feature = "fixed_heap"))]
+    unsafe {
+        OPS = OPS.wrapping_add(1);
+        if OPS % OPS_PER_TICK == 0 {
+            return tick();
+        }
+    }
+    now_ms()
+}
+
+/// Moves the coarse clock to the monotonic clock, returns it
+#[cfg(not(feature = "fixed_heap"))]
+pub(crate) fn tick() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let now = ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000;
+    CLOCK_MS.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+#[cfg(feature = "fixed_heap")]
+pub(crate) fn tick() -> u64 {
+    now_ms()
+}
+
+/// State of pages freed at `freed_at`, at `now`
+pub(crate) fn state_at(freed_at: u64, now: u64) -> PageState {
+    let age = now.saturating_sub(freed_at);
+    let dirty = dirty_decay_ms();
+    if age < dirty {
+        PageState::Dirty
+    } else if age < dirty.saturating_add(muzzy_decay_ms()) {
+        PageState::Muzzy
+    } else {
+        PageState::Clean
+    }
+}
+
+/// Whether the slow path should run a pass at `now`, at most one caller wins
+pub(crate) fn claim_pass(now: u64) -> bool {
+    let next = NEXT_PASS.load(Ordering::Relaxed);
+    if now < next {
+        return false;
+    }
+    NEXT_PASS
+        .compare_exchange(
+            next,
+            now + pass_interval(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+fn pass_interval() -> u64 {
+    let shortest = [dirty_decay_ms(), muzzy_decay_ms()]
+        .iter()
+        .filter(|&&ms| ms != 0)
+        .min()
+        .copied()
+        .unwrap_or(MAX_PASS_INTERVAL_MS);
+    (shortest / PASSES_PER_DECAY).clamp(1, MAX_PASS_INTERVAL_MS)
+}
+
+/// Accounts for `size` bytes entering `state`
+pub(crate) fn account(state: PageState, size: usize) {
+    match state {
+        PageState::Dirty => DIRTY_BYTES.fetch_add(size, Ordering::Relaxed),
+        PageState::Muzzy => MUZZY_BYTES.fetch_add(size, Ordering::Relaxed),
+        PageState::Clean => 0,
+    };
+}
+
+/// Accounts for `size` bytes leaving `state`
+pub(crate) fn unaccount(state: PageState, size: usize) {
+    match state {
+        PageState::Dirty => DIRTY_BYTES.fetch_sub(size, Ordering::Relaxed),
+        PageState::Muzzy => MUZZY_BYTES.fetch_sub(size, Ordering::Relaxed),
+        PageState::Clean => 0,
+    };
+}
@@ -1,6 +1,18 @@
+//! Page heap of the backend
+//!
+//! Freed runs of pages land in the per-CPU caches of [`page_cache`] first.
+//! Behind them, the central heap keeps the free blocks in a list per length,
+//! with boundary tags in the page map at their first and last pages, and
+//! carves fresh pages from `BUMP`. The lists and the tags change under
+//! `HEAP_LOCK` only, so a free always sees the tags of its neighbours as they
+//! are and merges with them safely.
 #[cfg(not(feature = "fixed_heap"))]
 use crate::pal::sys_alloc as system_alloc;
+#[cfg(not(feature = "fixed_heap"))]
+use crate::pal::sysinfo::{os_page_align_down, os_page_align_up};
 mod bump;
+pub mod decay;
+mod page_cache;
 use crate::collections::radix_tree::{get_rd_tree, RadixTree, TreeNode};
 use crate::error::AllocError;
 use crate::sc::{align_12k, META_BUMP};
@@ -8,27 +20,77 @@ use crate::size_class::BACKEND_MAX_PAGE;
 use bump::BumpAlloc;
 use core::alloc::{Allocator, GlobalAlloc, Layout};
 use core::borrow::BorrowMut;
+use core::ops::Range;
 use core::ptr::{null, null_mut, NonNull};
 use core::sync::atomic::{AtomicPtr, Ordering};
+use decay::PageState;
+use page_cache::PageCache;
 use spin::Mutex;
 
 const PG_SIZE: usize = 4096;
+/// Zeroing past this many bytes drops the pages instead, which beats the
+/// memset even counting the faults when they are touched again
+#[cfg(not(feature = "fixed_heap"))]
+const PURGE_ZERO_MIN: usize = 1 << 18;
 
 pub static mut BUMP: Mutex<BumpAlloc> = Mutex::new(BumpAlloc::new());
 
+/// Guards the lists of the heaps and the tags of their blocks, never held
+/// while carving pages from `BUMP`
+static HEAP_LOCK: Mutex<()> = Mutex::new(());
+
 struct DoubleLinkedList {
     prev: Option<*mut DoubleLinkedList>,
     next: Option<*mut DoubleLinkedList>,
+    /// State of the pages of the block, past the page holding this node
+    ///
+    /// The most resident state of the parts the block merged from.
+    state: PageState,
+    /// When the newest pages of the block were freed, in milliseconds
+    freed_at: u64,
+    /// Bytes of the parts of the block still dirty, and muzzy, the rest being
+    /// clean
+    dirty: usize,
+    muzzy: usize,
+    /// Addresses of the block known to read as zero, past this node
+    zeros: (usize, usize),
+    /// Bytes of the block the OS dropped or never backed, which do not count
+    /// against the limits until the block is taken again
+    purged: usize,
 }
 
 impl DoubleLinkedList {
-    const fn new() -> Self {
+    const fn new(
+        state: PageState,
+        freed_at: u64,
+        dirty: usize,
+        muzzy: usize,
+        zeros: (usize, usize),
+        purged: usize,
+    ) -> Self {
         Self {
             prev: None,
             next: None,
+            state,
+            freed_at,
+            dirty,
+            muzzy,
+            zeros,
+            purged,
         }
     }
 
+    /// Counts the bytes of the block in the totals of their states
+    fn account(&self) {
+        decay::account(PageState::Dirty, self.dirty);
+        decay::account(PageState::Muzzy, self.muzzy);
+    }
+
+    fn unaccount(&self) {
+        decay::unaccount(PageState::Dirty, self.dirty);
+        decay::unaccount(PageState::Muzzy, self.muzzy);
+    }
+
     fn push_before_head(&'static mut self, new_node: *mut DoubleLinkedList) {
         self.prev = Some(new_node);
         let self_ptr = self as *const _ as *mut DoubleLinkedList;
@@ -61,125 +123,533 @@ impl DoubleLinkedList {
 }
 
 pub struct FreeList {
-    lists: AtomicPtr<Mutex<Option<&'static mut DoubleLinkedList>>>,
+    lists: AtomicPtr<Option<&'static mut DoubleLinkedList>>,
+    cache: PageCache,
 }
 
 impl FreeList {
     const fn new() -> Self {
         Self {
             lists: AtomicPtr::new(null_mut()),
+            cache: PageCache::new(),
         }
     }
 
+    /// Takes the first free block of `idx + 1` pages, `HEAP_LOCK` held
     fn remove_one(&mut self, idx: usize) -> Option<*mut u8> {
-        let cur: &mut Mutex<Option<&'static mut DoubleLinkedList>> = &mut self.get_slice()[idx];
-        // if cur.get_mut().is_some() {
-        let mut locked = cur.lock();
-        if let Some(to_remove) = locked.take() {
-            let ans = to_remove as *const _ as *mut u8;
-            let rd_tree = get_rd_tree();
-            rd_tree.remove((ans as usize) << 16, 1).expect("err");
-            rd_tree
-                .remove((ans as usize + idx * PG_SIZE) << 16, 1)
-                .expect("err");
-            if let Some(next) = to_remove.remove_current() {
-                *locked = Some(next);
-            } else {
-                *locked = None;
-            }
-            return Some(ans);
-        }
-        // }
-        None
+        let to_remove = self.get_slice()[idx].take()?;
+        let ans = to_remove as *const _ as *mut u8;
+        let rd_tree = get_rd_tree();
+        rd_tree.remove((ans as usize) << 16, 1).expect("err");
+        rd_tree
+            .remove((ans as usize + idx * PG_SIZE) << 16, 1)
+            .expect("err");
+        to_remove.unaccount();
+        self.get_slice()[idx] = to_remove.remove_current();
+        Some(ans)
     }
 
+    /// Takes the free block of `idx + 1` pages starting at `ptr` out of its
+    /// list, `HEAP_LOCK` held
+    ///
+    /// Returns `None` if no such block is in the list.
     fn remove_spec_one(
         &mut self,
         idx: usize,
         ptr: *mut DoubleLinkedList,
         rd_tree: &mut RadixTree,
     ) -> Option<*mut u8> {
-        let cur: &mut Mutex<Option<&'static mut DoubleLinkedList>> = &mut self.get_slice()[idx];
-        // if cur.get_mut().is_some() {
-        let mut locked = cur.lock();
-        if -rd_tree.get_mut((ptr as usize) << 16) > 0 {
-            let target = DoubleLinkedList::get_ref(ptr);
-            if let Some(prev_next) = target.next {
-                if prev_next as usize == ptr as usize {
-                    rd_tree.remove((ptr as usize) << 16, 1).expect("err");
-                    rd_tree
-                        .remove((ptr as usize + idx * PG_SIZE) << 16, 1)
-                        .expect("err");
-                    if let Some(new_head) = target.remove_current() {
-                        *locked = Some(new_head);
+        if rd_tree.get_mut((ptr as usize) << 16) != -(idx as i64 + 1) << 48 {
+            return None;
+        }
+        rd_tree.remove((ptr as usize) << 16, 1).expect("err");
+        rd_tree
+            .remove((ptr as usize + idx * PG_SIZE) << 16, 1)
+            .expect("err");
+        let target = DoubleLinkedList::get_ref(ptr);
+        target.unaccount();
+        if target.prev.is_none() {
+            // the head of the list
+            self.get_slice()[idx] = target.remove_current();
+        } else {
+            target.remove_current();
+        }
+        Some(ptr as *mut u8)
+    }
+
+    /// Inserts the free block `node` of `idx + 1` pages, `HEAP_LOCK` held
+    ///
+    /// Fails if the radix tree cannot track the block, the list is unchanged
+    /// then.
+    fn insert_one(
+        &mut self,
+        idx: usize,
+        node: &'static mut DoubleLinkedList,
+    ) -> Result<(), AllocError> {
+        let start = node as *const _ as usize;
+        let end = start + idx * PG_SIZE;
+        let rd_tree = get_rd_tree();
+        rd_tree
+            .insert(start << 16, (-(idx as i64 + 1)) << 48, 1)
+            .or(Err(AllocError::ENOMEM))?;
+        if rd_tree
+            .insert(end << 16, -(idx as i64 + 1) << 48, 1)
+            .is_err()
+        {
+            rd_tree.remove(start << 16, 1).expect("err");
+            return Err(AllocError::ENOMEM);
+        }
+        node.account();
+        let head = &mut self.get_slice()[idx];
+        if let Some(to_remove) = head.take() {
+            to_remove.push_before_head(node);
+        }
+        *head = Some(node);
+        Ok(())
+    }
+
+    /// Allocates `size` bytes aligned to `align`
+    ///
+    /// Alignments beyond a page are met by over-allocating `align - PG_SIZE`
+    /// bytes. The pages before and after the aligned block go back to the list.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<*mut u8, AllocError> {
+        self.alloc_aligned_run(size, align).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocates `size` zeroed bytes aligned to `align`
+    ///
+    /// Only the bytes that may not be zero are cleared, see
+    /// [`Self::alloc_run`].
+    pub fn alloc_zeroed(&mut self, size: usize, align: usize) -> Result<*mut u8, AllocError> {
+        let size = Self::round_up(size);
+        let (ptr, zeros) = self.alloc_aligned_run(size, align)?;
+        Self::zero(ptr, zeros.start);
+        Self::zero(ptr.wrapping_add(zeros.end), size - zeros.end);
+        Ok(ptr)
+    }
+
+    /// Allocates `size` bytes aligned to `align`, also returning whether they
+    /// all read as zero
+    pub fn alloc_maybe_zeroed(
+        &mut self,
+        size: usize,
+        align: usize,
+    ) -> Result<(*mut u8, bool), AllocError> {
+        let size = Self::round_up(size);
+        let (ptr, zeros) = self.alloc_aligned_run(size, align)?;
+        Ok((ptr, zeros == (0..size)))
+    }
+
+    /// Clears `[ptr, ptr + len)`, letting the OS drop the whole pages of a
+    /// long range
+    fn zero(ptr: *mut u8, len: usize) {
+        #[cfg(not(feature = "fixed_heap"))]
+        if len >= PURGE_ZERO_MIN {
+            let start = os_page_align_up(ptr as usize);
+            let end = os_page_align_down(ptr as usize + len);
+            unsafe {
+                system_alloc::purge(start as *mut u8, end - start);
+                ptr.write_bytes(0, start - ptr as usize);
+                (end as *mut u8).write_bytes(0, ptr as usize + len - end);
+            }
+            return;
+        }
+        unsafe { ptr.write_bytes(0, len) };
+    }
+
+    /// Like [`Self::alloc_aligned`], also returning the offsets of the block
+    /// known to read as zero
+    fn alloc_aligned_run(
+        &mut self,
+        size: usize,
+        align: usize,
+    ) -> Result<(*mut u8, Range<usize>), AllocError> {
+        if align <= PG_SIZE {
+            return self.alloc_run(size);
+        }
+        let size = Self::round_up(size);
+        let total = size
+            .checked_add(align - PG_SIZE)
+            .ok_or(AllocError::ENOMEM)?;
+        let (start, zeros) = self.alloc_run(total)?;
+        let start = start as usize;
+        let aligned = (start + align - 1) & !(align - 1);
+        let head = aligned - start;
+        let tail = start + total - (aligned + size);
+        if head > 0 {
+            self.free(start as *mut u8, head);
+        }
+        if tail > 0 {
+            self.free((aligned + size) as *mut u8, tail);
+        }
+        let zeros = Self::clamp(
+            (start + zeros.start, start + zeros.end),
+            aligned,
+            aligned + size,
+        );
+        Ok((aligned as *mut u8, zeros.0 - aligned..zeros.1 - aligned))
+    }
+
+    /// Part of the range `zeros` within `[start, end)`, `(start, start)` if
+    /// none
+    fn clamp(zeros: (usize, usize), start: usize, end: usize) -> (usize, usize) {
+        let low = zeros.0.max(start);
+        let high = zeros.1.min(end);
+        if low < high {
+            (low, high)
+        } else {
+            (start, start)
+        }
+    }
+
+    /// Gives the pages of `[start, end)` back to the OS, leaving them `state`
+    ///
+    /// Only the pages of the OS fully inside the range are given back. Returns
+    /// the range of the pages that read as zero from now on, empty unless
+    /// they go clean.
+    #[cfg(not(feature = "fixed_heap"))]
+    fn advise(start: usize, end: usize, state: PageState) -> (usize, usize) {
+        let start = os_page_align_up(start);
+        let end = os_page_align_down(end);
+        if start >= end {
+            return (0, 0);
+        }
+        let ptr = start as *mut u8;
+        match state {
+            PageState::Muzzy => unsafe { system_alloc::purge_lazy(ptr, end - start) },
+            PageState::Clean => {
+                unsafe { system_alloc::purge(ptr, end - start) };
+                return (start, end);
+            }
+            PageState::Dirty => {}
+        }
+        (0, 0)
+    }
+
+    #[cfg(feature = "fixed_heap")]
+    fn advise(_start: usize, _end: usize, _state: PageState) -> (usize, usize) {
+        (0, 0)
+    }
+
+    /// Moves the free blocks whose decay time has passed to their next state
+    ///
+    /// The runs of the per-CPU caches due to decay go to the lists first.
+    /// With `force` every free block is dropped at once. The OS page holding
+    /// the node of a block, and the partial one at its end, stay dirty.
+    pub fn decay(&mut self, now: u64, force: bool) {
+        for (ptr, pages, freed_at) in self.cache.take_due(now, force) {
+            self.release(ptr, pages * PG_SIZE, freed_at, 0);
+        }
+        if self.lists.load(Ordering::Acquire).is_null() {
+            return;
+        }
+        let _heap = HEAP_LOCK.lock();
+        for (idx, head) in self.get_slice().iter().enumerate() {
+            let size = (idx + 1) * PG_SIZE;
+            let mut next = head
+                .as_ref()
+                .map(|node| &**node as *const _ as *mut DoubleLinkedList);
+            while let Some(ptr) = next {
+                let node = DoubleLinkedList::get_ref(ptr);
+                let target = if force {
+                    PageState::Clean
+                } else {
+                    decay::state_at(node.freed_at, now)
+                };
+                if target < node.state {
+                    let start = ptr as usize + core::mem::size_of::<DoubleLinkedList>();
+                    let zeros = Self::advise(start, ptr as usize + size, target);
+                    if target == PageState::Clean {
+                        node.zeros = Self::larger(node.zeros, zeros);
+                        Self::drop_purged(&mut node.purged, zeros);
                     }
-                    return Some(ptr as *mut u8);
+                    node.unaccount();
+                    // the dirty parts go muzzy, or all parts clean
+                    node.muzzy = match target {
+                        PageState::Muzzy => node.dirty + node.muzzy,
+                        _ => 0,
+                    };
+                    node.dirty = 0;
+                    node.state = target;
+                    node.account();
                 }
+                next = node.next;
             }
         }
-        None
     }
 
-    fn insert_one(&mut self, idx: usize, node: &'static mut DoubleLinkedList) {
-        let cur: &mut Mutex<Option<&'static mut DoubleLinkedList>> = &mut self.get_slice()[idx];
-        let mut locked = cur.lock();
-        if let Some(to_remove) = locked.take() {
-            to_remove.push_before_head(node);
-        }
-        let rd_tree = get_rd_tree();
-        rd_tree
-            .insert(
-                (node as *const _ as usize) << 16,
-                (-(idx as i64 + 1)) << 48,
-                1,
-            )
-            .expect("err");
-        rd_tree
-            .insert(
-                (node as *const _ as usize + idx * PG_SIZE) << 16,
-                -(idx as i64 + 1) << 48,
-                1,
-            )
-            .expect("err");
-        *locked = Some(node);
+    /// Rounds `size` up to whole pages, a zero-sized block takes a page
+    fn round_up(size: usize) -> usize {
+        (size.max(1) + PG_SIZE - 1) / PG_SIZE * PG_SIZE
     }
 
     pub fn alloc(&mut self, size: usize) -> Result<*mut u8, AllocError> {
-        let origin_size = (size + PG_SIZE - 1) / PG_SIZE - 1;
-        if origin_size < self.get_slice().len() {
+        self.alloc_run(size).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocates `size` bytes, also returning the offsets of the block known
+    /// to read as zero
+    ///
+    /// Fresh pages of the OS read as zeros, and so do the pages of free blocks
+    /// purged while clean, see [`Self::advise`]. Runs of the per-CPU caches
+    /// are dirty.
+    fn alloc_run(&mut self, size: usize) -> Result<(*mut u8, Range<usize>), AllocError> {
+        let size = Self::round_up(size);
+        let origin_size = size / PG_SIZE - 1;
+        let now = decay::op_now_ms();
+        if decay::claim_pass(now) {
+            self.decay(now, false);
+        }
+        if origin_size < page_cache::CACHED_PAGES {
+            if let Some(ans) = self.cache.take(origin_size + 1) {
+                return Ok((ans, 0..0));
+            }
+        }
+        let lists = self.try_get_slice()?.len();
+        let _heap = HEAP_LOCK.lock();
+        if origin_size < lists {
             if let Some(ans) = self.remove_one(origin_size) {
-                return Ok(ans);
+                let purged = DoubleLinkedList::get_ref(ans as *mut DoubleLinkedList).purged;
+                if !Self::commit(purged) {
+                    self.put_back(ans as usize, origin_size + 1);
+                    return Err(AllocError::ENOMEM);
+                }
+                return Ok((ans, Self::zeros_of(ans, size)));
             }
             //another complex case, we first iterate all its parents to find if we can get one, then
             //fall into bump alloc
             let mut parent_idx = origin_size + 1;
             while parent_idx < self.get_slice().len() {
                 if let Some(parent) = self.remove_one(parent_idx) {
-                    let remain = (parent as usize) + PG_SIZE * (origin_size + 1);
-                    let node: &'static mut DoubleLinkedList = unsafe {
-                        core::ptr::write(
-                            remain as *const DoubleLinkedList as *mut DoubleLinkedList,
-                            DoubleLinkedList::new(),
-                        );
-                        (remain as *const DoubleLinkedList as *mut DoubleLinkedList)
-                            .as_mut()
-                            .expect("err")
-                    };
-                    self.insert_one(parent_idx - origin_size - 1, node);
-                    return Ok(parent);
+                    let zeros = Self::zeros_of(parent, size);
+                    let purged =
+                        Self::purged_taken(parent as usize, origin_size + 1, parent_idx + 1);
+                    if !Self::commit(purged) {
+                        self.put_back(parent as usize, parent_idx + 1);
+                        return Err(AllocError::ENOMEM);
+                    }
+                    if self
+                        .split_off(parent as usize, origin_size + 1, parent_idx + 1)
+                        .is_err()
+                    {
+                        Self::uncommit(purged);
+                        self.put_back(parent as usize, parent_idx + 1);
+                        return Err(AllocError::ENOMEM);
+                    }
+                    return Ok((parent, zeros));
                 }
                 parent_idx += 1;
             }
             //fall into bump alloc
         }
-        return unsafe { BUMP.lock().alloc(size) };
+        // carving may map a reserve, and a failing map runs the OOM handler,
+        // which may free to this heap
+        drop(_heap);
+        let (ptr, remainder) = unsafe {
+            let mut bump = BUMP.lock();
+            (bump.alloc(size)?, bump.take_remainder())
+        };
+        if let Some((start, end)) = remainder {
+            self.release(start as *mut u8, end - start, now, end - start);
+        }
+        // a fixed heap may hold anything
+        #[cfg(feature = "fixed_heap")]
+        return Ok((ptr, 0..0));
+        #[cfg(not(feature = "fixed_heap"))]
+        Ok((ptr, 0..size))
+    }
+
+    /// Offsets known to read as zero of the first `size` bytes of the taken
+    /// free block `block`
+    fn zeros_of(block: *mut u8, size: usize) -> Range<usize> {
+        let start = block as usize;
+        let node = DoubleLinkedList::get_ref(block as *mut DoubleLinkedList);
+        let zeros = Self::clamp(node.zeros, start, start + size);
+        zeros.0 - start..zeros.1 - start
+    }
+
+    /// Puts back the pages of the taken free block `block` of `pages` pages
+    /// past its first `taken` ones
+    ///
+    /// Fails if the page map cannot track the remainder, which then stays
+    /// part of the taken block.
+    fn split_off(&mut self, block: usize, taken: usize, pages: usize) -> Result<(), AllocError> {
+        if taken == pages {
+            return Ok(());
+        }
+        // the remainder keeps the pages, and so the state, of the block, and
+        // as many of its dirty, muzzy and purged bytes as it can hold
+        let parent = DoubleLinkedList::get_ref(block as *mut DoubleLinkedList);
+        let size = (pages - taken) * PG_SIZE;
+        let dirty = parent.dirty.min(size);
+        let muzzy = parent.muzzy.min(size - dirty);
+        let purged = parent.purged.min(size);
+        let remain = block + PG_SIZE * taken;
+        // but for the bytes its node takes
+        let zeros = Self::clamp(
+            parent.zeros,
+            remain + core::mem::size_of::<DoubleLinkedList>(),
+            block + pages * PG_SIZE,
+        );
+        let (state, freed_at) = (parent.state, parent.freed_at);
+        let node: &'static mut DoubleLinkedList = unsafe {
+            core::ptr::write(
+                remain as *const DoubleLinkedList as *mut DoubleLinkedList,
+                DoubleLinkedList::new(state, freed_at, dirty, muzzy, zeros, purged),
+            );
+            (remain as *const DoubleLinkedList as *mut DoubleLinkedList)
+                .as_mut()
+                .expect("err")
+        };
+        self.insert_one(pages - taken - 1, node)
     }
 
+    /// Purged bytes of the taken free block `block` of `pages` pages its first
+    /// `taken` pages get, those the remainder cannot hold, see
+    /// [`Self::split_off`]
+    fn purged_taken(block: usize, taken: usize, pages: usize) -> usize {
+        let node = DoubleLinkedList::get_ref(block as *mut DoubleLinkedList);
+        node.purged - node.purged.min((pages - taken) * PG_SIZE)
+    }
+
+    /// Counts `size` purged bytes of a block taken again against the limits,
+    /// returns false if they would cross the hard limit
+    fn commit(size: usize) -> bool {
+        #[cfg(not(feature = "fixed_heap"))]
+        return size == 0 || crate::pal::limit::commit(size);
+        #[cfg(feature = "fixed_heap")]
+        true
+    }
+
+    /// Undoes [`Self::commit`]
+    #[allow(unused_variables)]
+    fn uncommit(size: usize) {
+        #[cfg(not(feature = "fixed_heap"))]
+        crate::pal::limit::uncommit(size);
+    }
+
+    /// Adds the bytes of `zeros`, which the OS just dropped, to the `purged`
+    /// ones of a block and stops counting them against the limits
+    ///
+    /// `zeros` covers the parts of the block purged before, so only the
+    /// bytes beyond those are new.
+    fn drop_purged(purged: &mut usize, zeros: (usize, usize)) {
+        let more = (zeros.1 - zeros.0).saturating_sub(*purged);
+        Self::uncommit(more);
+        *purged += more;
+    }
+
+    /// Puts the taken free block `block` of `pages` pages back whole,
+    /// `HEAP_LOCK` held
+    ///
+    /// If the page map cannot track it either, the block goes back to the OS.
+    fn put_back(&mut self, block: usize, pages: usize) {
+        let node = DoubleLinkedList::get_ref(block as *mut DoubleLinkedList);
+        // the links of the node are stale since it was taken
+        node.prev = None;
+        node.next = None;
+        let purged = node.purged;
+        if self.insert_one(pages - 1, node).is_err() {
+            Self::unmap(block, pages * PG_SIZE, purged);
+        }
+    }
+
+    /// Gives the whole OS pages of `[start, start + size)` back to the OS,
+    /// `purged` bytes of which no longer counted against the limits
+    #[allow(unused_variables)]
+    fn unmap(start: usize, size: usize, purged: usize) {
+        #[cfg(not(feature = "fixed_heap"))]
+        {
+            let low = os_page_align_up(start);
+            let high = os_page_align_down(start + size);
+            if low < high {
+                let committed = (high - low).saturating_sub(purged);
+                unsafe { system_alloc::munmap_committed(low as *mut u8, high - low, committed) };
+            }
+        }
+    }
+
+    /// Grows the block at `ptr` from `size` to `new_size` bytes over the free
+    /// block right after it, which cached runs never are
+    ///
+    /// Returns false, leaving everything as is, if that block is missing or
+    /// too small.
+    pub fn grow_in_place(&mut self, ptr: *mut u8, size: usize, new_size: usize) -> bool {
+        let size = Self::round_up(size);
+        let new_size = Self::round_up(new_size);
+        if new_size <= size {
+            return true;
+        }
+        let extra = (new_size - size) / PG_SIZE;
+        let next = ptr as usize + size;
+        let _heap = HEAP_LOCK.lock();
+        let rd_tree = get_rd_tree();
+        let nflag = -(rd_tree.get_mut(next << 16) >> 48);
+        if nflag <= 0 || (nflag as usize) < extra || nflag as usize > BACKEND_MAX_PAGE {
+            return false;
+        }
+        let nidx = nflag as usize - 1;
+        if self
+            .remove_spec_one(nidx, next as *mut DoubleLinkedList, rd_tree)
+            .is_none()
+        {
+            return false;
+        }
+        let purged = Self::purged_taken(next, extra, nidx + 1);
+        if !Self::commit(purged) {
+            self.put_back(next, nidx + 1);
+            return false;
+        }
+        if self.split_off(next, extra, nidx + 1).is_err() {
+            Self::uncommit(purged);
+            self.put_back(next, nidx + 1);
+            return false;
+        }
+        true
+    }
+
+    /// Frees the block `ptr` of `size` bytes
+    ///
+    /// Short runs are kept by the cache of the current CPU while they are
+    /// dirty.
     pub fn free(&mut self, ptr: *mut u8, size: usize) {
-        let origin_size = (size + PG_SIZE - 1) / PG_SIZE - 1;
+        let pages = Self::round_up(size) / PG_SIZE;
+        let now = decay::op_now_ms();
+        if pages <= page_cache::CACHED_PAGES
+            && decay::state_at(now, now) == PageState::Dirty
+            && self.cache.put(ptr, pages, now)
+        {
+            return;
+        }
+        self.release(ptr, size, now, 0);
+    }
+
+    /// Puts the block `ptr` of `size` bytes freed at `freed_at` in the
+    /// lists, merged with the free blocks around it
+    ///
+    /// `purged` of its bytes do not count against the limits, all of them
+    /// for pages of a reserve that were never carved.
+    fn release(&mut self, ptr: *mut u8, size: usize, freed_at: u64, purged: usize) {
+        let origin_size = Self::round_up(size) / PG_SIZE - 1;
 
         let mut final_ptr = ptr;
         let mut final_idx = origin_size;
+        // the block takes the most resident state and the newest free time
+        // of its parts, and counts the bytes of each part in its own state.
+        // The largest range known to be zero among the parts is kept.
+        let now = decay::now_ms();
+        let fresh = decay::state_at(freed_at, now);
+        let mut state = fresh;
+        let mut newest = freed_at;
+        let (mut dirty, mut muzzy) = match fresh {
+            PageState::Dirty => (Self::round_up(size), 0),
+            PageState::Muzzy => (0, Self::round_up(size)),
+            PageState::Clean => (0, 0),
+        };
+        let mut zeros = (0, 0);
+        let mut fresh_purged = purged;
+        let mut purged = 0;
+        let heap = HEAP_LOCK.lock();
         let rd_tree = get_rd_tree();
         //check prev
         let prev = ptr as usize - PG_SIZE;
@@ -192,6 +662,13 @@ impl FreeList {
                 rd_tree,
             ) {
                 //successfully combine with previous
+                let block = DoubleLinkedList::get_ref(prev_ptr as *mut _);
+                state = state.max(block.state);
+                newest = newest.max(block.freed_at);
+                dirty += block.dirty;
+                muzzy += block.muzzy;
+                purged += block.purged;
+                zeros = Self::larger(zeros, block.zeros);
                 final_ptr = prev_ptr;
                 final_idx += pflag as usize;
             }
@@ -210,61 +687,92 @@ impl FreeList {
                 .is_some()
             {
                 //successfully combine with next
+                // its node leaves a dirty page inside the block, which waits
+                // for the next pass to be clean again
+                let block = DoubleLinkedList::get_ref(start as *mut _);
+                state = state.max(block.state).max(PageState::Muzzy);
+                newest = newest.max(block.freed_at);
+                dirty += block.dirty;
+                muzzy += block.muzzy;
+                purged += block.purged;
+                zeros = Self::larger(zeros, block.zeros);
                 final_idx += nflag as usize;
             }
         }
         if final_idx < self.get_slice().len() {
+            // without a dirty decay time the freed pages skip the dirty state
+            let start =
+                (ptr as usize).max(final_ptr as usize + core::mem::size_of::<DoubleLinkedList>());
+            let fresh_zeros = Self::advise(start, ptr as usize + Self::round_up(size), fresh);
+            zeros = Self::larger(zeros, fresh_zeros);
+            Self::drop_purged(&mut fresh_purged, fresh_zeros);
+            let purged = purged + fresh_purged;
             let node: &'static mut DoubleLinkedList = unsafe {
                 core::ptr::write(
                     final_ptr as *const _ as *mut DoubleLinkedList,
-                    DoubleLinkedList::new(),
+                    DoubleLinkedList::new(state, newest, dirty, muzzy, zeros, purged),
                 );
                 (final_ptr as *const _ as *mut DoubleLinkedList)
                     .as_mut()
                     .expect("err")
             };
-            self.insert_one(final_idx, node);
+            if self.insert_one(final_idx, node).is_ok() {
+                return;
+            }
+        }
+        drop(heap);
+        // too large to keep, or the tree cannot track it: back to the OS
+        Self::unmap(
+            final_ptr as usize,
+            (final_idx + 1) * PG_SIZE,
+            purged + fresh_purged,
+        );
+    }
+
+    /// The longer of the ranges `a` and `b`
+    fn larger(a: (usize, usize), b: (usize, usize)) -> (usize, usize) {
+        if b.1 - b.0 > a.1 - a.0 {
+            b
         } else {
-            #[cfg(not(feature = "fixed_heap"))]
-            unsafe {
-                system_alloc::munmap(final_ptr, (final_idx + 1) * PG_SIZE)
-            };
+            a
         }
     }
 
-    fn get_slice(&mut self) -> &mut [Mutex<Option<&'static mut DoubleLinkedList>>] {
+    /// Gets the lists, which exist once a block was handed out
+    fn get_slice(&mut self) -> &mut [Option<&'static mut DoubleLinkedList>] {
+        self.try_get_slice().expect("err")
+    }
+
+    fn try_get_slice(
+        &mut self,
+    ) -> Result<&mut [Option<&'static mut DoubleLinkedList>], AllocError> {
         let mut ptr_val = self.lists.load(Ordering::Relaxed);
         if ptr_val.is_null() {
             unsafe {
-                let new_ptr = META_BUMP
-                    .lock()
-                    .alloc(core::mem::size_of::<
-                        [Mutex<Option<&'static mut DoubleLinkedList>>; BACKEND_MAX_PAGE],
-                    >())
-                    .expect("err");
+                let new_ptr = META_BUMP.lock().alloc(core::mem::size_of::<
+                    [Option<&'static mut DoubleLinkedList>; BACKEND_MAX_PAGE],
+                >())?;
                 let slice = core::slice::from_raw_parts_mut(
-                    new_ptr as *mut Mutex<Option<&'static mut DoubleLinkedList>>,
+                    new_ptr as *mut Option<&'static mut DoubleLinkedList>,
                     BACKEND_MAX_PAGE,
                 );
-                #[allow(clippy::declare_interior_mutable_const)]
-                const VAL: Mutex<Option<&'static mut DoubleLinkedList>> = Mutex::new(None);
                 for s in slice {
-                    *s = VAL;
+                    core::ptr::write(s, None);
                 }
                 if let Err(real_ptr) = self.lists.compare_exchange(
                     null_mut(),
-                    new_ptr as *mut Mutex<Option<&'static mut DoubleLinkedList>>,
+                    new_ptr as *mut Option<&'static mut DoubleLinkedList>,
                     Ordering::AcqRel,
                     Ordering::Relaxed,
                 ) {
                     META_BUMP.lock().dealloc(new_ptr as *mut usize);
                     ptr_val = real_ptr;
                 } else {
-                    ptr_val = new_ptr as *mut Mutex<Option<&'static mut DoubleLinkedList>>;
+                    ptr_val = new_ptr as *mut Option<&'static mut DoubleLinkedList>;
                 }
             }
         }
-        unsafe { core::slice::from_raw_parts_mut(ptr_val, BACKEND_MAX_PAGE) }
+        unsafe { Ok(core::slice::from_raw_parts_mut(ptr_val, BACKEND_MAX_PAGE)) }
     }
 }
 
@@ -283,17 +791,51 @@ impl Default for BuddySystemAllocator {
     }
 }
 
+impl BuddySystemAllocator {
+    /// Grows the block at `ptr` without moving it, see
+    /// [`FreeList::grow_in_place`]
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live block of `size` bytes of this allocator
+    pub unsafe fn grow_in_place(&self, ptr: *mut u8, size: usize, new_size: usize) -> bool {
+        FREELIST.grow_in_place(ptr, size, new_size)
+    }
+
+    /// Allocates a block for `layout`, also returning whether it reads as
+    /// zero, see [`FreeList::alloc_maybe_zeroed`]
+    pub unsafe fn alloc_maybe_zeroed(&self, layout: Layout) -> (*mut u8, bool) {
+        if layout.size() > isize::MAX as usize {
+            return (null_mut(), false);
+        }
+        FREELIST
+            .alloc_maybe_zeroed(layout.size(), layout.align())
+            .unwrap_or((null_mut(), false))
+    }
+}
+
 unsafe impl GlobalAlloc for BuddySystemAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         if layout.size() > isize::MAX as usize {
             return core::ptr::null_mut::<u8>();
         }
-        FREELIST.alloc(layout.size()).unwrap_or(null_mut())
+        FREELIST
+            .alloc_aligned(layout.size(), layout.align())
+            .unwrap_or(null_mut())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         FREELIST.free(ptr, layout.size())
     }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > isize::MAX as usize {
+            return core::ptr::null_mut::<u8>();
+        }
+        FREELIST
+            .alloc_zeroed(layout.size(), layout.align())
+            .unwrap_or(null_mut())
+    }
 }
 
 unsafe impl Allocator for BuddySystemAllocator {
@@ -336,4 +878,115 @@ mod test {
             assert_ne!(ptr3 as usize, ptr4 as usize)
         }
     }
+
+    #[test]
+    fn merged_state_accounting() {
+        // a heap of its own, so that no other test takes the freed runs
+        let heap: &'static mut FreeList =
+            alloc::boxed::Box::leak(alloc::boxed::Box::new(FreeList::new()));
+        // the guard pages around the runs stay allocated
+        let base = heap.alloc(4 * PG_SIZE).expect("err") as usize + PG_SIZE;
+        let now = decay::now_ms();
+        heap.release(base as *mut u8, PG_SIZE, now, 0);
+        heap.decay(now, true);
+
+        // the clean page and the dirty one count apart
+        heap.release((base + PG_SIZE) as *mut u8, PG_SIZE, now, 0);
+        let _heap = HEAP_LOCK.lock();
+        let node = DoubleLinkedList::get_ref(base as *mut DoubleLinkedList);
+        assert_eq!(node.state, PageState::Dirty);
+        assert_eq!((node.dirty, node.muzzy), (PG_SIZE, 0));
+    }
+
+    #[cfg(not(feature = "fixed_heap"))]
+    #[test]
+    fn purged_zeros() {
+        // a heap of its own, so that no other test takes the freed runs
+        let heap: &'static mut FreeList =
+            alloc::boxed::Box::leak(alloc::boxed::Box::new(FreeList::new()));
+        let base = heap.alloc(6 * PG_SIZE).expect("err") as usize + PG_SIZE;
+        let end = base + 4 * PG_SIZE;
+        let now = decay::now_ms();
+        heap.release(base as *mut u8, 4 * PG_SIZE, now, 0);
+        heap.decay(now, true);
+
+        // only the whole OS pages past the node were purged
+        let node = core::mem::size_of::<DoubleLinkedList>();
+        let purged = (os_page_align_up(base + node), os_page_align_down(end));
+        let offsets = |zeros: (usize, usize), start: usize| {
+            let (low, high) = FreeList::clamp(zeros, start, end);
+            low - start..high - start
+        };
+        let _heap = HEAP_LOCK.lock();
+        assert_eq!(
+            FreeList::zeros_of(base as *mut u8, 4 * PG_SIZE),
+            offsets(purged, base)
+        );
+        drop(_heap);
+
+        // the remainder of a split loses the bytes of its node
+        let (ptr, _) = heap.alloc_run(PG_SIZE).expect("err");
+        assert_eq!(ptr as usize, base);
+        let (ptr, zeros) = heap.alloc_run(3 * PG_SIZE).expect("err");
+        assert_eq!(ptr as usize, base + PG_SIZE);
+        let remain = (purged.0.max(base + PG_SIZE + node), purged.1);
+        assert_eq!(zeros, offsets(remain, base + PG_SIZE));
+    }
+
+    #[test]
+    fn concurrent_coalescing() {
+        extern crate std;
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+        use std::vec::Vec;
+
+        const THREADS: usize = 8;
+        const RUNS: usize = 40;
+        // runs of one to three pages, well within the lists together
+        let pages = |run: usize| 1 + run % 3;
+        let total: usize = (0..RUNS).map(pages).sum::<usize>() * PG_SIZE;
+
+        // a heap of its own, so that no other test takes the freed runs
+        let heap: &'static mut FreeList =
+            alloc::boxed::Box::leak(alloc::boxed::Box::new(FreeList::new()));
+        for _ in 0..20 {
+            // the guard pages around the runs stay allocated
+            let base = heap.alloc(total + 2 * PG_SIZE).expect("err") as usize + PG_SIZE;
+            let mut runs = Vec::new();
+            let mut start = base;
+            for run in 0..RUNS {
+                runs.push((start, pages(run) * PG_SIZE));
+                start += pages(run) * PG_SIZE;
+            }
+            let heap_addr = heap as *mut FreeList as usize;
+            let barrier = Arc::new(Barrier::new(THREADS));
+            let children: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    // neighbouring runs go to different threads
+                    let mine: Vec<_> = runs.iter().copied().skip(t).step_by(THREADS).collect();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        let heap = unsafe { &mut *(heap_addr as *mut FreeList) };
+                        barrier.wait();
+                        for (ptr, size) in mine.into_iter().rev() {
+                            heap.free(ptr as *mut u8, size);
+                        }
+                        // the cached runs merge as other threads free theirs
+                        heap.decay(decay::now_ms(), true);
+                    })
+                })
+                .collect();
+            for child in children {
+                child.join().expect("err");
+            }
+            // the runs merged back into a single block
+            let guard = HEAP_LOCK.lock();
+            assert_eq!(
+                get_rd_tree().get_mut(base << 16),
+                -((total / PG_SIZE) as i64) << 48
+            );
+            drop(guard);
+            assert_eq!(heap.alloc(total).expect("err") as usize, base);
+        }
+    }
 }
@@ -0,0 +1,230 @@
+//! Per-CPU caches of free page runs
+//!
+//! Runs of up to [`CACHED_PAGES`] pages freed to a heap are kept by the
+//! shard of the freeing CPU in its [`PageCache`], and handed out again to requests of the same
+//! length on that CPU without taking the lock of the central heap. The
+//! central heap only sees a cached run once it is flushed, which is also when
+//! the run merges with its free neighbours.
+//!
+//! Cached runs are dirty and accounted as such. Decay passes flush the runs
+//! due to become muzzy, purging flushes all of them.
+use super::decay::{self, PageState};
+use super::PG_SIZE;
+use crate::size_class::BACKEND_MAX_PAGE;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+/// Longest run kept by the shards, in pages
+pub(super) const CACHED_PAGES: usize = if BACKEND_MAX_PAGE < 64 {
+    BACKEND_MAX_PAGE
+} else {
+    64
+};
+
+/// Shards of the caches, CPUs past it share them
+const SHARDS: usize = 64;
+
+/// Bytes of runs one shard keeps at most
+const SHARD_BYTES: usize = 1 << 20;
+
+/// Header of a cached run, in its first page
+struct Run {
+    next: *mut Run,
+    pages: usize,
+    freed_at: u64,
+}
+
+/// Cached runs of one shard, a list per length
+struct Shard {
+    lists: [*mut Run; CACHED_PAGES],
+    bytes: usize,
+}
+
+unsafe impl Send for Shard {}
+
+impl Shard {
+    const fn new() -> Self {
+        Self {
+            lists: [null_mut(); CACHED_PAGES],
+            bytes: 0,
+        }
+    }
+
+    /// Takes a run of `pages` pages
+    fn pop(&mut self, pages: usize) -> Option<*mut u8> {
+        let run = self.lists[pages - 1];
+        if run.is_null() {
+            return None;
+        }
+        self.lists[pages - 1] = unsafe { (*run).next };
+        self.bytes -= pages * PG_SIZE;
+        Some(run as *mut u8)
+    }
+
+    /// Keeps the run `ptr` of `pages` pages freed at `freed_at`
+    ///
+    /// Returns false if the shard is full.
+    fn push(&mut self, ptr: *mut u8, pages: usize, freed_at: u64) -> bool {
+        let size = pages * PG_SIZE;
+        if self.bytes + size > SHARD_BYTES {
+            return false;
+        }
+        let run = ptr as *mut Run;
+        unsafe {
+            run.write(Run {
+                next: self.lists[pages - 1],
+                pages,
+                freed_at,
+            })
+        };
+        self.lists[pages - 1] = run;
+        self.bytes += size;
+        true
+    }
+
+    /// Moves the runs `due` says to flush to the list `taken`
+    fn take_due(&mut self, mut due: impl FnMut(u64) -> bool, mut taken: *mut Run) -> *mut Run {
+        for list in self.lists.iter_mut() {
+            let mut link: *mut *mut Run = list;
+            unsafe {
+                while !(*link).is_null() {
+                    let run = *link;
+                    if due((*run).freed_at) {
+                        *link = (*run).next;
+                        (*run).next = taken;
+                        taken = run;
+                        self.bytes -= (*run).pages * PG_SIZE;
+                    } else {
+                        link = &mut (*run).next;
+                    }
+                }
+            }
+        }
+        taken
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SHARD: Mutex<Shard> = Mutex::new(Shard::new());
+
+/// The shards of the caches of a heap
+pub(super) struct PageCache {
+    shards: [Mutex<Shard>; SHARDS],
+}
+
+impl PageCache {
+    pub(super) const fn new() -> Self {
+        Self {
+            shards: [SHARD; SHARDS],
+        }
+    }
+
+    /// Shard of the CPU running the thread
+    fn shard(&self) -> &Mutex<Shard> {
+        #[cfg(all(linux, not(feature = "fixed_heap")))]
+        let cpu = unsafe { libc::sched_getcpu() }.max(0) as usize;
+        #[cfg(not(all(linux, not(feature = "fixed_heap"))))]
+        let cpu = 0;
+        &self.shards[cpu % SHARDS]
+    }
+
+    /// Takes a run of `pages` pages cached by the current CPU
+    pub(super) fn take(&self, pages: usize) -> Option<*mut u8> {
+        debug_assert!((1..=CACHED_PAGES).contains(&pages));
+        let run = self.shard().lock().pop(pages)?;
+        decay::unaccount(PageState::Dirty, pages * PG_SIZE);
+        Some(run)
+    }
+
+    /// Caches the run `ptr` of `pages` pages freed at `now` on the current
+    /// CPU
+    ///
+    /// Returns false, leaving the run to the caller, if the shard is full.
+    pub(super) fn put(&self, ptr: *mut u8, pages: usize, now: u64) -> bool {
+        debug_assert!((1..=CACHED_PAGES).contains(&pages));
+        if !self.shard().lock().push(ptr, pages, now) {
+            return false;
+        }
+        decay::account(PageState::Dirty, pages * PG_SIZE);
+        true
+    }
+
+    /// Takes the runs to flush out of every shard
+    ///
+    /// With `force` every run goes, otherwise only the ones no longer dirty
+    /// at `now`.
+    pub(super) fn take_due(&self, now: u64, force: bool) -> Runs {
+        let mut taken = null_mut();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            let before = shard.bytes;
+            taken = shard.take_due(
+                |freed_at| force || decay::state_at(freed_at, now) != PageState::Dirty,
+                taken,
+            );
+            decay::unaccount(PageState::Dirty, before - shard.bytes);
+        }
+        Runs(taken)
+    }
+}
+
+/// Runs taken out of the caches, yielding their address, their length in
+/// pages and the time they were freed
+pub(super) struct Runs(*mut Run);
+
+impl Iterator for Runs {
+    type Item = (*mut u8, usize, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_null() {
+            return None;
+        }
+        let run = self.0;
+        let Run {
+            next,
+            pages,
+            freed_at,
+        } = unsafe { run.read() };
+        self.0 = next;
+        Some((run as *mut u8, pages, freed_at))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    #[test]
+    fn shard_test() {
+        let mut memory = Vec::<u8>::with_capacity(8 * PG_SIZE);
+        let base = memory.as_mut_ptr();
+        let run = |i: usize| unsafe { base.add(i * PG_SIZE) };
+        let mut shard = Shard::new();
+        assert!(shard.pop(1).is_none());
+        assert!(shard.push(run(0), 1, 10));
+        assert!(shard.push(run(1), 1, 20));
+        assert!(shard.push(run(2), 2, 30));
+        assert_eq!(shard.bytes, 4 * PG_SIZE);
+
+        assert_eq!(shard.pop(2), Some(run(2)));
+        assert!(shard.pop(2).is_none());
+        assert!(shard.push(run(2), 2, 30));
+
+        // only the runs freed before 25 are due
+        let due = shard.take_due(|freed_at| freed_at < 25, null_mut());
+        let mut flushed: Vec<_> = Runs(due).map(|(run, _, _)| run).collect();
+        flushed.sort();
+        assert_eq!(flushed, [run(0), run(1)]);
+        assert_eq!(shard.bytes, 2 * PG_SIZE);
+        assert!(shard.pop(1).is_none());
+        assert_eq!(shard.pop(2), Some(run(2)));
+
+        // a shard keeps `SHARD_BYTES` at most
+        let mut big = Shard::new();
+        big.bytes = SHARD_BYTES - PG_SIZE;
+        assert!(!big.push(run(0), 2, 0));
+        assert!(big.push(run(0), 1, 0));
+    }
+}
@@ -0,0 +1,183 @@
+//! Histogram of the sizes requested from the allocator
+//!
+//! With the `size_histogram` feature, every allocation and every resize
+//! counts its size, exactly up to [`MAX_TRACKED`] bytes and in a single bucket
+//! past it. At exit the histogram is written to the file named by
+//! `UNIALLOC_HISTOGRAM`, or `unialloc_histogram.txt` by default, with a
+//! `size count` line per requested size. `examples/size_class_tuner.rs` turns
+//! it into a table of `size_classes.txt`.
+//!
+//! Writing it out never goes through the allocator.
+use core::intrinsics::unlikely;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Largest size counted on its own
+pub const MAX_TRACKED: usize = 64 << 10;
+
+/// Bucket of the sizes past `MAX_TRACKED`
+pub const LARGER: usize = MAX_TRACKED + 1;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static COUNTS: [AtomicU64; LARGER + 1] = [ZERO; LARGER + 1];
+/// Whether the exit hook is registered
+static HOOK: AtomicBool = AtomicBool::new(false);
+
+/// Counts a request of `size` bytes
+#[inline]
+pub fn record(size: usize) {
+    COUNTS[size.min(LARGER)].fetch_add(1, Ordering::Relaxed);
+    if unlikely(!HOOK.load(Ordering::Relaxed)) && !HOOK.swap(true, Ordering::Relaxed) {
+        unsafe { libc::atexit(at_exit) };
+    }
+}
+
+/// Requests of `size` bytes so far, or of any size past `MAX_TRACKED` for
+/// [`LARGER`]
+pub fn count(size: usize) -> u64 {
+    COUNTS[size.min(LARGER)].load(Ordering::Relaxed)
+}
+
+/// Forgets the requests so far
+pub fn reset() {
+    for count in COUNTS.iter() {
+        count.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Writes the histogram to the file at `path`, which ends with a nul
+///
+/// Returns false if the file cannot be written.
+pub fn dump(path: &[u8]) -> bool {
+    assert_eq!(path.last(), Some(&0), "the path must end with a nul");
+    unsafe { dump_to(path.as_ptr() as *const libc::c_char) }
+}
+
+unsafe fn dump_to(path: *const libc::c_char) -> bool {
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+    let fd = libc::open(path, flags, 0o644);
+    if fd < 0 {
+        return false;
+    }
+    let mut out = Writer::new(fd);
+    out.write(b"# size count\n");
+    for size in 0..=MAX_TRACKED {
+        let count = count(size);
+        if count > 0 {
+            out.number(size as u64);
+            out.write(b" ");
+            out.number(count);
+            out.write(b"\n");
+        }
+    }
+    out.write(b"# larger ");
+    out.number(count(LARGER));
+    out.write(b"\n");
+    let ok = out.flush();
+    libc::close(fd) == 0 && ok
+}
+
+extern "C" fn at_exit() {
+    unsafe {
+        let path = libc::getenv(b"UNIALLOC_HISTOGRAM\0".as_ptr() as *const libc::c_char);
+        if path.is_null() {
+            dump_to(b"unialloc_histogram.txt\0".as_ptr() as *const libc::c_char);
+        } else {
+            dump_to(path);
+        }
+    }
+}
+
+/// Buffered writes to a file descriptor, on the stack
+struct Writer {
+    fd: libc::c_int,
+    buf: [u8; 4096],
+    len: usize,
+    ok: bool,
+}
+
+impl Writer {
+    fn new(fd: libc::c_int) -> Self {
+        Self {
+            fd,
+            buf: [0; 4096],
+            len: 0,
+            ok: true,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.flush();
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn number(&mut self, mut n: u64) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        self.write(&digits[start..]);
+    }
+
+    fn flush(&mut self) -> bool {
+        let mut done = 0;
+        while self.ok && done < self.len {
+            let n = unsafe {
+                libc::write(
+                    self.fd,
+                    self.buf[done..].as_ptr() as *const libc::c_void,
+                    self.len - done,
+                )
+            };
+            if n > 0 {
+                done += n as usize;
+            } else if n == 0 || errno() != libc::EINTR {
+                self.ok = false;
+            }
+        }
+        self.len = 0;
+        self.ok
+    }
+}
+
+fn errno() -> libc::c_int {
+    unsafe { *libc::__errno_location() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::string::String;
+
+    #[test]
+    fn dump_test() {
+        // other tests allocate concurrently, so only our own sizes are exact
+        let before = count(MAX_TRACKED);
+        record(MAX_TRACKED);
+        record(MAX_TRACKED);
+        record(MAX_TRACKED + 100);
+        assert_eq!(count(MAX_TRACKED), before + 2);
+        assert!(count(LARGER) >= 1);
+
+        let path = std::env::temp_dir().join(std::format!("histogram-{}", std::process::id()));
+        let mut cpath = String::from(path.to_str().unwrap());
+        cpath.push('\0');
+        assert!(dump(cpath.as_bytes()));
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let prefix = std::format!("{} ", MAX_TRACKED);
+        assert!(text.lines().any(|l| l.starts_with(&prefix)));
+        assert!(text.lines().any(|l| l.starts_with("# larger ")));
+    }
+}
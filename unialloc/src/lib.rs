@@ -4,6 +4,7 @@
 #![allow(clippy::uninit_assumed_init)]
 #![feature(allocator_api)]
 #![feature(thread_local)]
+#![feature(linkage)]
 #![feature(alloc_layout_extra)]
 #![allow(incomplete_features)]
 #![feature(ptr_internals)]
@@ -24,13 +25,18 @@
 #[macro_use]
 mod buddy_system;
 mod alloc_api;
+#[cfg(all(feature = "c_api", target_os = "linux"))]
+pub mod c_api;
 // mod arena;
-mod bg_thread;
+#[cfg(all(target_os = "linux", not(feature = "fixed_heap")))]
+pub mod bg_thread;
 mod bitmap_alloc;
 mod cache;
 mod collections;
 mod error;
 mod freelist;
+#[cfg(size_histogram)]
+pub mod histogram;
 mod mm;
 mod mpmc;
 mod page;
@@ -40,13 +46,21 @@ mod sc;
 mod size_class;
 #[cfg(not(feature = "fixed_heap"))]
 mod sync;
+#[cfg(test)]
+mod test_util;
+mod transfer;
 mod zone;
 
 include!(concat!(env!("OUT_DIR"), "/consts.rs"));
 extern crate alloc;
 
 pub use cache::RustAllocator as UniAlloc;
+pub use freelist::decay;
 pub use pal::arch::*;
+#[cfg(not(feature = "fixed_heap"))]
+pub use pal::limit;
+pub use pal::sysinfo;
+pub use size_class::SizeClass;
 
 // use core::panic::PanicInfo;
 
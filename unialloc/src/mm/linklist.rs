@@ -30,61 +30,20 @@ impl Linklist {
         self.length += 1;
     }
 
-    // /// Security
-    // ///
-    // /// This API lacks of pointer authentication
-    // pub fn pop_unchecked(&mut self) -> *mut u8 {
-    //     if self.link == 0 {
-    //         debug_assert_eq!(self.length, 0);
-    //         return core::ptr::null_mut::<u8>();
-    //     }
-    //
-    //     let result = self.link;
-    //     let next = unsafe { *(result as *const usize) };
-    //     self.link = next;
-    //     // if next != 0 {
-    //     //     unsafe {
-    //     //         core::intrinsics::prefetch_read_data(next as *const usize, 3);
-    //     //     }
-    //     // }
-    //     self.length -= 1;
-    //     result as *mut u8
-    // }
-
-    pub fn pop_unchecked_aligned(&mut self, align: usize) -> *mut u8 {
-        if self.length == 0 {
+    /// Security
+    ///
+    /// This API lacks of pointer authentication
+    pub fn pop_unchecked(&mut self) -> *mut u8 {
+        if self.link == 0 {
             debug_assert_eq!(self.length, 0);
             return core::ptr::null_mut::<u8>();
         }
 
         let result = self.link;
-        assert_ne!(result, 0);
-        if result & (align - 1) == 0 {
-            let next = unsafe { *(result as *const usize) };
-            self.link = next;
-            // if next != 0 {
-            //     unsafe {
-            //         core::intrinsics::prefetch_read_data(next as *const usize, 3);
-            //     }
-            // }
-            self.length -= 1;
-            result as *mut u8
-        } else {
-            let mut pre = result;
-            let mut now = unsafe { *(pre as *const usize) };
-            while now != 0 {
-                if now & (align - 1) == 0 {
-                    unsafe { *(pre as *mut usize) = *(now as *mut usize) };
-                    self.length -= 1;
-                    break;
-                } else {
-                    pre = now;
-                    now = unsafe { *(pre as *const usize) };
-                }
-            }
-
-            now as *mut u8
-        }
+        let next = unsafe { *(result as *const usize) };
+        self.link = next;
+        self.length -= 1;
+        result as *mut u8
     }
 
     pub fn length(&self) -> usize {
@@ -4,6 +4,7 @@ use crate::*;
 use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::{self, null_mut, NonNull};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 include!(concat!(env!("OUT_DIR"), "/consts.rs"));
 /// Holds allocated data within pages.
 ///
@@ -29,8 +30,23 @@ pub struct ObjectPage {
     ptr: *mut u8,
     prev: usize,
     next: usize,
+    /// id of the thread cache the objects were last handed to
+    owner: AtomicU32,
+    /// objects freed by the page heap owning the page, see `cache::page_heap`
+    local_free: *mut u8,
+    /// objects freed by other threads to the page heap owning the page, or
+    /// `ABANDONED`
+    thread_free: AtomicUsize,
+    /// whether the memory of the page reads as zero, until its objects are
+    /// first handed out
+    zeroed: bool,
 }
 
+/// `thread_free` of the pages no page heap owns
+///
+/// Objects are at least 8 bytes aligned, so no list starts there.
+const ABANDONED: usize = 1;
+
 // impl Default for ObjectPage {
 //     fn default() -> Self {
 //         Self {
@@ -49,6 +65,10 @@ impl Default for ObjectPage {
             ptr: ptr::null_mut(),
             prev: 0,
             next: 0,
+            owner: AtomicU32::new(0),
+            local_free: ptr::null_mut(),
+            thread_free: AtomicUsize::new(0),
+            zeroed: false,
         }
     }
 }
@@ -61,6 +81,10 @@ impl ObjectPage {
             ptr: ptr::null_mut(),
             prev: 0,
             next: 0,
+            owner: AtomicU32::new(0),
+            local_free: ptr::null_mut(),
+            thread_free: AtomicUsize::new(0),
+            zeroed: false,
         }
     }
 
@@ -98,16 +122,25 @@ impl ObjectPage {
         self.next = nnext;
     }
 
-    pub fn allocate_page(&mut self, pg_num: usize) -> *mut u8 {
-        let ans = unsafe {
+    /// Backs this page with `pg_num` pages from the backend
+    pub fn allocate_page(&mut self, pg_num: usize) -> Result<*mut u8> {
+        let (ans, zeroed) = unsafe {
             let layout = Layout::from_size_align_unchecked(PAGE_SIZE * pg_num, 8);
-            GlobalBackend.alloc(layout)
+            GlobalBackend.alloc_maybe_zeroed(layout)
         };
-        assert_ne!(ans as usize, 0);
+        if ans.is_null() {
+            return Err(AllocError::ENOMEM);
+        }
         self.data = ans;
+        self.zeroed = zeroed;
         self.ptr = ptr::null_mut();
         self.counter = 0;
-        ans
+        Ok(ans)
+    }
+
+    /// Start of the objects of the page, null if it has no memory
+    pub fn data(&self) -> *mut u8 {
+        self.data
     }
 
     //This function is used only in sc.rs, for more details, please refer to sc:deallocate
@@ -139,6 +172,135 @@ impl ObjectPage {
     pub fn is_inited(&self) -> bool {
         !self.data.is_null()
     }
+
+    /// Returns the id of the thread cache owning this page
+    #[inline]
+    pub fn owner(&self) -> u32 {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_owner(&self, owner: u32) {
+        self.owner.store(owner, Ordering::Relaxed)
+    }
+}
+
+/// Free lists of a page owned by a page heap
+///
+/// `ptr` is the list the owner allocates from, `local_free` the one it frees
+/// to, and other threads push to `thread_free`. The owner moves the two
+/// latter lists to `ptr` once it runs out. `counter` keeps counting the
+/// objects in use, so a page given back to the zone fits its slab lists.
+impl ObjectPage {
+    /// Links all the objects of an empty page into `ptr`
+    pub(crate) fn carve(&mut self, pg_count: usize, pg_align: usize) {
+        debug_assert!(self.is_empty());
+        self.zeroed = false;
+        let base = self.data as usize;
+        for i in 0..pg_count {
+            let next = if i + 1 < pg_count {
+                base + (i + 1) * pg_align
+            } else {
+                0
+            };
+            unsafe { *((base + i * pg_align) as *mut usize) = next };
+        }
+        self.ptr = self.data;
+    }
+
+    /// Hands the page over to the page heap `owner`
+    pub(crate) fn adopt(&mut self, owner: u32) {
+        debug_assert!(self.local_free.is_null());
+        self.thread_free.store(0, Ordering::Relaxed);
+        self.set_owner(owner);
+    }
+
+    /// Takes the page back from its page heap
+    ///
+    /// Every free list is merged into `ptr`. Other threads freeing objects of
+    /// the page from now on find it `ABANDONED`, and give them to the zone.
+    pub(crate) fn abandon(&mut self) {
+        let thread_free = self.thread_free.swap(ABANDONED, Ordering::Acquire);
+        self.counter -= self.splice(thread_free as *mut u8);
+        let local_free = core::mem::replace(&mut self.local_free, null_mut());
+        self.splice(local_free);
+        self.set_owner(0);
+    }
+
+    /// Pops an object of `ptr`
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<NonNull<u8>> {
+        let obj = NonNull::new(self.ptr)?;
+        self.ptr = unsafe { *(self.ptr as *mut *mut u8) };
+        self.counter += 1;
+        Some(obj)
+    }
+
+    /// Frees `obj` on the thread owning the page
+    #[inline]
+    pub(crate) fn free_local(&mut self, obj: *mut u8) {
+        debug_assert!(self.counter > 0);
+        unsafe { *(obj as *mut *mut u8) = self.local_free };
+        self.local_free = obj;
+        self.counter -= 1;
+    }
+
+    /// Frees `obj` on another thread than the owner
+    ///
+    /// Returns false, leaving the object to the caller, if the page is
+    /// abandoned.
+    pub(crate) fn free_remote(&self, obj: *mut u8) -> bool {
+        let mut head = self.thread_free.load(Ordering::Relaxed);
+        loop {
+            if head == ABANDONED {
+                return false;
+            }
+            unsafe { *(obj as *mut usize) = head };
+            match self.thread_free.compare_exchange_weak(
+                head,
+                obj as usize,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(cur) => head = cur,
+            }
+        }
+    }
+
+    /// Moves the objects freed since the last call to `ptr`
+    ///
+    /// Returns whether `ptr` has objects left.
+    pub(crate) fn collect(&mut self) -> bool {
+        if self.thread_free.load(Ordering::Relaxed) != 0 {
+            let thread_free = self.thread_free.swap(0, Ordering::Acquire);
+            self.counter -= self.splice(thread_free as *mut u8);
+        }
+        if self.ptr.is_null() {
+            self.ptr = core::mem::replace(&mut self.local_free, null_mut());
+        }
+        !self.ptr.is_null()
+    }
+
+    /// Puts the null terminated list `head` in front of `ptr`
+    ///
+    /// Returns the length of the list
+    fn splice(&mut self, head: *mut u8) -> usize {
+        if head.is_null() {
+            return 0;
+        }
+        let mut tail = head as *mut usize;
+        let mut count = 1;
+        unsafe {
+            while *tail != 0 {
+                tail = *tail as *mut usize;
+                count += 1;
+            }
+            *tail = self.ptr as usize;
+        }
+        self.ptr = head;
+        count
+    }
 }
 
 impl ObjectPage {
@@ -154,23 +316,35 @@ impl ObjectPage {
         self.counter == 0
     }
 
+    /// Number of objects of the page in use
+    #[inline]
+    pub fn in_use(&self) -> usize {
+        self.counter
+    }
+
+    /// Hands out all the free objects of the page
+    ///
+    /// Returns the first object and their number. Those of an empty page are
+    /// handed out as a run with the stride of the page, also telling whether
+    /// they read as zero. The others are a list.
     pub(crate) fn allocate_all(
         &mut self,
         pg_count: usize,
         pg_align: usize,
-    ) -> (*mut u8, usize, Option<usize>) {
+    ) -> (*mut u8, usize, Option<usize>, bool) {
         if self.counter == 0 {
             self.counter = pg_count;
             if !self.ptr.is_null() {
                 self.ptr = null_mut();
             }
-            (self.data, pg_count, Some(pg_align))
+            let zeroed = core::mem::replace(&mut self.zeroed, false);
+            (self.data, pg_count, Some(pg_align), zeroed)
         } else {
             let to_allocate = pg_count - self.counter;
             self.counter = pg_count;
             let ans = self.ptr;
             self.ptr = null_mut();
-            (ans, to_allocate, None)
+            (ans, to_allocate, None, false)
         }
     }
 
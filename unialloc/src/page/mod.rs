@@ -61,25 +61,26 @@ impl PageBumpAlloc {
                 let prot = system_alloc::prots::get_prot(true, true, false);
                 let start = unsafe {
                     #[cfg(feature = "hugepage")]
-                    let mut ptr = system_alloc::mmap_huge(Self::DEFAULT_SIZE, prot) as *mut u8;
+                    let ptr = system_alloc::mmap_huge(Self::DEFAULT_SIZE, prot);
                     #[cfg(not(feature = "hugepage"))]
-                    let mut ptr = system_alloc::mmap(Self::DEFAULT_SIZE, prot) as *mut u8;
-                    // When fail, mmap return -1, which is 0xffffffffffff
-                    // So need to use i64 to identify if it fails and return null
-                    if ptr as usize == usize::MAX {
-                        ptr = core::ptr::null_mut();
-                    }
-                    ptr
+                    let ptr = system_alloc::mmap(Self::DEFAULT_SIZE, prot);
+                    ptr as usize
                 };
-                let end = (start as usize + Self::DEFAULT_SIZE) as *mut u8;
-                self.current = end as usize;
-                self.start = start as usize;
+                // When fail, mmap return -1, which is 0xffffffffffff
+                if start == usize::MAX {
+                    return Err(AllocError::ENOMEM);
+                }
+                self.current = start + Self::DEFAULT_SIZE;
+                self.start = start;
             }
             #[cfg(feature = "fixed_heap")]
             {
                 let start =
                     unsafe { GlobalBackend.alloc(Layout::from_size_align_unchecked(4096, 1)) }
                         as usize;
+                if start == 0 {
+                    return Err(AllocError::ENOMEM);
+                }
                 let end = (start + 4096) as *mut u8;
                 self.current = end as usize;
                 self.start = start as usize;
@@ -0,0 +1,143 @@
+//! Process-wide limits on the memory committed by the allocator
+//!
+//! Mappings of metadata and of blocks too large for the backend count as
+//! committed as a whole, until they are unmapped. The reserves the backend
+//! and the metadata carve pages from only take address space: their pages
+//! count once carved. The backend stops counting the free pages it drops
+//! with `MADV_DONTNEED`, see [`crate::decay`], until it hands them out
+//! again. Two limits apply to that count:
+//!
+//! * the soft limit calls the out-of-memory handler when a mapping crosses
+//!   it, and the mapping goes on whatever the handler does;
+//! * the hard limit calls the handler as well, but the mapping fails, and the
+//!   allocation is retried if the handler made room, or returns null.
+//!
+//! The handler runs on the thread whose mapping crossed the limit, once the
+//! allocation is over and every lock of the allocator is dropped. It may
+//! allocate and free memory, through this allocator too; mappings it makes
+//! do not call it again. Returning `true` tells the allocator that memory
+//! was released and that a failed allocation is worth another try. A limit
+//! of zero means no limit.
+use crate::prelude::*;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Called with the size of the mapping or carve that crosses a limit
+pub type OomHandler = fn(requested: usize) -> bool;
+
+/// How many times an allocation is retried after the handler released memory
+const MAX_RETRIES: usize = 4;
+
+static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static SOFT_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static HARD_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+/// Size of the largest mapping of the thread that crossed a limit since the
+/// handler last ran, 0 if none
+#[thread_local]
+static mut PENDING: usize = 0;
+/// Set while the handler runs on the thread, its mappings do not call it
+/// again
+#[thread_local]
+static mut IN_HANDLER: bool = false;
+
+/// Bytes currently committed by the allocator
+pub fn committed() -> usize {
+    COMMITTED.load(Ordering::Relaxed)
+}
+
+/// Sets the soft limit in bytes
+pub fn set_soft_limit(bytes: usize) {
+    SOFT_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Sets the hard limit in bytes
+pub fn set_hard_limit(bytes: usize) {
+    HARD_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+pub fn soft_limit() -> usize {
+    SOFT_LIMIT.load(Ordering::Relaxed)
+}
+
+pub fn hard_limit() -> usize {
+    HARD_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Registers the out-of-memory handler, `None` removes it
+pub fn set_oom_handler(handler: Option<OomHandler>) {
+    let ptr = handler.map_or(core::ptr::null_mut(), |f| f as *mut ());
+    HANDLER.store(ptr, Ordering::Release);
+}
+
+/// Runs the handler for `size`, returns whether it released memory
+fn run_handler(size: usize) -> bool {
+    let ptr = HANDLER.load(Ordering::Acquire);
+    if ptr.is_null() || unsafe { IN_HANDLER } {
+        return false;
+    }
+    // SAFETY: only `set_oom_handler` stores to `HANDLER`, and it stores
+    // `OomHandler`s
+    let handler: OomHandler = unsafe { core::mem::transmute(ptr) };
+    unsafe { IN_HANDLER = true };
+    let released = handler(size);
+    unsafe { IN_HANDLER = false };
+    released
+}
+
+/// Runs the handler if a mapping of the thread crossed a limit, returns
+/// whether it released memory
+fn run_pending() -> bool {
+    let size = unsafe { PENDING };
+    if likely(size == 0) {
+        return false;
+    }
+    unsafe { PENDING = 0 };
+    run_handler(size)
+}
+
+/// Runs `alloc`, then the handler if a mapping crossed a limit meanwhile
+///
+/// A null `alloc` is retried as long as the handler releases memory. It must
+/// be called with no lock of the allocator held.
+#[inline]
+pub(crate) fn with_handler(mut alloc: impl FnMut() -> *mut u8) -> *mut u8 {
+    let mut ptr = alloc();
+    let mut retries = 0;
+    while run_pending() && ptr.is_null() && retries < MAX_RETRIES {
+        retries += 1;
+        ptr = alloc();
+    }
+    ptr
+}
+
+/// Accounts for `size` bytes mapped or carved
+///
+/// Returns false if they would cross the hard limit. Crossing either
+/// limit leaves the handler to run, see [`with_handler`].
+pub(crate) fn commit(size: usize) -> bool {
+    loop {
+        let cur = COMMITTED.load(Ordering::Relaxed);
+        let new = cur.saturating_add(size);
+        let soft = soft_limit();
+        let hard = hard_limit();
+        let over_soft = soft != 0 && new > soft;
+        let over_hard = hard != 0 && new > hard;
+        if over_soft || over_hard {
+            unsafe { PENDING = PENDING.max(size) };
+            if over_hard {
+                return false;
+            }
+        }
+        if COMMITTED
+            .compare_exchange_weak(cur, new, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            return true;
+        }
+    }
+}
+
+/// Accounts for `size` bytes unmapped or dropped
+pub(crate) fn uncommit(size: usize) {
+    COMMITTED.fetch_sub(size, Ordering::Relaxed);
+}
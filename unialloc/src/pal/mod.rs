@@ -1,10 +1,13 @@
 // TODO: change it to private module
 pub mod arch;
+#[cfg(not(feature = "fixed_heap"))]
+pub mod limit;
 pub mod os;
 #[cfg(not(feature = "fixed_heap"))]
 pub mod sync;
 #[cfg(not(feature = "fixed_heap"))]
 pub mod sys_alloc;
+pub mod sysinfo;
 pub mod thread;
 #[cfg(not(feature = "fixed_heap"))]
 pub use sys_alloc::PageHeap as SystemAllocator;
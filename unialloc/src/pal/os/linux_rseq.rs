@@ -122,7 +122,66 @@ fn sys_rseq(rseq: *const rseq, rseq_len: u32, flags: i32, sig: u32) -> isize {
 pub static RSEQ_ABI: rseq = rseq::new();
 pub const RSEQ_SIG: u32 = 0x53053053;
 
+// Exported by glibc 2.35 onwards, which registers an rseq area for every
+// thread at `__rseq_offset` from the thread pointer unless `__rseq_size` is
+// 0. Weak, so that older libcs leave them null.
+extern "C" {
+    #[linkage = "extern_weak"]
+    static __rseq_offset: *const isize;
+    #[linkage = "extern_weak"]
+    static __rseq_size: *const u32;
+}
+
+/// Whether the libc registers the rseq areas of the threads itself
+///
+/// The kernel takes a single area per thread, so ours would be refused with
+/// `EBUSY`; the one of the libc is used instead.
+#[inline]
+pub fn libc_registered() -> bool {
+    unsafe { !__rseq_size.is_null() && *__rseq_size != 0 }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn thread_pointer() -> usize {
+    let tp: usize;
+    unsafe { asm!("mov {}, fs:0", out(reg) tp, options(nostack, readonly, preserves_flags)) };
+    tp
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn thread_pointer() -> usize {
+    let tp: usize;
+    unsafe { asm!("mrs {}, tpidr_el0", out(reg) tp, options(nostack, nomem, preserves_flags)) };
+    tp
+}
+
+/// The rseq area of the current thread, the one of the libc if it registers
+/// them and [`RSEQ_ABI`] otherwise
+#[inline]
+pub fn rseq_area() -> *const rseq {
+    if libc_registered() {
+        (thread_pointer() as isize + unsafe { *__rseq_offset }) as *const rseq
+    } else {
+        &RSEQ_ABI as *const rseq
+    }
+}
+
+/// Whether the kernel implements rseq, whether or not this thread could
+/// register an area
+pub fn kernel_has_rseq() -> bool {
+    // a null area is refused with EINVAL by any kernel that has the syscall
+    sys_rseq(core::ptr::null(), 0, 0, 0) != -(libc::ENOSYS as isize)
+}
+
 pub fn register_current_thread() {
+    if libc_registered() {
+        if !is_registered() {
+            panic!("The libc failed to register rseq");
+        }
+        return;
+    }
     let rseq_abi: *const rseq = &RSEQ_ABI as *const rseq;
     let rc = sys_rseq(rseq_abi, mem::size_of::<rseq>() as u32, 0, RSEQ_SIG);
     // let rc = unsafe {libc::syscall(334, rseq_abi, mem::size_of_val(&RSEQ_ABI) as u32, 0, RSEQ_SIG)};
@@ -134,7 +193,25 @@ pub fn register_current_thread() {
     }
 }
 
+/// Registers the rseq area of the current thread
+///
+/// Unlike [`register_current_thread`], returns false on failure, e.g. when
+/// the kernel lacks rseq. If the libc registers the areas, only checks that
+/// it did so for this thread.
+pub fn try_register_current_thread() -> bool {
+    if libc_registered() {
+        return is_registered();
+    }
+    let rseq_abi: *const rseq = &RSEQ_ABI as *const rseq;
+    sys_rseq(rseq_abi, mem::size_of::<rseq>() as u32, 0, RSEQ_SIG) == 0
+}
+
+/// Unregisters the rseq area of the current thread, leaving the one of the
+/// libc alone
 pub fn unregister_current_thread() {
+    if libc_registered() {
+        return;
+    }
     let rseq_abi: *const rseq = &RSEQ_ABI as *const rseq;
     let rc = sys_rseq(rseq_abi, mem::size_of::<rseq>() as u32, 1, RSEQ_SIG);
 
@@ -145,12 +222,12 @@ pub fn unregister_current_thread() {
 
 #[inline]
 pub fn cpu_id() -> i32 {
-    unsafe { read_volatile(&RSEQ_ABI.cpu_id) }
+    unsafe { read_volatile(&(*rseq_area()).cpu_id) }
 }
 
 #[inline]
 pub fn cpu_id_start() -> i32 {
-    unsafe { read_volatile(&RSEQ_ABI.cpu_id_start) }
+    unsafe { read_volatile(&(*rseq_area()).cpu_id_start) }
 }
 
 #[inline]
@@ -201,9 +278,10 @@ mod tests {
 
     #[test]
     fn register_rseq_test() {
-        register_current_thread();
+        // the allocator may have registered the thread already
+        register_current_thread_checked();
         assert_eq!(is_registered(), true);
-        assert!(RSEQ_ABI.cpu_id as usize <= NCPU);
+        assert!((cpu_id() as usize) < crate::pal::sysinfo::ncpu());
 
         let cpuid = cpu_id();
         for _ in 0..1000 {
@@ -223,7 +301,7 @@ mod tests {
                     // assert!(false, "The address cannot have duplications");
                     // }
                     // seen.lock().insert(addr);
-                    register_current_thread();
+                    register_current_thread_checked();
                     assert_eq!(is_registered(), true);
                 })
             })
@@ -234,6 +312,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rseq_area_test() {
+        register_current_thread_checked();
+        let area = rseq_area();
+        if libc_registered() {
+            assert_ne!(area, &RSEQ_ABI as *const rseq);
+        } else {
+            assert_eq!(area, &RSEQ_ABI as *const rseq);
+        }
+        assert_eq!(unsafe { (*area).cpu_id }, cpu_id());
+    }
+
     #[test]
     fn inline_asm_add_test() {
         let i: u64 = 3;
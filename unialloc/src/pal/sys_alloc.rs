@@ -1,4 +1,5 @@
 //! underlying system allocator
+use super::limit;
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::ptr::NonNull;
 use core::result::Result;
@@ -51,29 +52,51 @@ use std::thread::spawn;
 /// # Safety
 ///
 /// safe if the size is valid
+///
+/// Returns `MAP_FAILED` if the mapping would cross the hard limit
 #[cfg(unix)]
 pub unsafe fn mmap(req: usize, prot: i32) -> *mut u8 {
-    libc::mmap(
-        core::ptr::null_mut(),
+    mmap_with_flags(req, prot, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS)
+}
+
+pub unsafe fn mmap_huge(req: usize, prot: i32) -> *mut u8 {
+    mmap_with_flags(
         req,
         prot,
-        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-        -1,
-        0,
-    ) as *mut u8
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+    )
 }
 
-pub unsafe fn mmap_huge(req: usize, prot: i32) -> *mut u8 {
+/// Maps `req` bytes that do not count against the limits, a reserve whose
+/// pages are committed with [`limit::commit`] as they are carved
+///
+/// # Safety
+///
+/// safe if the size is valid
+#[cfg(unix)]
+pub unsafe fn mmap_uncommitted(req: usize, prot: i32) -> *mut u8 {
     libc::mmap(
         core::ptr::null_mut(),
         req,
         prot,
-        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
         -1,
         0,
     ) as *mut u8
 }
 
+#[cfg(unix)]
+unsafe fn mmap_with_flags(req: usize, prot: i32, flags: i32) -> *mut u8 {
+    if !limit::commit(req) {
+        return libc::MAP_FAILED as *mut u8;
+    }
+    let ptr = libc::mmap(core::ptr::null_mut(), req, prot, flags, -1, 0);
+    if ptr == libc::MAP_FAILED {
+        limit::uncommit(req);
+    }
+    ptr as *mut u8
+}
+
 /// # Safety
 ///
 /// safe if the size is valid
@@ -91,9 +114,106 @@ pub unsafe fn mmap(req: usize, prot: u32) -> *mut u8 {
 /// safe if the size is valid
 #[cfg(unix)]
 pub unsafe fn munmap(ptr: *mut u8, size: usize) {
+    munmap_committed(ptr, size, size)
+}
+
+/// Unmaps `[ptr, ptr + size)`, of which only `committed` bytes count against
+/// the limits
+///
+/// # Safety
+///
+/// safe if the size is valid
+#[cfg(unix)]
+pub unsafe fn munmap_committed(ptr: *mut u8, size: usize, committed: usize) {
+    if libc::munmap(ptr as *mut libc::c_void, size) == 0 {
+        limit::uncommit(committed);
+    }
+}
+
+/// Resizes the mapping at `ptr` from `old` to `new` bytes, in place if it
+/// can, or else over `to`
+///
+/// `to` is a placeholder of `new` bytes from [`reserve`], replaced by the
+/// moved mapping. A null `to` only resizes in place. Returns `MAP_FAILED` if
+/// the mapping would cross the hard limit, or cannot be resized; `to` is left
+/// alone then.
+///
+/// # Safety
+///
+/// safe if `[ptr, ptr + old)` is mapped and aligned to OS pages
+#[cfg(target_os = "linux")]
+pub unsafe fn mremap(ptr: *mut u8, old: usize, new: usize, to: *mut u8) -> *mut u8 {
+    if new > old && !limit::commit(new - old) {
+        return libc::MAP_FAILED as *mut u8;
+    }
+    let mut moved = libc::mremap(ptr as *mut libc::c_void, old, new, 0);
+    if moved == libc::MAP_FAILED && !to.is_null() {
+        moved = libc::mremap(
+            ptr as *mut libc::c_void,
+            old,
+            new,
+            libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+            to as *mut libc::c_void,
+        );
+    }
+    if moved == libc::MAP_FAILED {
+        if new > old {
+            limit::uncommit(new - old);
+        }
+    } else if new < old {
+        limit::uncommit(old - new);
+    }
+    moved as *mut u8
+}
+
+/// Maps `size` bytes nobody can access, a placeholder for [`mremap`]
+///
+/// Placeholders do not count against the limits. Returns `MAP_FAILED` if
+/// none can be mapped.
+#[cfg(target_os = "linux")]
+pub unsafe fn reserve(size: usize) -> *mut u8 {
+    libc::mmap(
+        core::ptr::null_mut(),
+        size,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+        0,
+    ) as *mut u8
+}
+
+/// Unmaps the placeholder `[ptr, ptr + size)` from [`reserve`]
+#[cfg(target_os = "linux")]
+pub unsafe fn unreserve(ptr: *mut u8, size: usize) {
     libc::munmap(ptr as *mut libc::c_void, size);
 }
 
+/// Releases the physical pages of `[ptr, ptr + size)`, which stays mapped and
+/// reads back as zeros
+///
+/// # Safety
+///
+/// safe if the range is mapped and aligned to OS pages
+#[cfg(unix)]
+pub unsafe fn purge(ptr: *mut u8, size: usize) {
+    libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_DONTNEED);
+}
+
+/// Lets the OS reclaim the physical pages of `[ptr, ptr + size)` when it
+/// needs them, until then they keep their contents
+///
+/// Falls back to [`purge`] where `MADV_FREE` is not supported.
+///
+/// # Safety
+///
+/// safe if the range is mapped and aligned to OS pages
+#[cfg(unix)]
+pub unsafe fn purge_lazy(ptr: *mut u8, size: usize) {
+    if libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_FREE) != 0 {
+        purge(ptr, size);
+    }
+}
+
 /// # Safety
 ///
 /// safe if the size is valid
@@ -0,0 +1,175 @@
+//! Runtime information about the system
+//!
+//! Every value is probed once, on first use, and cached. The build machine
+//! says nothing about where the allocator runs, so nothing here is decided at
+//! build time unless overridden with the `UNIALLOC_NCPU`,
+//! `UNIALLOC_OS_PAGE_SIZE` or `UNIALLOC_RSEQ` environment variables.
+//!
+//! The allocator keeps its own page unit, [`crate::PAGE_SIZE`], which may
+//! differ from the one of the OS. Requests made to the OS (e.g., `munmap`)
+//! must be aligned to [`os_page_size`] instead.
+use crate::prelude::*;
+use crate::PAGE_SIZE;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+include!(concat!(env!("OUT_DIR"), "/overrides.rs"));
+
+static NCPU: AtomicUsize = AtomicUsize::new(0);
+static OS_PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+const RSEQ_UNKNOWN: u8 = 0;
+const RSEQ_UNAVAILABLE: u8 = 1;
+const RSEQ_AVAILABLE: u8 = 2;
+static RSEQ: AtomicU8 = AtomicU8::new(RSEQ_UNKNOWN);
+
+/// Probes all the values at once
+pub fn init() {
+    ncpu();
+    os_page_size();
+    has_rseq();
+}
+
+/// Number of CPU ids the kernel may hand out, including offline ones
+pub fn ncpu() -> usize {
+    let ncpu = NCPU.load(Ordering::Relaxed);
+    if likely(ncpu != 0) {
+        return ncpu;
+    }
+    let ncpu = NCPU_OVERRIDE.unwrap_or_else(probe_ncpu);
+    assert_ne!(ncpu, 0);
+    NCPU.store(ncpu, Ordering::Relaxed);
+    ncpu
+}
+
+/// Page size of the OS
+pub fn os_page_size() -> usize {
+    let size = OS_PAGE_SIZE.load(Ordering::Relaxed);
+    if likely(size != 0) {
+        return size;
+    }
+    let size = OS_PAGE_SIZE_OVERRIDE.unwrap_or_else(probe_os_page_size);
+    assert!(size.is_power_of_two());
+    OS_PAGE_SIZE.store(size, Ordering::Relaxed);
+    size
+}
+
+/// Whether the per-CPU caches can use rseq
+///
+/// The probe registers the area of the calling thread, which stays
+/// registered, or checks the one the libc registered for it. It fails if the
+/// kernel lacks rseq.
+pub fn has_rseq() -> bool {
+    match RSEQ.load(Ordering::Relaxed) {
+        RSEQ_AVAILABLE => true,
+        RSEQ_UNAVAILABLE => false,
+        _ => {
+            let available = HAS_RSEQ_OVERRIDE.unwrap_or_else(probe_rseq);
+            let state = if available {
+                RSEQ_AVAILABLE
+            } else {
+                RSEQ_UNAVAILABLE
+            };
+            RSEQ.store(state, Ordering::Relaxed);
+            available
+        }
+    }
+}
+
+#[cfg(not(feature = "fixed_heap"))]
+fn probe_ncpu() -> usize {
+    let ncpu = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    if ncpu > 0 {
+        ncpu as usize
+    } else {
+        1
+    }
+}
+
+#[cfg(feature = "fixed_heap")]
+fn probe_ncpu() -> usize {
+    1
+}
+
+#[cfg(not(feature = "fixed_heap"))]
+fn probe_os_page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as usize
+    } else {
+        PAGE_SIZE
+    }
+}
+
+#[cfg(feature = "fixed_heap")]
+fn probe_os_page_size() -> usize {
+    PAGE_SIZE
+}
+
+// The rseq syscall number and the per-CPU critical sections are x86_64 only
+#[cfg(percpu)]
+fn probe_rseq() -> bool {
+    use crate::pal::os::rseq::{is_registered, try_register_current_thread};
+    is_registered() || try_register_current_thread()
+}
+
+#[cfg(not(percpu))]
+fn probe_rseq() -> bool {
+    false
+}
+
+/// Whether [`has_rseq`] is expected to hold: the kernel implements rseq and
+/// no override turns it off
+///
+/// Not cached, meant for the tests to tell a broken registration from a
+/// missing syscall.
+pub fn rseq_expected() -> bool {
+    HAS_RSEQ_OVERRIDE.unwrap_or_else(probe_kernel_rseq)
+}
+
+#[cfg(percpu)]
+fn probe_kernel_rseq() -> bool {
+    crate::pal::os::rseq::kernel_has_rseq()
+}
+
+#[cfg(not(percpu))]
+fn probe_kernel_rseq() -> bool {
+    false
+}
+
+/// Rounds `size` down to a multiple of the OS page size
+#[inline]
+pub fn os_page_align_down(size: usize) -> usize {
+    size & !(os_page_size() - 1)
+}
+
+/// Rounds `size` up to a multiple of the OS page size
+#[inline]
+pub fn os_page_align_up(size: usize) -> usize {
+    (size + os_page_size() - 1) & !(os_page_size() - 1)
+}
+
+#[cfg(all(test, not(feature = "fixed_heap")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_test() {
+        init();
+        assert!(ncpu() >= 1);
+        assert_eq!(os_page_size(), unsafe {
+            libc::sysconf(libc::_SC_PAGESIZE) as usize
+        });
+        assert_eq!(os_page_align_down(os_page_size() + 1), os_page_size());
+        assert_eq!(os_page_align_up(1), os_page_size());
+    }
+
+    #[cfg(percpu)]
+    #[test]
+    fn rseq_probe_test() {
+        use crate::pal::os::rseq::{cpu_id, is_registered};
+        assert_eq!(has_rseq(), rseq_expected());
+        if has_rseq() && is_registered() {
+            assert!((cpu_id() as usize) < ncpu());
+        }
+    }
+}
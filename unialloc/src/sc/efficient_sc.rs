@@ -9,6 +9,7 @@ use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::borrow::BorrowMut;
 use core::cmp::Ordering;
+use core::marker::PhantomData;
 use core::ptr::{self, null_mut, NonNull};
 
 pub fn align_12k(ptr: usize) -> usize {
@@ -31,6 +32,9 @@ pub fn align_12k(ptr: usize) -> usize {
 ///  * `slabs`: A list of pages partially allocated and still have room for more.
 ///  * `full_slabs`: A list of pages that are completely allocated.
 ///
+/// Partial pages are bucketed by the share of their objects in use, see
+/// [`PARTIAL_BUCKETS`].
+///
 /// On allocation we allocate memory from `slabs`, however if the list is empty
 /// we try to reclaim a page from `empty_slabs` before we return with an out-of-memory
 /// error. If a page becomes full after the allocation we move it from `slabs` to
@@ -38,11 +42,14 @@ pub fn align_12k(ptr: usize) -> usize {
 ///
 /// Similarly, on dealloaction we might move a page from `full_slabs` to `slabs`
 /// or from `slabs` to `empty_slabs` after we deallocated an object.
-pub struct SCAllocator {
+///
+/// Its class is one of the size class policy `P`.
+pub struct SCAllocator<P = DefaultSizeClass> {
     /// Tracks the start of full slab. It is meaningful only if `full_count >= 1`
     full_start: *mut EfObjectPage,
-    /// Tracks the start of partial slab. It is meaningful only if `partial_count >= 1`
-    partial_start: *mut EfObjectPage,
+    /// Tracks the start of the partial slabs of each bucket, null if the
+    /// bucket is empty
+    partial_start: [*mut EfObjectPage; PARTIAL_BUCKETS],
     /// Tracks the start of empty slab. It is meaningful only if `empty_count >= 1`
     empty_start: *mut EfObjectPage,
     /// Tracks the start of uninitialized slab. It is meaningful only if `uninit_count >= 1`
@@ -61,24 +68,68 @@ pub struct SCAllocator {
     pg_count: i32,
     // when allocating, what align between chunks shall we take
     pg_align: i32,
+    // how many empty pages we keep, the surplus goes back to the backend
+    max_empty: usize,
+    // index of the size class, stamped in the page map entries of our pages
+    class: usize,
+    policy: PhantomData<P>,
 }
 
-impl SCAllocator {
+/// Bytes of empty pages each size class keeps for itself
+///
+/// Past it, freed pages go back to the backend, where other size classes
+/// and large allocations can reuse them.
+pub const EMPTY_SLAB_BYTES: usize = 1 << 20;
+
+/// Buckets of partial pages, by the share of their objects in use
+///
+/// Bucket `b` holds the pages with `b / PARTIAL_BUCKETS` to
+/// `(b + 1) / PARTIAL_BUCKETS` of their objects in use. Batches are taken
+/// from the fullest pages first, like the span prioritization of tcmalloc,
+/// so nearly empty pages get the chance to drain and go back to the backend.
+pub const PARTIAL_BUCKETS: usize = 4;
+
+impl<P: SizeClassPolicy> SCAllocator<P> {
     // The new "new" function takes three parameters:
-    // current size class, current size class's idx, how many OS pages are combined into one page.rs
-    pub fn new(size_class: usize, num_os_pages: usize) -> Self {
+    // current size class, how many OS pages are combined into one page.rs, current size class's idx
+    pub fn new(size_class: usize, num_os_pages: usize, class: usize) -> Self {
         if size_class == 0 {
             return Self {
                 full_start: null_mut(),
-                partial_start: null_mut(),
+                partial_start: [null_mut(); PARTIAL_BUCKETS],
                 empty_start: null_mut(),
                 uninit_start: null_mut(),
                 empty_count: 0,
                 pg_count: 0,
                 pg_num: 0,
                 pg_align: 0,
+                max_empty: 0,
+                class,
+                policy: PhantomData,
             };
         }
+        let pg_count = (PAGE_SIZE * num_os_pages) / size_class;
+        Self {
+            full_start: null_mut(),
+            partial_start: [null_mut(); PARTIAL_BUCKETS],
+            empty_start: null_mut(),
+            uninit_start: null_mut(),
+            empty_count: 0,
+            pg_count: pg_count as i32,
+            pg_num: num_os_pages,
+            pg_align: Self::stride(size_class, num_os_pages) as i32,
+            max_empty: (EMPTY_SLAB_BYTES / (PAGE_SIZE * num_os_pages)).max(1),
+            class,
+            policy: PhantomData,
+        }
+    }
+
+    /// Distance between two objects of `size_class` bytes in a page of
+    /// `num_os_pages` pages
+    ///
+    /// The slack of the page is spread between the objects, as far as it
+    /// keeps them aligned.
+    fn stride(size_class: usize, num_os_pages: usize) -> usize {
         let pg_count = (PAGE_SIZE * num_os_pages) / size_class;
         let perfect_align: usize = (PAGE_SIZE * num_os_pages) / pg_count;
         let diff_aligh: usize = perfect_align - size_class;
@@ -93,21 +144,10 @@ impl SCAllocator {
         } else {
             (size_class / min_align + 1) * min_align
         };
-        let align: usize = if perfect_align.is_power_of_two() {
+        if perfect_align.is_power_of_two() {
             perfect_align
         } else {
             rem
-        };
-
-        Self {
-            full_start: null_mut(),
-            partial_start: null_mut(),
-            empty_start: null_mut(),
-            uninit_start: null_mut(),
-            empty_count: 0,
-            pg_count: pg_count as i32,
-            pg_num: num_os_pages,
-            pg_align: align as i32,
         }
     }
 
@@ -149,9 +189,17 @@ impl SCAllocator {
         }
     }
 
+    /// Bucket of a partial page with `in_use` objects in use
+    fn partial_bucket(&self, in_use: usize) -> usize {
+        debug_assert!(in_use > 0 && in_use < self.pg_count as usize);
+        in_use * PARTIAL_BUCKETS / self.pg_count as usize
+    }
+
+    /// Links the partial page `idx` in the bucket of its current occupancy
     pub fn insert_partial(&mut self, idx: &mut EfObjectPage) {
-        if !self.partial_start.is_null() {
-            let partial_head = self.partial_start;
+        let bucket = self.partial_bucket(idx.in_use());
+        if !self.partial_start[bucket].is_null() {
+            let partial_head = self.partial_start[bucket];
             let prev = Self::get_ref(partial_head).get_prev();
             idx.set_prev(prev as *const _ as usize);
             idx.set_next(partial_head as *const _ as usize);
@@ -160,16 +208,21 @@ impl SCAllocator {
         } else {
             idx.set_prev(idx as *const _ as usize);
             idx.set_next(idx as *const _ as usize);
-            self.partial_start = idx;
+            self.partial_start[bucket] = idx;
         }
     }
 
+    /// Unlinks the partial page `idx`
+    ///
+    /// Its occupancy must not have changed since it was inserted, as it
+    /// picks the bucket.
     pub fn remove_partial(&mut self, idx: &mut EfObjectPage) {
-        assert!(!self.partial_start.is_null());
-        let partial_head = self.partial_start;
+        let bucket = self.partial_bucket(idx.in_use());
+        assert!(!self.partial_start[bucket].is_null());
+        let partial_head = self.partial_start[bucket];
         if core::ptr::eq(Self::get_ref(partial_head).get_next(), partial_head) {
             assert_eq!(idx as *const _ as usize, partial_head as *const _ as usize);
-            self.partial_start = null_mut();
+            self.partial_start[bucket] = null_mut();
         } else {
             let prev = idx.get_prev();
             idx.get_next().set_prev(prev as *const _ as usize);
@@ -178,7 +231,7 @@ impl SCAllocator {
             // idx.set_next(idx as *const _ as usize);
             // idx.set_prev(idx as *const _ as usize);
             if core::ptr::eq(partial_head, idx) {
-                self.partial_start = next;
+                self.partial_start[bucket] = next;
             }
         }
     }
@@ -223,37 +276,44 @@ impl SCAllocator {
         ety_head
     }
 
-    pub fn get_uninit(&mut self) -> (&mut EfObjectPage, usize) {
-        if !self.uninit_start.is_null() {
-            let res = self.remove_uninit();
-            let ptr = res.allocate_page(self.pg_num as usize) as usize;
-            (res, ptr)
+    /// Takes an uninitialized page and backs it with memory
+    ///
+    /// When the backend is out of memory, the page stays uninitialized.
+    pub fn get_uninit(&mut self) -> Result<(&mut EfObjectPage, usize)> {
+        let res = if !self.uninit_start.is_null() {
+            self.remove_uninit()
         } else {
-            if let Ok(memory) =
-                unsafe { PG_BUMP.lock().alloc(core::mem::size_of::<EfObjectPage>()) }
-            {
-                let res = unsafe {
-                    core::ptr::write(memory as *mut EfObjectPage, EfObjectPage::new());
-                    (memory as *mut EfObjectPage).as_mut().expect("err")
-                };
-                let ptr = res.allocate_page(self.pg_num as usize) as usize;
-                return (res, ptr);
+            let memory = unsafe { PG_BUMP.lock().alloc(core::mem::size_of::<EfObjectPage>())? };
+            unsafe {
+                core::ptr::write(memory as *mut EfObjectPage, EfObjectPage::new());
+                (memory as *mut EfObjectPage).as_mut().expect("err")
+            }
+        };
+        match res.allocate_page(self.pg_num as usize) {
+            Ok(ptr) => Ok((res, ptr as usize)),
+            Err(e) => {
+                self.link_uninit(res);
+                Err(e)
             }
-            panic!();
         }
     }
 
-    pub fn get_empty(&mut self) -> (*mut EfObjectPage, Option<usize>) {
+    pub fn get_empty(&mut self) -> Result<(*mut EfObjectPage, Option<usize>)> {
         if !self.empty_start.is_null() {
             let res = self.remove_empty();
-            (res, None)
+            Ok((res, None))
         } else {
-            let ans = self.get_uninit();
-            (ans.0, Some(ans.1))
+            let ans = self.get_uninit()?;
+            Ok((ans.0, Some(ans.1)))
         }
     }
 
     pub fn insert_uninit(&mut self, idx: &mut EfObjectPage) -> *mut u8 {
+        self.link_uninit(idx);
+        idx.destroy_page(self.pg_num as usize)
+    }
+
+    fn link_uninit(&mut self, idx: &mut EfObjectPage) {
         if !self.uninit_start.is_null() {
             let uninit_head = self.uninit_start;
             let prev = Self::get_ref(uninit_head).get_prev();
@@ -266,12 +326,11 @@ impl SCAllocator {
             idx.set_next(idx as *const _ as usize);
             self.uninit_start = idx;
         }
-        idx.destroy_page(self.pg_num as usize)
     }
 
     // This function is only used in deallocate, for more info, refer to that function
     pub fn try_insert_ety(&mut self, idx: &mut EfObjectPage) -> Option<usize> {
-        if self.empty_count > 2048 {
+        if self.empty_count >= self.max_empty {
             let ptr = idx.get_data_ptr();
             Some(ptr as usize)
         } else {
@@ -280,7 +339,7 @@ impl SCAllocator {
     }
 
     pub fn insert_ety(&mut self, idx: &mut EfObjectPage) -> Option<usize> {
-        if self.empty_count > 2048 {
+        if self.empty_count >= self.max_empty {
             let ptr = self.insert_uninit(idx);
             Some(ptr as usize)
         } else {
@@ -301,57 +360,153 @@ impl SCAllocator {
         }
     }
 
-    fn handle_rd_tree_insert(pg_num: usize, ptr_map: &mut RadixTree, idx: usize, addr: usize) {
+    /// Maps the pages at `addr` to their object page `idx`
+    ///
+    /// The low 48 bits of the entries hold `idx`, the bits above hold the
+    /// size class. Fails when the tree cannot allocate a node, leaving
+    /// nothing mapped.
+    fn handle_rd_tree_insert(
+        pg_num: usize,
+        ptr_map: &mut RadixTree,
+        idx: usize,
+        addr: usize,
+    ) -> Result<()> {
         let num = 1_usize << 16;
         let rem = num - ((addr >> PAGE_SIZE.trailing_zeros()) & (num - 1));
         let ptr = align_12k(addr);
         if rem >= pg_num {
             ptr_map
                 .insert(ptr << 16, (idx) as i64, pg_num)
-                .expect("err");
+                .or(Err(AllocError::ENOMEM))
         } else {
             let temp = ptr;
-            ptr_map.insert(temp << 16, (idx) as i64, rem).expect("err");
             ptr_map
+                .insert(temp << 16, (idx) as i64, rem)
+                .or(Err(AllocError::ENOMEM))?;
+            if ptr_map
                 .insert((ptr + 4096_usize * rem) << 16, (idx) as i64, pg_num - rem)
-                .expect("err");
+                .is_err()
+            {
+                ptr_map.remove(temp << 16, rem).expect("err");
+                return Err(AllocError::ENOMEM);
+            }
+            Ok(())
+        }
+    }
+
+    /// Takes the fullest partial page out of its list, or else an empty one
+    ///
+    /// A page new to the page map is mapped first.
+    fn take_page(&mut self, ptr_map: &mut RadixTree) -> Result<&'static mut EfObjectPage> {
+        let fullest = self
+            .partial_start
+            .iter()
+            .rev()
+            .copied()
+            .find(|page| !page.is_null());
+        if let Some(page) = fullest {
+            let idx = Self::get_ref(page);
+            self.remove_partial(idx);
+            return Ok(idx);
         }
+        let idx = self.get_empty()?;
+        let obj = Self::get_ref(idx.0);
+        if let Some(addr) = idx.1 {
+            if let Err(e) = Self::handle_rd_tree_insert(
+                self.pg_num as usize,
+                ptr_map,
+                obj as *const _ as usize | self.class << 48,
+                addr,
+            ) {
+                self.insert_uninit(obj);
+                return Err(e);
+            }
+        }
+        Ok(obj)
     }
 
     // We use the same strategy from tcmalloc:
-    // We first try to alloc from partial, then create empty page
+    // We first try to alloc from the fullest partial page, then create empty page
+    // The page handed out is stamped with `owner`
     pub fn allocate_batch_v2(
         &mut self,
-        align: usize,
+        owner: u32,
         ptr_map: &mut RadixTree,
-    ) -> Result<(*mut u8, usize, Option<usize>)> {
-        let pg_count = self.pg_count;
-        let pg_align = self.pg_align;
-        if !self.partial_start.is_null() && align == 1 {
-            let idx = Self::get_ref(self.partial_start);
-            let ans = idx.allocate_all(pg_count as usize, pg_align as usize);
-            self.remove_partial(idx);
+    ) -> Result<(*mut u8, usize, Option<usize>, bool)> {
+        let idx = self.take_page(ptr_map)?;
+        let ans = idx.allocate_all(self.pg_count as usize, self.pg_align as usize);
+        idx.set_owner(owner);
+        self.insert_full(idx);
+        Ok(ans)
+    }
+
+    /// Hands a page over to the page heap `owner`
+    ///
+    /// The page leaves the lists of this allocator until it is abandoned,
+    /// see [`Self::abandon_page`].
+    pub fn adopt_page(
+        &mut self,
+        owner: u32,
+        ptr_map: &mut RadixTree,
+    ) -> Result<&'static mut EfObjectPage> {
+        let idx = self.take_page(ptr_map)?;
+        if idx.is_empty() {
+            idx.carve(self.pg_count as usize, self.pg_align as usize);
+        }
+        idx.adopt(owner);
+        Ok(idx)
+    }
+
+    /// Takes back a page its page heap gave up
+    pub fn abandon_page(&mut self, idx: &mut EfObjectPage, ptr_map: &mut RadixTree) {
+        idx.abandon();
+        if idx.is_empty() {
+            if let Some(p) = self.try_insert_ety(idx) {
+                self.handle_rd_tree_remove(ptr_map, p);
+            }
+            self.insert_ety(idx);
+        } else if idx.is_full(self.pg_count as usize) {
             self.insert_full(idx);
-            Ok(ans)
         } else {
-            let idx = self.get_empty();
-            let obj = Self::get_ref(idx.0);
-            let new_page = idx.1;
-            let ans = obj.allocate_all(pg_count as usize, pg_align as usize);
-
-            if let Some(addr) = new_page {
-                Self::handle_rd_tree_insert(
-                    self.pg_num as usize,
-                    ptr_map,
-                    obj as *const _ as usize,
-                    addr,
-                );
-            }
-            self.insert_full(obj);
-            Ok(ans)
+            self.insert_partial(idx);
         }
     }
 
+    /// Returns the size class of the slab page holding `ptr`, if it is one
+    pub fn page_class(ptr: usize, ptr_map: &mut RadixTree) -> Option<usize> {
+        let entry = ptr_map.get_mut(align_12k(ptr) << 16);
+        match entry >> 48 {
+            class if class > 0 && (class as usize) < P::TOTAL => Some(class as usize),
+            _ => None,
+        }
+    }
+
+    /// Whether `ptr`, in a slab page of class `idx`, is where one of its
+    /// objects starts
+    ///
+    /// Free objects start there as well.
+    pub fn is_object_start(ptr: usize, idx: usize, ptr_map: &mut RadixTree) -> bool {
+        let data = Self::page_of(ptr, ptr_map).data() as usize;
+        let stride = Self::stride(P::rounded_size(idx), P::num_pages(idx));
+        let pg_count = (PAGE_SIZE * P::num_pages(idx)) / P::rounded_size(idx);
+        ptr >= data && (ptr - data) % stride == 0 && (ptr - data) / stride < pg_count
+    }
+
+    /// Returns the page holding `ptr`
+    ///
+    /// `ptr` must be a live object of a slab, so its page stays in `ptr_map`
+    pub fn page_of(ptr: usize, ptr_map: &mut RadixTree) -> &'static mut EfObjectPage {
+        let page_vaddr = align_12k(ptr);
+        let idx = ptr_map.get_mut(page_vaddr << 16) & ((1i64 << 48) - 1);
+        debug_assert_ne!(idx, 0);
+        Self::get_ref(idx as *mut EfObjectPage)
+    }
+
+    /// Returns the owner of the page holding `ptr`, see [`Self::page_of`]
+    pub fn page_owner(ptr: usize, ptr_map: &mut RadixTree) -> u32 {
+        Self::page_of(ptr, ptr_map).owner()
+    }
+
     fn handle_rd_tree_remove(&self, ptr_map: &mut RadixTree, addr: usize) {
         let rem = 4096 - ((addr >> PAGE_SIZE.trailing_zeros()) % 4096);
         let ptr = align_12k(addr);
@@ -382,24 +537,20 @@ impl SCAllocator {
                     .expect("err")
             };
             // assert_ne!(obj_pge.is_empty());
-            let mut back_partial = false;
+            // the page is unlinked while its occupancy still picks its bucket
             if obj_pge.is_full(self.pg_count as usize) {
-                back_partial = true;
+                self.remove_full(obj_pge);
+            } else {
+                self.remove_partial(obj_pge);
             }
             head = obj_pge.deallocate(head as usize, self.pg_num as usize);
 
             if obj_pge.is_empty() {
-                if back_partial {
-                    self.remove_full(obj_pge);
-                } else {
-                    self.remove_partial(obj_pge);
-                }
                 if let Some(p) = self.try_insert_ety(obj_pge) {
                     self.handle_rd_tree_remove(ptr_map, p);
                 }
                 self.insert_ety(obj_pge);
-            } else if back_partial {
-                self.remove_full(obj_pge);
+            } else {
                 self.insert_partial(obj_pge);
             }
         }
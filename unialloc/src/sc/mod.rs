@@ -1,5 +1,6 @@
 mod efficient_sc;
 mod separate_sc;
+use crate::error::Result;
 #[cfg(not(feature = "fixed_heap"))]
 use crate::pal::sys_alloc as system_alloc;
 #[cfg(not(feature = "fixed_heap"))]
@@ -107,29 +108,36 @@ impl BumpAlloc {
         panic!()
     }
 
-    pub fn alloc(&mut self, size: usize) -> Result<*mut u8, AllocError> {
-        let cur_start = self.start;
-        if !(self.current > self.start && (self.current - cur_start) <= Self::DEFAULT_SIZE) {
+    /// Carves `size` bytes, from a fresh reserve if the current one is too
+    /// short
+    ///
+    /// Reserves of regular pages count against the limits as they are
+    /// carved, huge pages as a whole.
+    pub fn alloc(&mut self, size: usize) -> Result<*mut u8> {
+        if self.current < self.start + size || self.current - self.start > Self::DEFAULT_SIZE {
             #[cfg(not(feature = "fixed_heap"))]
             {
                 let prot = system_alloc::prots::get_prot(true, true, false);
                 let start = unsafe {
                     #[cfg(feature = "hugepage")]
-                    let mut ptr = system_alloc::mmap_huge(Self::DEFAULT_SIZE, prot) as *mut u8;
+                    let ptr = system_alloc::mmap_huge(Self::DEFAULT_SIZE, prot);
                     #[cfg(not(feature = "hugepage"))]
-                    let mut ptr = system_alloc::mmap(Self::DEFAULT_SIZE, prot) as *mut u8;
-                    // When fail, mmap return -1, which is 0xffffffffffff
-                    // So need to use i64 to identify if it fails and return null
-                    if ptr as usize == usize::MAX {
-                        ptr = core::ptr::null_mut();
-                    }
-                    ptr
+                    let ptr = system_alloc::mmap_uncommitted(Self::DEFAULT_SIZE, prot);
+                    ptr as usize
                 };
-                self.start = start as usize;
+                // When fail, mmap return -1, which is 0xffffffffffff
+                if start == usize::MAX {
+                    return Err(crate::error::AllocError::ENOMEM);
+                }
+                self.start = start;
                 self.current = self.start + Self::DEFAULT_SIZE;
             }
             #[cfg(feature = "fixed_heap")]
-            return Err(AllocError);
+            return Err(crate::error::AllocError::ENOMEM);
+        }
+        #[cfg(not(any(feature = "fixed_heap", feature = "hugepage")))]
+        if !crate::pal::limit::commit(size) {
+            return Err(crate::error::AllocError::ENOMEM);
         }
         let new_cur = self.current - size;
         self.current = new_cur;
@@ -166,7 +174,7 @@ impl MetaBumpAlloc {
         self.bumper.init_with_range(start, end, page_size);
     }
 
-    pub fn alloc(&mut self, size: usize) -> Result<*mut u8, AllocError> {
+    pub fn alloc(&mut self, size: usize) -> Result<*mut u8> {
         let alloc_unit = core::mem::size_of::<ThreadCache>();
         let size = (size + alloc_unit - 1) / alloc_unit * alloc_unit;
         if size == alloc_unit {
@@ -195,9 +203,9 @@ pub static mut META_BUMP: Mutex<MetaBumpAlloc> = Mutex::new(MetaBumpAlloc::new()
 pub struct MetaAllocator {}
 
 unsafe impl Allocator for MetaAllocator {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let raw_ptr = unsafe { META_BUMP.lock().alloc(layout.size()) }.expect("err") as *mut u8;
-        let ptr = NonNull::new(raw_ptr).ok_or(core::alloc::AllocError)?;
+    fn allocate(&self, layout: Layout) -> core::result::Result<NonNull<[u8]>, AllocError> {
+        let raw_ptr = unsafe { META_BUMP.lock().alloc(layout.size()) }.or(Err(AllocError))?;
+        let ptr = NonNull::new(raw_ptr).ok_or(AllocError)?;
         Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
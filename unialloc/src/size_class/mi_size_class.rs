@@ -1,22 +1,13 @@
 use super::*;
 use crate::*;
-use alloc_macros::generate_num_pages;
+
+include!(concat!(env!("OUT_DIR"), "/size_classes_mi.rs"));
 
 const SZOFUSIZE: usize = core::mem::size_of::<usize>();
-pub const TOTAL_SIZE_CLASS: usize = 46;
-pub const MAX_SIZE: usize = 40960;
-pub const BACKEND_MAX_PAGE: usize = 128;
 fn bsr(x: u64) -> u8 {
     (63 - x.leading_zeros()) as u8
 }
 
-// TODO: handle size 0
-const SIZE_ARRAY: [usize; TOTAL_SIZE_CLASS] = [
-    8, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512,
-    640, 768, 896, 1024, 1280, 1536, 1792, 2048, 2560, 3072, 3584, 4096, 5120, 6144, 7168, 8192,
-    10240, 12288, 14336, 16384, 20480, 24576, 28672, 32768, 40960,
-];
-
 fn get_idx_by_size(req: usize) -> usize {
     let n: usize = get_num_of_usize(req) - 1;
     let b = bsr(n as u64);
@@ -60,13 +51,36 @@ pub fn get_size_class(req: usize) -> SizeClass {
 }
 
 pub fn get_rounded_size_by_idx(idx: usize) -> usize {
-    SIZE_ARRAY[idx]
+    SIZE_CLASSES[idx]
 }
 
-generate_num_pages! {
-    8, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512,
-    640, 768, 896, 1024, 1280, 1536, 1792, 2048, 2560, 3072, 3584, 4096, 5120, 6144, 7168, 8192,
-    10240, 12288, 14336, 16384, 20480, 24576, 28672, 32768, 40960
+/// The classes of mimalloc up to 40K, four per power of two
+pub struct MiSizeClass;
+
+impl SizeClassPolicy for MiSizeClass {
+    const TOTAL: usize = TOTAL_SIZE_CLASS;
+    const MAX_SIZE: usize = MAX_SIZE;
+    const ALIGNED_CLASSES: &'static [u8] = &ALIGNED_CLASSES;
+
+    #[inline]
+    fn size_class(req: usize) -> SizeClass {
+        get_size_class(req)
+    }
+
+    #[inline]
+    fn rounded_size(idx: usize) -> usize {
+        get_rounded_size_by_idx(idx)
+    }
+
+    #[inline]
+    fn num_pages(idx: usize) -> usize {
+        get_num_pages_by_idx(idx)
+    }
+
+    #[inline]
+    fn objects_per_slab(idx: usize) -> usize {
+        OBJECTS_PER_SLAB[idx]
+    }
 }
 
 #[cfg(test)]
@@ -124,4 +138,12 @@ mod tests {
         assert_eq!(get_size_class(40960).index(), get_idx_by_size(40960));
         assert_eq!(get_size_class(32768).index(), get_idx_by_size(32768));
     }
+
+    #[test]
+    fn size_class_matches_lookup() {
+        for req in 0..=MAX_SIZE {
+            let idx = CLASS_LOOKUP[lookup_index(req)] as usize;
+            assert_eq!(get_size_class(req), SizeClass::Base(idx), "req: {}", req);
+        }
+    }
 }
@@ -2,6 +2,9 @@ mod mi_size_class;
 mod small_size_class;
 mod tc_size_class;
 
+use core::alloc::Layout;
+use core::intrinsics::likely;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum SizeClass {
     Base(usize),
@@ -24,37 +27,149 @@ impl SizeClass {
     }
 }
 
-// use crate::size_class::get_sizeclass_tuple2;
+pub use mi_size_class::MiSizeClass;
+pub use small_size_class::Pow2SizeClass;
+pub use tc_size_class::TcSizeClass;
+
+/// The policy of the global heap, a fixed heap is too small for the others
+#[cfg(not(feature = "fixed_heap"))]
+pub type DefaultSizeClass = TcSizeClass;
+#[cfg(feature = "fixed_heap")]
+pub type DefaultSizeClass = Pow2SizeClass;
+
+/// Room for classes in the per-class tables of zones and thread caches,
+/// whatever their policy
+pub const MAX_CLASSES: usize = 64;
+
+const _: () = assert!(TcSizeClass::TOTAL <= MAX_CLASSES);
+const _: () = assert!(MiSizeClass::TOTAL <= MAX_CLASSES);
+const _: () = assert!(Pow2SizeClass::TOTAL <= MAX_CLASSES);
+
+/// Largest block, in pages, the free lists of the backend keep
 #[cfg(not(feature = "fixed_heap"))]
-pub use tc_size_class::*;
-// pub use mi_size_class::*;
+pub const BACKEND_MAX_PAGE: usize = 128;
 #[cfg(feature = "fixed_heap")]
-pub use small_size_class::*;
+pub const BACKEND_MAX_PAGE: usize = 32;
+
+/// Alignments from `1 << MIN_ALIGN_SHIFT` up to a page get a row in the
+/// `ALIGNED_CLASSES` tables generated from `size_classes.txt`, smaller ones
+/// are met by every class
+pub const MIN_ALIGN_SHIFT: usize = 4;
+
+/// Index of `req` bytes in the `CLASS_LOOKUP` tables generated from
+/// `size_classes.txt`
+///
+/// Requests up to 1K take an entry per 8 bytes and larger ones an entry per
+/// 128 bytes, like `lookup_index` of the build script.
+#[inline]
+pub(crate) const fn lookup_index(req: usize) -> usize {
+    if req <= 1024 {
+        (req + 7) >> 3
+    } else {
+        ((req + 127) >> 7) + 120
+    }
+}
+
+/// How requests are rounded up to size classes
+///
+/// The class 0 only serves zero-sized requests, the classes below `TOTAL`
+/// serve requests up to `MAX_SIZE` bytes and larger ones are large blocks.
+/// The slab allocators, zones and thread caches of a heap are generic over
+/// its policy, so they cannot disagree on the classes.
+pub trait SizeClassPolicy: 'static {
+    /// Number of classes, at most [`MAX_CLASSES`]
+    const TOTAL: usize;
+    /// Largest request served by a class
+    const MAX_SIZE: usize;
+    /// Makes a heap of a policy with too many classes fail to build
+    const FITS: () = assert!(Self::TOTAL <= MAX_CLASSES, "too many size classes");
 
-// pub use mi_size_class as size_class;
+    /// Gets the class of `req` bytes
+    fn size_class(req: usize) -> SizeClass;
 
-// pub trait GlobalSizeclass {
-//     /// Gets the corresponding size class by requested size
-//     fn get_size_class(sz: usize) -> SizeClass;
+    /// Bytes held by the objects of class `idx`
+    fn rounded_size(idx: usize) -> usize;
 
-//     /// Gets the corresponding size by size class index
-//     fn get_rounded_size_by_idx(idx: usize) -> usize;
+    /// Pages of `PAGE_SIZE` bytes in a slab of class `idx`
+    fn num_pages(idx: usize) -> usize;
 
-//     /// Gets number of pages of by size class index
-//     fn get_num_pages_by_idx(idx: usize) -> usize;
+    /// Objects in a slab of class `idx`
+    fn objects_per_slab(idx: usize) -> usize;
 
-//     /// Rounds the size to allocation size
-//     fn get_rounded_size(sz: usize) -> usize {
-//         let idx = Self::get_size_class(sz).index();
-//         Self::get_rounded_size_by_idx(idx)
-//     }
+    /// `ALIGNED_CLASSES[row * TOTAL + idx]` is the first class from `idx` on
+    /// whose size is a multiple of `1 << (row + MIN_ALIGN_SHIFT)`, or `TOTAL`
+    /// if none, as generated from `size_classes.txt`
+    const ALIGNED_CLASSES: &'static [u8];
 
-//     /// Gets a tuple of size class and rounded size
-//     fn get_size_class_tuple(sz: usize) -> (SizeClass, usize) {
-//         let cl = Self::get_size_class(sz);
-//         (cl, Self::get_rounded_size_by_idx(cl.index()))
-//     }
-// }
+    /// Gets the class of `req` bytes aligned to `align`
+    ///
+    /// Slab objects start at a page boundary, so a class whose size is a
+    /// multiple of `align` also has a stride that is, and all of its objects
+    /// are aligned. The first such class from the one of `req` is taken, up
+    /// to a page alignment. Other requests are large blocks.
+    #[inline]
+    fn size_class_aligned(req: usize, align: usize) -> SizeClass {
+        let cls = Self::size_class(req);
+        if likely(align < 1 << MIN_ALIGN_SHIFT) {
+            return cls;
+        }
+        match cls {
+            SizeClass::Base(idx) if align <= crate::PAGE_SIZE => {
+                let row = align.trailing_zeros() as usize - MIN_ALIGN_SHIFT;
+                let aligned = Self::ALIGNED_CLASSES[row * Self::TOTAL + idx] as usize;
+                if aligned < Self::TOTAL {
+                    SizeClass::Base(aligned)
+                } else {
+                    SizeClass::Large(req)
+                }
+            }
+            SizeClass::Base(_) => SizeClass::Large(req),
+            large => large,
+        }
+    }
+
+    /// Gets the class serving `layout`
+    #[inline]
+    fn size_class_by_layout(layout: Layout) -> SizeClass {
+        Self::size_class_aligned(layout.size(), layout.align())
+    }
+}
+
+/// Number of classes of the global heap
+pub const TOTAL_SIZE_CLASS: usize = DefaultSizeClass::TOTAL;
+
+/// Largest request served by a class of the global heap
+pub const MAX_SIZE: usize = DefaultSizeClass::MAX_SIZE;
+
+/// Gets the class of `req` bytes in the global heap
+#[inline]
+pub fn get_size_class(req: usize) -> SizeClass {
+    DefaultSizeClass::size_class(req)
+}
+
+/// Gets the class of `req` bytes aligned to `align` in the global heap
+#[inline]
+pub fn get_size_class_aligned(req: usize, align: usize) -> SizeClass {
+    DefaultSizeClass::size_class_aligned(req, align)
+}
+
+/// Bytes held by the objects of class `idx` of the global heap
+#[inline]
+pub fn get_rounded_size_by_idx(idx: usize) -> usize {
+    DefaultSizeClass::rounded_size(idx)
+}
+
+/// Pages in a slab of class `idx` of the global heap
+#[inline]
+pub fn get_num_pages_by_idx(idx: usize) -> usize {
+    DefaultSizeClass::num_pages(idx)
+}
+
+/// Objects in a slab of class `idx` of the global heap
+#[inline]
+pub fn get_objects_per_slab_by_idx(idx: usize) -> usize {
+    DefaultSizeClass::objects_per_slab(idx)
+}
 
 /// Rounds the size to allocation size
 pub fn get_rounded_size(sz: usize) -> usize {
@@ -67,3 +182,72 @@ pub fn get_size_class_tuple(sz: usize) -> (SizeClass, usize) {
     let cl = get_size_class(sz);
     (cl, get_rounded_size_by_idx(cl.index()))
 }
+
+/// Gets the size class serving `layout`
+///
+/// Over-aligned requests take a class whose objects all meet the alignment,
+/// or go to the page heap if there is none.
+#[inline]
+pub fn get_size_class_by_layout(layout: Layout) -> SizeClass {
+    get_size_class_aligned(layout.size(), layout.align())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_policy<P: SizeClassPolicy>() {
+        assert_eq!(P::size_class(0), SizeClass::Base(0));
+        assert_eq!(P::rounded_size(P::TOTAL - 1), P::MAX_SIZE);
+        for idx in 1..P::TOTAL {
+            assert!(P::rounded_size(idx) > P::rounded_size(idx - 1));
+            let slab = P::num_pages(idx) * crate::PAGE_SIZE;
+            assert_eq!(P::objects_per_slab(idx), slab / P::rounded_size(idx));
+            assert!(P::objects_per_slab(idx) > 0);
+        }
+        for req in 1..=P::MAX_SIZE {
+            let idx = P::size_class(req).index();
+            assert!(P::rounded_size(idx) >= req, "req: {}", req);
+            assert!(idx == 1 || P::rounded_size(idx - 1) < req, "req: {}", req);
+        }
+        assert_eq!(
+            P::size_class(P::MAX_SIZE + 1),
+            SizeClass::Large(P::MAX_SIZE + 1)
+        );
+    }
+
+    fn check_aligned<P: SizeClassPolicy>() {
+        for align in (0..13).map(|shift| 1 << shift) {
+            for req in (1..=P::MAX_SIZE).step_by(7) {
+                let cls = P::size_class_aligned(req, align);
+                if let SizeClass::Base(idx) = cls {
+                    assert!(P::rounded_size(idx) >= req);
+                    assert_eq!(P::rounded_size(idx) % align, 0, "req: {}", req);
+                }
+                // the first class from the one of `req` meeting `align`
+                let first = (P::size_class(req).index()..P::TOTAL)
+                    .find(|&idx| P::rounded_size(idx) % align == 0)
+                    .map_or(SizeClass::Large(req), SizeClass::Base);
+                assert_eq!(cls, first, "req: {}, align: {}", req, align);
+            }
+        }
+    }
+
+    #[test]
+    fn tc_size_class_test() {
+        check_policy::<TcSizeClass>();
+        check_aligned::<TcSizeClass>();
+    }
+
+    #[test]
+    fn mi_size_class_test() {
+        check_policy::<MiSizeClass>();
+        check_aligned::<MiSizeClass>();
+    }
+
+    #[test]
+    fn pow2_size_class_test() {
+        check_policy::<Pow2SizeClass>();
+        check_aligned::<Pow2SizeClass>();
+    }
+}
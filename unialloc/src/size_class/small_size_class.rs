@@ -1,33 +1,70 @@
-use crate::size_class::SizeClass;
+use crate::size_class::{lookup_index, SizeClass, SizeClassPolicy};
 
-const SIZE_ARRAY: [u16; 10] = [
-    0_u16, 8_u16, 16_u16, 32_u16, 64_u16, 128_u16, 256_u16, 512_u16, 1024_u16, 2048_u16,
-];
-
-pub const BACKEND_MAX_PAGE: usize = 32;
-
-pub const TOTAL_SIZE_CLASS: usize = 10;
-
-pub const MAX_SIZE: usize = 2048;
+include!(concat!(env!("OUT_DIR"), "/size_classes_pow2.rs"));
 
 pub fn get_rounded_size_by_idx(idx: usize) -> usize {
-    SIZE_ARRAY[idx] as usize
+    SIZE_CLASSES[idx]
 }
 
 pub fn get_size_class_by_idx(idx: usize) -> usize {
-    SIZE_ARRAY[idx] as usize
+    SIZE_CLASSES[idx]
 }
 
-pub fn get_num_pages_by_idx(_idx: usize) -> usize {
-    1
+pub fn get_num_pages_by_idx(idx: usize) -> usize {
+    SIZE_CLASS_PAGES[idx]
 }
 
 pub fn get_size_class(req: usize) -> SizeClass {
-    let final_size = (req + 7) & !(7_usize);
-    if final_size <= MAX_SIZE {
-        let idx = final_size.trailing_zeros() as usize;
-        SizeClass::Base(idx - 2)
+    if req <= MAX_SIZE {
+        SizeClass::Base(CLASS_LOOKUP[lookup_index(req)] as usize)
     } else {
         SizeClass::Large(req)
     }
 }
+
+/// Gets the size class of `req` bytes aligned to `align`
+///
+/// Every class is a power of two, so the class of `align` is aligned as well.
+pub fn get_size_class_aligned(req: usize, align: usize) -> SizeClass {
+    if align <= 8 {
+        return get_size_class(req);
+    }
+    match get_size_class(req.max(align)) {
+        SizeClass::Base(idx) => SizeClass::Base(idx),
+        SizeClass::Large(_) => SizeClass::Large(req),
+    }
+}
+
+/// Powers of two up to 2K, each in a single page, for small fixed heaps
+pub struct Pow2SizeClass;
+
+impl SizeClassPolicy for Pow2SizeClass {
+    const TOTAL: usize = TOTAL_SIZE_CLASS;
+    const MAX_SIZE: usize = MAX_SIZE;
+    const ALIGNED_CLASSES: &'static [u8] = &ALIGNED_CLASSES;
+
+    #[inline]
+    fn size_class(req: usize) -> SizeClass {
+        get_size_class(req)
+    }
+
+    #[inline]
+    fn rounded_size(idx: usize) -> usize {
+        get_rounded_size_by_idx(idx)
+    }
+
+    #[inline]
+    fn num_pages(idx: usize) -> usize {
+        get_num_pages_by_idx(idx)
+    }
+
+    #[inline]
+    fn objects_per_slab(idx: usize) -> usize {
+        OBJECTS_PER_SLAB[idx]
+    }
+
+    #[inline]
+    fn size_class_aligned(req: usize, align: usize) -> SizeClass {
+        get_size_class_aligned(req, align)
+    }
+}
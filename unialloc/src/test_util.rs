@@ -0,0 +1,16 @@
+//! Helpers shared by the unit tests
+
+/// Links the objects `range` of `stride` bytes from `base` into a null
+/// terminated list, returns its head
+pub fn link(base: *mut u8, stride: usize, range: core::ops::Range<usize>) -> *mut u8 {
+    let end = range.end;
+    for i in range.clone() {
+        let next = if i + 1 < end {
+            base as usize + (i + 1) * stride
+        } else {
+            0
+        };
+        unsafe { *(base.add(i * stride) as *mut usize) = next };
+    }
+    unsafe { base.add(range.start * stride) }
+}
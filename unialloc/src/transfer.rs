@@ -0,0 +1,188 @@
+//! Transfer caches of the zone
+//!
+//! Thread caches refill and flush whole batches of objects. Rather than
+//! locking the slab allocator of the size class, a flushed batch is parked in
+//! a slot of the transfer cache of the class, where the next refill of any
+//! thread takes it as is. The slab allocator is only locked when no batch is
+//! parked, or when all the slots are taken.
+//!
+//! A slot holds the head of a null terminated list, with the length of the
+//! list in the upper 16 bits. Slots are only ever swapped out whole, which
+//! makes them free of ABA issues.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Batches parked per size class, a power of two
+pub const TRANSFER_SLOTS: usize = 16;
+
+/// Position of the length of the list in a slot
+const COUNT_SHIFT: u32 = 48;
+
+/// Longest list a slot can hold
+const MAX_COUNT: usize = (1 << (usize::BITS - COUNT_SHIFT)) - 1;
+
+/// Lock-free slots of parked batches of one size class
+#[repr(align(64))]
+pub struct TransferCache {
+    slots: [AtomicUsize; TRANSFER_SLOTS],
+    /// Whether a batch was taken since the last `drain_idle`
+    used: AtomicBool,
+}
+
+impl TransferCache {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+
+    pub const fn new() -> Self {
+        Self {
+            slots: [Self::EMPTY; TRANSFER_SLOTS],
+            used: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes a parked batch
+    ///
+    /// Returns the head of the list and its length
+    pub fn take(&self) -> Option<(*mut u8, usize)> {
+        let start = start_slot();
+        for i in 0..TRANSFER_SLOTS {
+            let slot = &self.slots[(start + i) % TRANSFER_SLOTS];
+            if slot.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let packed = slot.swap(0, Ordering::Acquire);
+            if packed != 0 {
+                if !self.used.load(Ordering::Relaxed) {
+                    self.used.store(true, Ordering::Relaxed);
+                }
+                return Some(unpack(packed));
+            }
+        }
+        None
+    }
+
+    /// Parks the null terminated list `head` of `count` objects
+    ///
+    /// Lists shorter than `min` objects are not worth a slot. Returns false,
+    /// leaving the list to the caller, if the list is not parked.
+    pub fn put(&self, head: *mut u8, count: usize, min: usize) -> bool {
+        if count < min || count > MAX_COUNT {
+            return false;
+        }
+        let start = start_slot();
+        let free = (0..TRANSFER_SLOTS)
+            .map(|i| (start + i) % TRANSFER_SLOTS)
+            .find(|&i| self.slots[i].load(Ordering::Relaxed) == 0);
+        let free = match free {
+            Some(free) => free,
+            None => return false,
+        };
+        let packed = head as usize | count << COUNT_SHIFT;
+        for i in 0..TRANSFER_SLOTS {
+            let slot = &self.slots[(free + i) % TRANSFER_SLOTS];
+            if slot.load(Ordering::Relaxed) == 0
+                && slot
+                    .compare_exchange(0, packed, Ordering::Release, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Hands every parked batch to `release`, unless a batch was taken since
+    /// the last call
+    ///
+    /// Batches nobody takes would otherwise keep their slabs forever.
+    pub fn drain_idle(&self, mut release: impl FnMut(*mut u8)) {
+        if self.used.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        for slot in self.slots.iter() {
+            if slot.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let packed = slot.swap(0, Ordering::Acquire);
+            if packed != 0 {
+                release(unpack(packed).0);
+            }
+        }
+    }
+}
+
+fn unpack(packed: usize) -> (*mut u8, usize) {
+    (
+        (packed & ((1 << COUNT_SHIFT) - 1)) as *mut u8,
+        packed >> COUNT_SHIFT,
+    )
+}
+
+/// Slot to start scanning from
+///
+/// Threads run on different stacks, so hashing the page of the stack spreads
+/// them over the slots instead of having all of them race on the first one.
+#[inline]
+fn start_slot() -> usize {
+    let marker = 0u8;
+    let page = &marker as *const u8 as usize >> 12;
+    page.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (usize::BITS - TRANSFER_SLOTS.trailing_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use core::ptr::null_mut;
+
+    /// Links `nodes` into a null terminated list
+    fn link(nodes: &mut [usize]) -> *mut u8 {
+        let len = nodes.len();
+        crate::test_util::link(nodes.as_mut_ptr() as *mut u8, size_of::<usize>(), 0..len)
+    }
+
+    #[test]
+    fn put_take_test() {
+        let cache = TransferCache::new();
+        assert!(cache.take().is_none());
+
+        let mut short = [0usize; 3];
+        assert!(!cache.put(link(&mut short), 3, 4));
+
+        let mut batches = [[0usize; 8]; TRANSFER_SLOTS + 1];
+        for batch in batches[..TRANSFER_SLOTS].iter_mut() {
+            assert!(cache.put(link(batch), 8, 4));
+        }
+        // every slot is taken
+        assert!(!cache.put(link(&mut batches[TRANSFER_SLOTS]), 8, 4));
+
+        let mut heads = [null_mut(); TRANSFER_SLOTS];
+        for head in heads.iter_mut() {
+            let (batch, count) = cache.take().expect("err");
+            assert_eq!(count, 8);
+            *head = batch;
+        }
+        assert!(cache.take().is_none());
+        for batch in batches[..TRANSFER_SLOTS].iter_mut() {
+            assert!(heads.contains(&(batch.as_mut_ptr() as *mut u8)));
+        }
+    }
+
+    #[test]
+    fn drain_idle_test() {
+        let cache = TransferCache::new();
+        let mut first = [0usize; 4];
+        let mut second = [0usize; 4];
+        assert!(cache.put(link(&mut first), 4, 1));
+        assert!(cache.put(link(&mut second), 4, 1));
+        cache.take().expect("err");
+
+        // a batch was taken since the cache was created
+        let mut drained = 0;
+        cache.drain_idle(|_| drained += 1);
+        assert_eq!(drained, 0);
+
+        cache.drain_idle(|_| drained += 1);
+        assert_eq!(drained, 1);
+        assert!(cache.take().is_none());
+    }
+}
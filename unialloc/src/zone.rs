@@ -1,10 +1,15 @@
+use crate::cache::remote::NO_OWNER;
 use crate::collections::radix_tree::{get_rd_tree, RadixTree};
 use crate::error::{AllocError, Result};
+use crate::page::EfObjectPage;
+#[cfg(all(target_os = "linux", not(feature = "fixed_heap")))]
+use crate::pal::sys_alloc;
 use crate::prelude::*;
 use crate::sc::SCAllocator;
-use crate::sc::META_BUMP;
+use crate::sc::{align_12k, META_BUMP};
 #[cfg(not(feature = "fixed_heap"))]
 use crate::sync::PthreadMutex as Mutex;
+use crate::transfer::TransferCache;
 use alloc::{boxed::Box, slice};
 use core::alloc::{Allocator, GlobalAlloc, Layout};
 use core::borrow::BorrowMut;
@@ -12,32 +17,68 @@ use core::cmp;
 use core::mem::MaybeUninit;
 use core::ops::Deref;
 use core::ptr::{null_mut, NonNull};
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 #[cfg(feature = "fixed_heap")]
 use spin::Mutex;
 
+/// Size class in the page map entry of a large block, whose low bits hold
+/// the size of the block in pages of `LARGE_PAGE`
+const LARGE_CLASS: i64 = 0x7fff;
+/// Large blocks are made of pages of the backend
+const LARGE_PAGE: usize = 4096;
+
+/// Flushed batches shorter than a slab worth of objects divided by this go
+/// to the slab directly, as refills would bring too few objects
+const TRANSFER_MIN_FRACTION: usize = 4;
+
+/// Slow paths of the zone between two returns of the idle transfer batches,
+/// see [`ZoneAllocator::release_idle_transfers`]
+const TRANSFER_RELEASE_PERIOD: usize = 256;
+
+/// Large blocks past the lists of the backend go back to the OS when freed,
+/// so the OS can also move them when they grow
+#[cfg(all(target_os = "linux", not(feature = "fixed_heap")))]
+const MREMAP_MIN: usize = crate::size_class::BACKEND_MAX_PAGE * LARGE_PAGE;
+
+/// Bytes the backend hands out for a large block of `size` bytes
+pub fn round_large(size: usize) -> usize {
+    (size.max(1) + LARGE_PAGE - 1) & !(LARGE_PAGE - 1)
+}
+
 /// An allocator holding a bunch of slabs
 ///
 /// It dispatches the allocation request to different slab
-/// according to the index of size class
-pub struct ZoneAllocator {
-    slabs: [Mutex<SCAllocator>; TOTAL_SIZE_CLASS],
+/// according to the index of size class, of the size class policy `P`.
+/// Batches moving between thread caches go through the transfer cache of
+/// their class, without locking the slab.
+pub struct ZoneAllocator<P = DefaultSizeClass> {
+    slabs: [Mutex<SCAllocator<P>>; MAX_CLASSES],
+    transfer: [TransferCache; MAX_CLASSES],
+    /// Slow paths taken so far, the idle transfer batches are returned once
+    /// in `TRANSFER_RELEASE_PERIOD` of them
+    slow_paths: AtomicUsize,
 }
 
-impl ZoneAllocator {
+impl<P: SizeClassPolicy> ZoneAllocator<P> {
     pub fn new() -> Self {
+        let () = P::FITS;
         let mut ans = Self {
             slabs: unsafe { MaybeUninit::uninit().assume_init() },
+            transfer: unsafe { MaybeUninit::uninit().assume_init() },
+            slow_paths: AtomicUsize::new(0),
         };
+        for item in ans.transfer.iter_mut() {
+            unsafe { core::ptr::write(item, TransferCache::new()) };
+        }
         for (idx, item) in ans.slabs.iter_mut().enumerate() {
+            // the slabs past the classes of the policy stay empty
+            let (size, pages) = if idx < P::TOTAL {
+                (P::rounded_size(idx), P::num_pages(idx))
+            } else {
+                (0, 0)
+            };
             unsafe {
-                core::ptr::write(
-                    item,
-                    Mutex::new(SCAllocator::new(
-                        get_rounded_size_by_idx(idx),
-                        get_num_pages_by_idx(idx),
-                    )),
-                );
+                core::ptr::write(item, Mutex::new(SCAllocator::new(size, pages, idx)));
             }
         }
         ans
@@ -51,14 +92,48 @@ impl ZoneAllocator {
     // }
 
     /// Allocates a batch of chunks from a specific slab described by `idx`
+    ///
+    /// The page of the batch is marked as owned by the thread cache `owner`.
+    /// A batch parked in the transfer cache is taken first, its pages keep
+    /// their owners, which only change under the lock of the slab. See
+    /// [`EfObjectPage::allocate_all`] for the batch.
     pub fn allocate_batch_from_slab(
         &mut self,
         idx: usize,
-        align: usize,
-    ) -> Result<(*mut u8, usize, Option<usize>)> {
+        owner: u32,
+    ) -> Result<(*mut u8, usize, Option<usize>, bool)> {
         debug_assert!(idx < self.slabs.len(), "idx: {}", idx);
-        let sc: &mut Mutex<SCAllocator> = &mut self.slabs[idx];
-        sc.lock().allocate_batch_v2(align, get_rd_tree())
+        if let Some((head, count)) = self.transfer[idx].take() {
+            return Ok((head, count, None, false));
+        }
+        self.release_idle_transfers_if_due();
+        let sc: &mut Mutex<SCAllocator<P>> = &mut self.slabs[idx];
+        sc.lock().allocate_batch_v2(owner, get_rd_tree())
+    }
+
+    /// Returns the thread cache owning the slab object `ptr`
+    pub fn owner_of(&self, ptr: NonNull<u8>) -> u32 {
+        SCAllocator::<P>::page_owner(ptr.as_ptr() as usize, get_rd_tree())
+    }
+
+    /// Finds the size class of the block at `ptr` through the page map
+    ///
+    /// Returns `None` for blocks the allocator did not hand out, and for
+    /// addresses inside a block. The size of a large block is in bytes.
+    pub fn class_of(&self, ptr: *const u8) -> Option<SizeClass> {
+        let rd_tree = get_rd_tree();
+        if let Some(idx) = SCAllocator::<P>::page_class(ptr as usize, rd_tree) {
+            return SCAllocator::<P>::is_object_start(ptr as usize, idx, rd_tree)
+                .then(|| SizeClass::Base(idx));
+        }
+        let entry = rd_tree.get_mut(align_12k(ptr as usize) << 16);
+        if entry >> 48 == LARGE_CLASS && (ptr as usize & (LARGE_PAGE - 1)) == 0 {
+            Some(SizeClass::Large(
+                (entry & ((1 << 48) - 1)) as usize * LARGE_PAGE,
+            ))
+        } else {
+            None
+        }
     }
 
     // /// Deallocates a chunk to the slab desceibed by `idx`
@@ -70,23 +145,226 @@ impl ZoneAllocator {
     //     Ok(())
     // }
 
-    pub fn deallocate_batch_to_slab(&mut self, idx: usize, ptr: *mut u8) -> Result<()> {
+    /// Returns the null terminated list `ptr` of `count` objects of class
+    /// `idx`
+    ///
+    /// Long enough lists are parked in the transfer cache for the next
+    /// refill, if it has room. A caller not knowing the length passes 0, the
+    /// list then goes to the slab.
+    pub fn deallocate_batch_to_slab(
+        &mut self,
+        idx: usize,
+        ptr: *mut u8,
+        count: usize,
+    ) -> Result<()> {
         assert!(idx < self.slabs.len());
-        let sc: &mut Mutex<SCAllocator> = &mut self.slabs[idx];
+        let min = (P::objects_per_slab(idx) / TRANSFER_MIN_FRACTION).max(2);
+        if self.transfer[idx].put(ptr, count, min) {
+            return Ok(());
+        }
+        self.release_idle_transfers_if_due();
+        let sc: &mut Mutex<SCAllocator<P>> = &mut self.slabs[idx];
         sc.lock().deallocate_batch(ptr as *mut usize, get_rd_tree())
     }
+
+    /// Returns the batches of the transfer caches no refill took from since
+    /// the last call to the slabs
+    ///
+    /// The background thread calls it at every tick, and the slow paths once
+    /// in `TRANSFER_RELEASE_PERIOD` of them.
+    pub fn release_idle_transfers(&mut self) {
+        for idx in 1..P::TOTAL {
+            let sc = &self.slabs[idx];
+            self.transfer[idx].drain_idle(|head| {
+                sc.lock()
+                    .deallocate_batch(head as *mut usize, get_rd_tree())
+                    .expect("dealloc err");
+            });
+        }
+    }
+
+    /// Counts a slow path, and runs [`Self::release_idle_transfers`] once in
+    /// `TRANSFER_RELEASE_PERIOD` of them
+    ///
+    /// Called before the lock of any slab is taken.
+    fn release_idle_transfers_if_due(&mut self) {
+        let taken = self.slow_paths.fetch_add(1, Ordering::Relaxed);
+        if taken % TRANSFER_RELEASE_PERIOD == 0 {
+            self.release_idle_transfers();
+        }
+    }
+
+    /// Hands a page of class `idx` over to the page heap `owner`
+    ///
+    /// All the free objects of the page are linked in its free list. Pages
+    /// of page heaps bypass the transfer caches, which hand out objects of
+    /// any page.
+    pub fn adopt_page(&mut self, idx: usize, owner: u32) -> Result<&'static mut EfObjectPage> {
+        debug_assert!(idx < self.slabs.len(), "idx: {}", idx);
+        self.slabs[idx].lock().adopt_page(owner, get_rd_tree())
+    }
+
+    /// Takes back the page `page` of class `idx` its page heap gave up
+    pub fn abandon_page(&mut self, idx: usize, page: &mut EfObjectPage) {
+        assert!(idx < self.slabs.len());
+        self.slabs[idx].lock().abandon_page(page, get_rd_tree())
+    }
+
+    /// Returns the page of a page heap holding the slab object `ptr`
+    pub fn page_of(&self, ptr: NonNull<u8>) -> &'static mut EfObjectPage {
+        SCAllocator::<P>::page_of(ptr.as_ptr() as usize, get_rd_tree())
+    }
+
+    /// Frees the object `ptr` of class `idx` whose page was abandoned
+    ///
+    /// Pages only change hands under the lock of their slab, so the page is
+    /// either still in the slab or was adopted by another page heap since.
+    pub fn deallocate_abandoned(&mut self, idx: usize, ptr: NonNull<u8>) -> Result<()> {
+        assert!(idx < self.slabs.len());
+        let mut sc = self.slabs[idx].lock();
+        let page = SCAllocator::<P>::page_of(ptr.as_ptr() as usize, get_rd_tree());
+        if page.owner() != NO_OWNER && page.free_remote(ptr.as_ptr()) {
+            return Ok(());
+        }
+        unsafe { *(ptr.as_ptr() as *mut usize) = 0 };
+        sc.deallocate_batch(ptr.as_ptr() as *mut usize, get_rd_tree())
+    }
 }
 
-impl ZoneAllocator {
+impl<P: SizeClassPolicy> ZoneAllocator<P> {
+    /// Allocates a block from the backend and records it in the page map
     pub fn allocate_large(&mut self, layout: Layout) -> Result<NonNull<u8>> {
-        unsafe {
-            let ptr = GlobalBackend.alloc(layout);
-            let res = NonNull::new_unchecked(ptr);
-            Ok(res)
+        Self::track_large(unsafe { GlobalBackend.alloc(layout) }, layout)
+    }
+
+    /// Like [`Self::allocate_large`], with the block zeroed
+    ///
+    /// The backend knows which of its pages are zero already.
+    pub fn allocate_large_zeroed(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        Self::track_large(unsafe { GlobalBackend.alloc_zeroed(layout) }, layout)
+    }
+
+    /// Records the block `ptr` the backend handed out for `layout`, which is
+    /// freed if that fails
+    fn track_large(ptr: *mut u8, layout: Layout) -> Result<NonNull<u8>> {
+        let ptr = NonNull::new(ptr).ok_or(AllocError::ENOMEM)?;
+        if Self::record_large(ptr, layout.size()).is_err() {
+            unsafe { GlobalBackend.dealloc(ptr.as_ptr(), layout) };
+            return Err(AllocError::ENOMEM);
+        }
+        Ok(ptr)
+    }
+
+    /// Gives the pages of a large block past `new_size` back to the backend
+    ///
+    /// Fails, leaving the block as is, when its size cannot be recorded.
+    pub fn shrink_large(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_size: usize,
+    ) -> Result<()> {
+        let old = round_large(old_layout.size());
+        let new = round_large(new_size);
+        if new < old {
+            Self::record_large(ptr, new)?;
+            unsafe {
+                GlobalBackend.dealloc(
+                    ptr.as_ptr().add(new),
+                    Layout::from_size_align_unchecked(old - new, LARGE_PAGE),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Resizes the large block `ptr` to `new_size` bytes without copying it
+    ///
+    /// Shrinking, and growing over the free pages right after the block, keep
+    /// it in place. Blocks past `MREMAP_MIN` bytes are otherwise moved by the
+    /// OS. Fails, leaving the block as is, when none of these apply.
+    pub fn reallocate_large(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>> {
+        let old = round_large(old_layout.size());
+        let new = round_large(new_size);
+        if new <= old {
+            self.shrink_large(ptr, old_layout, new_size)?;
+            return Ok(ptr);
+        }
+        if unsafe { GlobalBackend.grow_in_place(ptr.as_ptr(), old, new) } {
+            if Self::record_large(ptr, new).is_err() {
+                unsafe {
+                    GlobalBackend.dealloc(
+                        ptr.as_ptr().add(old),
+                        Layout::from_size_align_unchecked(new - old, LARGE_PAGE),
+                    );
+                }
+                return Err(AllocError::ENOMEM);
+            }
+            return Ok(ptr);
+        }
+        #[cfg(all(target_os = "linux", not(feature = "fixed_heap")))]
+        if old > MREMAP_MIN && old_layout.align() <= LARGE_PAGE {
+            return Self::remap_large(ptr, old, new);
+        }
+        Err(AllocError::ENOMEM)
+    }
+
+    /// Grows the large block `ptr` from `old` to `new` bytes through the OS
+    ///
+    /// The place the block may move to is reserved and recorded first, so
+    /// that a failure leaves the block where it was.
+    #[cfg(all(target_os = "linux", not(feature = "fixed_heap")))]
+    fn remap_large(ptr: NonNull<u8>, old: usize, new: usize) -> Result<NonNull<u8>> {
+        let to = unsafe { sys_alloc::reserve(new) };
+        let to = match NonNull::new(to) {
+            Some(to) if to.as_ptr() != libc::MAP_FAILED as *mut u8 => to,
+            _ => return Err(AllocError::ENOMEM),
+        };
+        if Self::record_large(to, new).is_err() {
+            unsafe { sys_alloc::unreserve(to.as_ptr(), new) };
+            return Err(AllocError::ENOMEM);
+        }
+        let moved = unsafe { sys_alloc::mremap(ptr.as_ptr(), old, new, to.as_ptr()) };
+        // the entry of the place not taken goes
+        let unused = if moved == to.as_ptr() { ptr } else { to };
+        get_rd_tree()
+            .remove(align_12k(unused.as_ptr() as usize) << 16, 1)
+            .expect("err");
+        if moved == to.as_ptr() {
+            return Ok(to);
+        }
+        unsafe { sys_alloc::unreserve(to.as_ptr(), new) };
+        if moved != ptr.as_ptr() {
+            return Err(AllocError::ENOMEM);
+        }
+        // grown in place, over an entry that exists already
+        if Self::record_large(ptr, new).is_err() {
+            unsafe { sys_alloc::mremap(ptr.as_ptr(), new, old, null_mut()) };
+            return Err(AllocError::ENOMEM);
         }
+        Ok(ptr)
+    }
+
+    /// Stamps the size of the large block `ptr` in the page map
+    ///
+    /// Only fails when the block is new to the page map and the tree cannot
+    /// allocate a node.
+    fn record_large(ptr: NonNull<u8>, size: usize) -> Result<()> {
+        let entry = LARGE_CLASS << 48 | (round_large(size) / LARGE_PAGE) as i64;
+        get_rd_tree()
+            .insert(align_12k(ptr.as_ptr() as usize) << 16, entry, 1)
+            .or(Err(AllocError::ENOMEM))
     }
 
     pub fn deallocate_large(&mut self, page_ptr: NonNull<u8>, layout: Layout) {
+        get_rd_tree()
+            .remove(align_12k(page_ptr.as_ptr() as usize) << 16, 1)
+            .expect("err");
         unsafe {
             GlobalBackend.dealloc(page_ptr.as_ptr(), layout);
         }
@@ -195,6 +473,83 @@ pub use Fixed_Zone::*;
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::link;
+
+    #[test]
+    fn transfer_test() {
+        let zone = Box::leak(Box::new(ZoneAllocator::<DefaultSizeClass>::new()));
+        let idx = get_size_class(48).index();
+        let (head, count, stride, _) = zone.allocate_batch_from_slab(idx, 1).expect("err");
+        if let Some(stride) = stride {
+            link(head, stride, 0..count);
+        }
+        zone.deallocate_batch_to_slab(idx, head, count)
+            .expect("err");
+
+        // the flushed batch is the next refill, its page keeps its owner
+        let refill = zone.allocate_batch_from_slab(idx, 2).expect("err");
+        assert_eq!(refill, (head, count, None, false));
+        assert_eq!(zone.owner_of(NonNull::new(head).expect("err")), 1);
+
+        zone.deallocate_batch_to_slab(idx, head, count)
+            .expect("err");
+        // a batch was taken since the zone was created
+        zone.release_idle_transfers();
+        assert_eq!(zone.allocate_batch_from_slab(idx, 1).expect("err").0, head);
+        zone.deallocate_batch_to_slab(idx, head, count)
+            .expect("err");
+        zone.release_idle_transfers();
+        zone.release_idle_transfers();
+        assert!(zone.transfer[idx].take().is_none());
+    }
+
+    #[test]
+    fn slow_path_release_test() {
+        let zone = Box::leak(Box::new(ZoneAllocator::<DefaultSizeClass>::new()));
+        let idx = get_size_class(48).index();
+        let (head, count, stride, _) = zone.allocate_batch_from_slab(idx, 1).expect("err");
+        let stride = stride.expect("err");
+        zone.deallocate_batch_to_slab(idx, link(head, stride, 0..count - 1), count - 1)
+            .expect("err");
+
+        // a list too short to park makes the batch nobody took go, once the
+        // period is over
+        zone.slow_paths
+            .store(TRANSFER_RELEASE_PERIOD, Ordering::Relaxed);
+        let last = link(head, stride, count - 1..count);
+        zone.deallocate_batch_to_slab(idx, last, 1).expect("err");
+        assert!(zone.transfer[idx].take().is_none());
+    }
+
+    #[test]
+    fn fullest_partial_test() {
+        let zone = Box::leak(Box::new(ZoneAllocator::<DefaultSizeClass>::new()));
+        let idx = get_size_class(48).index();
+        let rd_tree = get_rd_tree();
+        let mut slab = zone.slabs[idx].lock();
+        let (first, count, stride, _) = slab.allocate_batch_v2(1, rd_tree).expect("err");
+        let stride = stride.expect("err");
+        let (second, ..) = slab.allocate_batch_v2(1, rd_tree).expect("err");
+        assert!(count >= 4);
+
+        // the first page keeps one object in use, the second all but one
+        let list = link(first, stride, 1..count) as *mut usize;
+        slab.deallocate_batch(list, rd_tree).expect("err");
+        let list = link(second, stride, count - 1..count) as *mut usize;
+        slab.deallocate_batch(list, rd_tree).expect("err");
+
+        // the nearly full page goes first, the other one may drain meanwhile
+        let refill = slab.allocate_batch_v2(1, rd_tree).expect("err");
+        assert_eq!(
+            refill,
+            (unsafe { second.add((count - 1) * stride) }, 1, None, false)
+        );
+        let refill = slab.allocate_batch_v2(1, rd_tree).expect("err");
+        assert_eq!(
+            refill,
+            (unsafe { first.add(stride) }, count - 1, None, false)
+        );
+    }
 
     // #[test]
     // fn allocate_batch_sanity_check() {
@@ -0,0 +1,109 @@
+#![feature(allocator_api)]
+#![feature(slice_ptr_get)]
+use std::alloc::{Allocator, Layout};
+
+include!("allocator.rs");
+
+const KB: usize = 1 << 10;
+const MB: usize = 1 << 20;
+const GB: usize = 1 << 30;
+
+/// Checks every power-of-two alignment from a page up to `max_align`, with
+/// sizes below, at and above the alignment
+fn check_large_alignments<T: Allocator>(allocator: T, max_align: usize) {
+    let mut align = 4 * KB;
+    while align <= max_align {
+        for &size in &[1, 4 * KB, align / 2, align, align + 4 * KB] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocator.allocate(layout).expect("alloc failed");
+            let raw = ptr.as_non_null_ptr().as_ptr();
+            assert_eq!(
+                raw as usize % align,
+                0,
+                "Got a pointer less aligned than requested"
+            );
+            unsafe {
+                // touch both ends of the block
+                raw.write(0xa5);
+                raw.add(size - 1).write(0x5a);
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+        align <<= 1;
+    }
+}
+
+#[test]
+fn page_aligned() {
+    check_large_alignments(UniAlloc, 64 * KB);
+}
+
+#[test]
+fn huge_page_aligned() {
+    check_large_alignments(UniAlloc, 2 * MB);
+}
+
+#[test]
+fn over_aligned_small_objects() {
+    // small sizes with alignment above a page skip the slabs
+    for &align in &[8 * KB, 64 * KB, 2 * MB] {
+        let layout = Layout::from_size_align(64, align).unwrap();
+        let pointers: Vec<_> = (0..16)
+            .map(|_| UniAlloc.allocate(layout).expect("alloc failed"))
+            .collect();
+        for &ptr in &pointers {
+            assert_eq!(ptr.as_non_null_ptr().as_ptr() as usize % align, 0);
+        }
+        for ptr in pointers {
+            unsafe { UniAlloc.deallocate(ptr.as_non_null_ptr(), layout) };
+        }
+    }
+}
+
+#[test]
+fn small_aligned_objects() {
+    for &(size, align) in &[(48, 64), (24, 32), (100, 128), (520, 512), (1000, 4 * KB)] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        // cycle through the thread cache a few times
+        for _ in 0..4 {
+            let pointers: Vec<_> = (0..1000)
+                .map(|_| UniAlloc.allocate(layout).expect("alloc failed"))
+                .collect();
+            for &ptr in &pointers {
+                assert_eq!(ptr.as_non_null_ptr().as_ptr() as usize % align, 0);
+            }
+            for ptr in pointers {
+                unsafe { UniAlloc.deallocate(ptr.as_non_null_ptr(), layout) };
+            }
+        }
+    }
+}
+
+#[test]
+fn gigabyte_aligned() {
+    check_large_alignments(UniAlloc, GB);
+}
+
+#[test]
+fn aligned_blocks_reuse_split_pages() {
+    // the excess of an aligned block goes back to the free list and can be
+    // handed out again without corrupting the aligned block
+    let layout = Layout::from_size_align(8 * KB, 256 * KB).unwrap();
+    let aligned = UniAlloc.allocate(layout).expect("alloc failed");
+    let aligned_ptr = aligned.as_non_null_ptr().as_ptr();
+    unsafe { aligned_ptr.write_bytes(0x11, 8 * KB) };
+    let small = Layout::from_size_align(4 * KB, 4 * KB).unwrap();
+    let others: Vec<_> = (0..64)
+        .map(|_| UniAlloc.allocate(small).expect("alloc failed"))
+        .collect();
+    for &ptr in &others {
+        unsafe { ptr.as_non_null_ptr().as_ptr().write_bytes(0x22, 4 * KB) };
+    }
+    unsafe {
+        assert!((0..8 * KB).all(|i| *aligned_ptr.add(i) == 0x11));
+        for ptr in others {
+            UniAlloc.deallocate(ptr.as_non_null_ptr(), small);
+        }
+        UniAlloc.deallocate(aligned.as_non_null_ptr(), layout);
+    }
+}
@@ -0,0 +1,68 @@
+//! Checks the usable sizes and the resizing of `impl Allocator for UniAlloc`
+#![feature(allocator_api)]
+use std::alloc::{Allocator, Layout};
+use std::ptr::NonNull;
+
+include!("allocator.rs");
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+#[test]
+fn allocate_returns_usable_size() {
+    for size in [0, 1, 20, 1000, 28032, 28033, 100_000] {
+        let block = UniAlloc.allocate(layout(size)).unwrap();
+        assert!(block.len() >= size);
+        if size > 0 {
+            assert_eq!(Some(block.len()), UniAlloc.usable_size(block.as_mut_ptr()));
+        }
+        // the whole block can be handed back
+        unsafe { UniAlloc.deallocate(block.cast(), layout(block.len())) };
+    }
+}
+
+#[test]
+fn grow_within_class() {
+    let block = UniAlloc.allocate(layout(20)).unwrap();
+    let ptr: NonNull<u8> = block.cast();
+    unsafe {
+        ptr.as_ptr().write_bytes(1, 20);
+        let grown = UniAlloc
+            .grow_zeroed(ptr, layout(20), layout(block.len()))
+            .unwrap();
+        assert_eq!(grown.cast(), ptr);
+        let bytes = std::slice::from_raw_parts(ptr.as_ptr(), block.len());
+        assert!(bytes[..20].iter().all(|&b| b == 1));
+        assert!(bytes[20..].iter().all(|&b| b == 0));
+
+        // past the class the block moves
+        let moved = UniAlloc
+            .grow(ptr, layout(block.len()), layout(4000))
+            .unwrap();
+        assert_ne!(moved.cast(), ptr);
+        assert_eq!(*moved.as_mut_ptr().add(19), 1);
+        UniAlloc.deallocate(moved.cast(), layout(4000));
+    }
+}
+
+#[test]
+fn shrink_large_in_place() {
+    let block = UniAlloc.allocate(layout(1 << 20)).unwrap();
+    let ptr: NonNull<u8> = block.cast();
+    unsafe {
+        ptr.as_ptr().write_bytes(3, 1 << 20);
+        let shrunk = UniAlloc
+            .shrink(ptr, layout(1 << 20), layout(100_000))
+            .unwrap();
+        assert_eq!(shrunk.cast(), ptr);
+        assert!(shrunk.len() >= 100_000 && shrunk.len() < 1 << 20);
+        assert_eq!(UniAlloc.usable_size(ptr.as_ptr()), Some(shrunk.len()));
+        assert_eq!(*ptr.as_ptr().add(99_999), 3);
+
+        // down to a small class it moves
+        let small = UniAlloc.shrink(ptr, layout(100_000), layout(100)).unwrap();
+        assert_eq!(*small.as_mut_ptr().add(99), 3);
+        UniAlloc.deallocate(small.cast(), layout(100));
+    }
+}
@@ -0,0 +1,43 @@
+//! Drives the background maintenance thread
+//!
+//! There is a single thread per process, so everything runs in a single test.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+use unialloc::bg_thread::{self, Task};
+
+include!("allocator.rs");
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn count(arg: usize) {
+    RUNS.fetch_add(arg, Ordering::Relaxed);
+}
+
+#[test]
+fn start_tick_fork_stop() {
+    assert!(bg_thread::start(10));
+    assert!(!bg_thread::start(10));
+    assert!(bg_thread::is_running());
+
+    bg_thread::post(Task { run: count, arg: 3 }).ok().unwrap();
+    sleep(Duration::from_millis(200));
+    assert_eq!(RUNS.load(Ordering::Relaxed), 3);
+    assert!(bg_thread::stats().ticks > 0);
+    assert!(bg_thread::stats().committed > 0);
+
+    // the child has no thread, but can start its own
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let ok = !bg_thread::is_running() && bg_thread::start(10) && bg_thread::stop();
+        unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+    assert!(bg_thread::stop());
+    assert!(!bg_thread::stop());
+    assert!(!bg_thread::is_running());
+}
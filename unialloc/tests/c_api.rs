@@ -0,0 +1,112 @@
+//! Exercises the malloc family exported with the `c_api` feature
+//!
+//! The exported symbols replace the ones of libc in this binary.
+#![cfg(all(feature = "c_api", target_os = "linux"))]
+use std::ptr::null_mut;
+use unialloc::c_api::*;
+
+include!("allocator.rs");
+
+#[test]
+fn malloc_free_usable_size() {
+    unsafe {
+        for size in [0, 1, 24, 1000, 28032, 28033, 1 << 20] {
+            let ptr = malloc(size) as *mut u8;
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 16, 0);
+            assert!(malloc_usable_size(ptr as *mut _) >= size.max(1));
+            ptr.write_bytes(0xa5, size);
+            free(ptr as *mut _);
+        }
+        free(null_mut());
+    }
+}
+
+#[test]
+fn foreign_pointers() {
+    unsafe {
+        // libc is the allocator loaded after us here
+        let next_malloc: unsafe extern "C" fn(usize) -> *mut libc::c_void = std::mem::transmute(
+            libc::dlsym(libc::RTLD_NEXT, "malloc\0".as_ptr() as *const _),
+        );
+        // spans several OS pages, all of them are kept by `realloc`
+        let size = 64 << 10;
+        let ptr = next_malloc(size) as *mut u8;
+        ptr.write_bytes(7, size);
+        assert!(malloc_usable_size(ptr as *mut _) >= size);
+        let ptr = realloc(ptr as *mut _, 4 * size) as *mut u8;
+        assert!((0..size).all(|i| *ptr.add(i) == 7));
+        assert!(malloc_usable_size(ptr as *mut _) >= 4 * size);
+        free(ptr as *mut _);
+    }
+}
+
+#[test]
+fn calloc_realloc() {
+    unsafe {
+        let ptr = calloc(100, 40) as *mut u8;
+        assert!(!ptr.is_null());
+        assert!((0..4000).all(|i| *ptr.add(i) == 0));
+        ptr.write_bytes(7, 4000);
+
+        let ptr = realloc(ptr as *mut _, 100_000) as *mut u8;
+        assert!((0..4000).all(|i| *ptr.add(i) == 7));
+        let ptr = realloc(ptr as *mut _, 10) as *mut u8;
+        assert!((0..10).all(|i| *ptr.add(i) == 7));
+        assert!(malloc_usable_size(ptr as *mut _) < 4000);
+        assert!(realloc(ptr as *mut _, 0).is_null());
+
+        assert!(calloc(usize::MAX, 2).is_null());
+    }
+}
+
+#[test]
+fn realloc_large() {
+    unsafe {
+        // large blocks are resized by the zone, grown and shrunk
+        let size = 1 << 20;
+        let ptr = malloc(size) as *mut u8;
+        ptr.write_bytes(7, size);
+        let ptr = realloc(ptr as *mut _, 3 * size) as *mut u8;
+        assert!((0..size).all(|i| *ptr.add(i) == 7));
+        assert!(malloc_usable_size(ptr as *mut _) >= 3 * size);
+        ptr.add(size).write_bytes(8, 2 * size);
+        let ptr = realloc(ptr as *mut _, size + 4096) as *mut u8;
+        assert!((0..size).all(|i| *ptr.add(i) == 7));
+        assert!((size..size + 4096).all(|i| *ptr.add(i) == 8));
+        assert!(malloc_usable_size(ptr as *mut _) < 3 * size);
+        free(ptr as *mut _);
+    }
+}
+
+#[test]
+fn aligned() {
+    unsafe {
+        for align in [8, 64, 4096, 1 << 16] {
+            let mut ptr = null_mut();
+            assert_eq!(posix_memalign(&mut ptr, align, 100), 0);
+            assert_eq!(ptr as usize % align, 0);
+            free(ptr);
+
+            let ptr = aligned_alloc(align, 3 * align);
+            assert_eq!(ptr as usize % align, 0);
+            assert!(malloc_usable_size(ptr) >= 3 * align);
+            free(ptr);
+        }
+        let mut ptr = null_mut();
+        assert_eq!(posix_memalign(&mut ptr, 24, 100), libc::EINVAL);
+        assert!(aligned_alloc(24, 100).is_null());
+
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        for ptr in [memalign(100, 10), valloc(10), pvalloc(10)] {
+            assert!(!ptr.is_null());
+            free(ptr);
+        }
+        let ptr = valloc(10);
+        assert_eq!(ptr as usize % page, 0);
+        free(ptr);
+        let ptr = pvalloc(1);
+        assert!(malloc_usable_size(ptr) >= page);
+        free(ptr);
+    }
+}
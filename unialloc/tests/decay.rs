@@ -0,0 +1,72 @@
+//! Walks free backend pages through the decay states
+//!
+//! The decay times and counters are global, so everything runs in a single
+//! test.
+use std::alloc::{alloc, dealloc, Layout};
+use std::thread::sleep;
+use std::time::Duration;
+use unialloc::decay;
+
+include!("allocator.rs");
+
+const BLOCK: usize = 64 * 1024;
+const COUNT: usize = 64;
+const NEVER: u64 = u64::MAX;
+
+fn layout() -> Layout {
+    Layout::from_size_align(BLOCK, 8).unwrap()
+}
+
+fn alloc_touched() -> *mut u8 {
+    let ptr = unsafe { alloc(layout()) };
+    assert!(!ptr.is_null());
+    unsafe { ptr.write_bytes(0xa5, BLOCK) };
+    ptr
+}
+
+#[test]
+fn dirty_muzzy_clean() {
+    decay::set_dirty_decay_ms(NEVER);
+    decay::set_muzzy_decay_ms(NEVER);
+    // every other block stays allocated so that the freed ones do not merge
+    let mut blocks: Vec<*mut u8> = (0..2 * COUNT).map(|_| alloc_touched()).collect();
+    let dirty = decay::dirty_bytes();
+    for ptr in blocks.iter().step_by(2) {
+        unsafe { dealloc(*ptr, layout()) };
+    }
+    assert!(decay::dirty_bytes() >= dirty + COUNT * BLOCK);
+
+    // dirty pages become muzzy
+    let muzzy = decay::muzzy_bytes();
+    decay::set_dirty_decay_ms(50);
+    sleep(Duration::from_millis(100));
+    decay::decay();
+    assert!(decay::muzzy_bytes() >= muzzy + COUNT * BLOCK);
+    assert!(decay::dirty_bytes() < COUNT * BLOCK);
+
+    // then clean
+    decay::set_muzzy_decay_ms(50);
+    sleep(Duration::from_millis(100));
+    decay::decay();
+    assert!(decay::muzzy_bytes() < COUNT * BLOCK);
+
+    // purging does not wait
+    decay::set_dirty_decay_ms(NEVER);
+    decay::set_muzzy_decay_ms(NEVER);
+    for ptr in blocks.iter_mut().step_by(2) {
+        *ptr = alloc_touched();
+    }
+    for ptr in blocks.iter().step_by(2) {
+        unsafe { dealloc(*ptr, layout()) };
+    }
+    assert!(decay::dirty_bytes() > 0);
+    decay::purge();
+    assert!(decay::dirty_bytes() < BLOCK);
+    assert!(decay::muzzy_bytes() < BLOCK);
+
+    for ptr in blocks.iter().skip(1).step_by(2) {
+        unsafe { dealloc(*ptr, layout()) };
+    }
+    decay::set_dirty_decay_ms(10_000);
+    decay::set_muzzy_decay_ms(10_000);
+}
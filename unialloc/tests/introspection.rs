@@ -0,0 +1,50 @@
+//! Looks blocks up through `owns`, `usable_size` and `size_class_of`
+use std::alloc::{alloc, dealloc, Layout};
+use unialloc::SizeClass;
+
+include!("allocator.rs");
+
+#[test]
+fn small_objects() {
+    for size in [1, 8, 24, 100, 1000, 28032] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(UniAlloc.owns(ptr));
+        assert!(matches!(
+            UniAlloc.size_class_of(ptr),
+            Some(SizeClass::Base(_))
+        ));
+        let usable = UniAlloc.usable_size(ptr).unwrap();
+        assert!(usable >= size && usable < 2 * size + 16, "size: {}", size);
+        // only the start of a block is one
+        assert!(!UniAlloc.owns(ptr.wrapping_add(1)));
+        assert_eq!(UniAlloc.usable_size(ptr.wrapping_add(usable / 2 + 1)), None);
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn large_blocks() {
+    for (size, align) in [(28033, 8), (1 << 20, 8), (64, 1 << 13)] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(UniAlloc.owns(ptr));
+        let usable = UniAlloc.usable_size(ptr).unwrap();
+        assert!(usable >= size && usable % 4096 == 0, "size: {}", size);
+        assert_eq!(UniAlloc.size_class_of(ptr), Some(SizeClass::Large(usable)));
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn foreign_pointers() {
+    let local = 0u64;
+    assert!(!UniAlloc.owns(&local as *const u64 as *const u8));
+    assert_eq!(
+        UniAlloc.usable_size(&local as *const u64 as *const u8),
+        None
+    );
+    assert!(!UniAlloc.owns(std::ptr::null()));
+    let code = foreign_pointers as fn() as *const u8;
+    assert_eq!(UniAlloc.size_class_of(code), None);
+}
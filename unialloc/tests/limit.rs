@@ -0,0 +1,158 @@
+//! Exercises the process-wide memory limits
+//!
+//! The limits are global, so everything runs in a single test.
+use std::alloc::{alloc, dealloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use unialloc::limit;
+
+include!("allocator.rs");
+
+const MB: usize = 1 << 20;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+/// Threads inside `wait_for_other`
+static ENTERED: AtomicUsize = AtomicUsize::new(0);
+/// Blocks the handler may release, as addresses
+static SPARE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Large enough to get a mapping of its own
+fn large() -> Layout {
+    Layout::from_size_align(1280 * MB, 8).unwrap()
+}
+
+fn count_calls(_requested: usize) -> bool {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+fn release_spare(_requested: usize) -> bool {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    match SPARE.lock().unwrap().pop() {
+        Some(ptr) => {
+            unsafe { dealloc(ptr as *mut u8, large()) };
+            true
+        }
+        None => false,
+    }
+}
+
+fn purge_free(_requested: usize) -> bool {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    unialloc::decay::purge();
+    true
+}
+
+/// Waits for the handler to run on another thread too, at most 5 seconds
+fn wait_for_other(requested: usize) -> bool {
+    if requested < large().size() {
+        return false;
+    }
+    ENTERED.fetch_add(1, Ordering::SeqCst);
+    let start = Instant::now();
+    while ENTERED.load(Ordering::SeqCst) < 2 && start.elapsed() < Duration::from_secs(5) {
+        std::hint::spin_loop();
+    }
+    false
+}
+
+#[test]
+fn soft_and_hard_limits() {
+    let mut blocks: Vec<*mut u8> = Vec::with_capacity(8);
+    SPARE.lock().unwrap().reserve(8);
+
+    // the soft limit only calls the handler
+    limit::set_oom_handler(Some(count_calls));
+    limit::set_soft_limit(limit::committed());
+    let ptr = unsafe { alloc(large()) };
+    assert!(!ptr.is_null());
+    assert!(CALLS.load(Ordering::Relaxed) > 0);
+    unsafe { dealloc(ptr, large()) };
+    limit::set_soft_limit(0);
+
+    // the hard limit fails allocations
+    CALLS.store(0, Ordering::Relaxed);
+    limit::set_hard_limit(limit::committed() + 3 * 1024 * MB);
+    loop {
+        let ptr = unsafe { alloc(large()) };
+        if ptr.is_null() {
+            break;
+        }
+        blocks.push(ptr);
+        assert!(blocks.len() < 8, "the hard limit never applied");
+    }
+    assert_eq!(blocks.len(), 2);
+    assert!(limit::committed() <= limit::hard_limit());
+    assert!(CALLS.load(Ordering::Relaxed) > 0);
+
+    // unless the handler makes room
+    SPARE
+        .lock()
+        .unwrap()
+        .extend(blocks.drain(..).map(|ptr| ptr as usize));
+    limit::set_oom_handler(Some(release_spare));
+    let ptr = unsafe { alloc(large()) };
+    assert!(!ptr.is_null());
+
+    limit::set_hard_limit(0);
+    limit::set_oom_handler(None);
+    unsafe {
+        dealloc(ptr, large());
+        for ptr in SPARE.lock().unwrap().drain(..) {
+            dealloc(ptr as *mut u8, large());
+        }
+    }
+
+    // threads crossing the limit at the same time each run the handler
+    let start = Arc::new(Barrier::new(3));
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                unsafe { alloc(large()) }.is_null()
+            })
+        })
+        .collect();
+    limit::set_oom_handler(Some(wait_for_other));
+    limit::set_hard_limit(limit::committed() + 64 * MB);
+    start.wait();
+    for thread in threads {
+        assert!(thread.join().expect("thread"));
+    }
+    limit::set_hard_limit(0);
+    limit::set_oom_handler(None);
+    assert_eq!(ENTERED.load(Ordering::SeqCst), 2);
+
+    // the reserves of the backend do not take the room of a small limit
+    limit::set_hard_limit(limit::committed() + 64 * MB);
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let ptrs: Vec<usize> = (0..200_000)
+        .map(|_| unsafe { alloc(small) } as usize)
+        .collect();
+    assert!(ptrs.iter().all(|&ptr| ptr != 0));
+    for ptr in ptrs {
+        unsafe { dealloc(ptr as *mut u8, small) };
+    }
+
+    // and purging the free pages of the backend makes room
+    let block = Layout::from_size_align(256 * 1024, 8).unwrap();
+    let freed: Vec<usize> = (0..3).map(|_| unsafe { alloc(block) } as usize).collect();
+    assert!(freed.iter().all(|&ptr| ptr != 0));
+    for ptr in freed {
+        unsafe { dealloc(ptr as *mut u8, block) };
+    }
+    CALLS.store(0, Ordering::Relaxed);
+    limit::set_oom_handler(Some(purge_free));
+    limit::set_hard_limit(limit::committed());
+    let fresh = Layout::from_size_align(512 * 1024, 8).unwrap();
+    let ptr = unsafe { alloc(fresh) };
+    assert!(!ptr.is_null());
+    assert!(CALLS.load(Ordering::Relaxed) > 0);
+    assert!(limit::committed() <= limit::hard_limit());
+    limit::set_hard_limit(0);
+    limit::set_oom_handler(None);
+    unsafe { dealloc(ptr, fresh) };
+}
@@ -0,0 +1,109 @@
+//! Runs the allocator out of address space with `RLIMIT_AS`
+//!
+//! The limit applies to the whole process, so everything runs in a single
+//! test and restores the limit before returning.
+use std::alloc::{alloc, dealloc, Layout};
+
+include!("allocator.rs");
+
+const MB: usize = 1 << 20;
+
+/// Returns the current size of the address space in bytes
+fn address_space() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").expect("statm");
+    let pages: usize = statm
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .expect("statm");
+    pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize
+}
+
+/// Caps the address space at `limit` bytes, returns the previous limit
+fn set_limit(limit: libc::rlim_t) -> libc::rlimit {
+    let mut old = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_AS, &mut old), 0);
+        let new = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: old.rlim_max,
+        };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_AS, &new), 0);
+    }
+    old
+}
+
+/// Allocates `layout` until the allocator returns null, at most `max` times
+fn exhaust(layout: Layout, max: usize) -> Vec<*mut u8> {
+    let mut blocks = Vec::with_capacity(max);
+    for _ in 0..max {
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            return blocks;
+        }
+        blocks.push(ptr);
+    }
+    panic!("{} allocations of {:?} never failed", max, layout);
+}
+
+/// Fills `large_blocks` then `small_blocks` until the allocator returns null
+/// under a cap of `limit` bytes, frees them, and returns their bytes
+fn fill_and_free(
+    limit: usize,
+    large_blocks: &mut Vec<*mut u8>,
+    small_blocks: &mut Vec<*mut u8>,
+) -> usize {
+    let large = Layout::from_size_align(64 * MB, 8).unwrap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let old = set_limit(limit as libc::rlim_t);
+    // eat the backend reserve, then the leftovers through the slabs
+    large_blocks.extend(exhaust(large, large_blocks.capacity()));
+    small_blocks.extend(exhaust(small, small_blocks.capacity()));
+    unsafe { libc::setrlimit(libc::RLIMIT_AS, &old) };
+
+    let bytes = large_blocks.len() * large.size() + small_blocks.len() * small.size();
+    unsafe {
+        for ptr in small_blocks.drain(..) {
+            dealloc(ptr, small);
+        }
+        for ptr in large_blocks.drain(..) {
+            dealloc(ptr, large);
+        }
+    }
+    bytes
+}
+
+#[test]
+fn out_of_memory_returns_null() {
+    // warm up the caches so that their metadata is mapped
+    drop(vec![0u8; 64]);
+    // room for the bookkeeping of the blocks below
+    let mut large_blocks: Vec<*mut u8> = Vec::with_capacity(1 << 12);
+    let mut small_blocks: Vec<*mut u8> = Vec::with_capacity(1 << 22);
+    let limit = address_space() + 256 * MB;
+
+    // a request beyond the limit fails at once
+    let old = set_limit(limit as libc::rlim_t);
+    let mut v: Vec<u8> = Vec::new();
+    let failed = v.try_reserve(1 << 40).is_err();
+    unsafe { libc::setrlimit(libc::RLIMIT_AS, &old) };
+    assert!(failed);
+
+    let first = fill_and_free(limit, &mut large_blocks, &mut small_blocks);
+    assert!(first > 0);
+    // the freed memory serves the same requests again under the same limit
+    let second = fill_and_free(limit, &mut large_blocks, &mut small_blocks);
+    assert!(
+        second >= first / 10 * 9,
+        "first: {}, second: {}",
+        first,
+        second
+    );
+
+    // the allocator still works after running dry
+    let v = vec![7u64; 1024];
+    assert_eq!(v.iter().sum::<u64>(), 7 * 1024);
+}
@@ -0,0 +1,67 @@
+//! Runs a C program with the library in `LD_PRELOAD`
+//!
+//! The library is built with the `c_api` feature in a target directory of its
+//! own, the one of the running build being locked. The test is skipped when no
+//! C compiler is found, unless `UNIALLOC_REQUIRE_CC` is set.
+#![cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn build_library(target: &Path) -> PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--features", "c_api", "--target-dir"])
+        .arg(target)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("cargo");
+    assert!(status.success());
+    target.join("debug").join("libunialloc.so")
+}
+
+/// Compiles `tests/preload/prog.c`, returns None if there is no compiler
+fn build_program(target: &Path) -> Option<PathBuf> {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/preload/prog.c");
+    let prog = target.join("prog");
+    match Command::new("cc")
+        .arg("-o")
+        .arg(&prog)
+        .arg(src)
+        .arg("-ldl")
+        .status()
+    {
+        Ok(status) => {
+            assert!(status.success());
+            Some(prog)
+        }
+        Err(err) => {
+            assert!(
+                std::env::var_os("UNIALLOC_REQUIRE_CC").is_none(),
+                "no C compiler: {}",
+                err
+            );
+            eprintln!("preload: skipped, no C compiler ({})", err);
+            None
+        }
+    }
+}
+
+#[test]
+fn c_program() {
+    let target = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/preload");
+    std::fs::create_dir_all(&target).expect("target dir");
+    let prog = match build_program(&target) {
+        Some(prog) => prog,
+        None => return,
+    };
+    let library = build_library(&target);
+    let output = Command::new(prog)
+        .env("LD_PRELOAD", library)
+        .output()
+        .expect("run");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"ok\n");
+}
@@ -0,0 +1,74 @@
+/* Run by tests/preload.rs with the library in LD_PRELOAD */
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <malloc.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define CHECK(cond)                                                    \
+    do {                                                               \
+        if (!(cond)) {                                                 \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond); \
+            return 1;                                                  \
+        }                                                              \
+    } while (0)
+
+static int filled(const unsigned char *p, size_t len, unsigned char c)
+{
+    for (size_t i = 0; i < len; i++)
+        if (p[i] != c)
+            return 0;
+    return 1;
+}
+
+int main(void)
+{
+    /* malloc is ours */
+    Dl_info info;
+    CHECK(dladdr((void *)malloc, &info) && strstr(info.dli_fname, "libunialloc"));
+
+    void *blocks[64];
+    for (size_t i = 0; i < 64; i++) {
+        size_t size = (i * 977) % 40000 + 1;
+        blocks[i] = malloc(size);
+        CHECK(blocks[i] && (size_t)blocks[i] % 16 == 0);
+        CHECK(malloc_usable_size(blocks[i]) >= size);
+        memset(blocks[i], (int)i, size);
+    }
+    for (size_t i = 0; i < 64; i++)
+        free(blocks[i]);
+
+    unsigned char *p = calloc(1000, 40);
+    CHECK(p && filled(p, 40000, 0));
+    memset(p, 7, 40000);
+    p = realloc(p, 1 << 20);
+    CHECK(p && filled(p, 40000, 7));
+    free(p);
+
+    void *aligned = NULL;
+    CHECK(posix_memalign(&aligned, 4096, 100) == 0 && (size_t)aligned % 4096 == 0);
+    free(aligned);
+
+    /* libc allocates through us as well */
+    char *dup = strdup("preloaded");
+    CHECK(dup && malloc_usable_size(dup) >= 10);
+    free(dup);
+
+    /* a block of libc's own allocator goes back to it */
+    void *libc = dlopen("libc.so.6", RTLD_LAZY | RTLD_NOLOAD);
+    CHECK(libc);
+    void *(*libc_malloc)(size_t) = (void *(*)(size_t))dlsym(libc, "malloc");
+    CHECK(libc_malloc && libc_malloc != malloc);
+    size_t size = 64 << 10;
+    unsigned char *foreign = libc_malloc(size);
+    CHECK(foreign);
+    memset(foreign, 9, size);
+    CHECK(malloc_usable_size(foreign) >= size);
+    foreign = realloc(foreign, 4 * size);
+    CHECK(foreign && filled(foreign, size, 9));
+    free(foreign);
+
+    printf("ok\n");
+    return 0;
+}
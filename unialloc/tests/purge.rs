@@ -0,0 +1,54 @@
+//! Checks that freed memory leaves the resident set
+//!
+//! The resident set is process-wide, so everything runs in a single test.
+use std::alloc::{alloc, dealloc, Layout};
+
+include!("allocator.rs");
+
+const MB: usize = 1 << 20;
+
+/// Returns the resident set size in bytes
+fn rss() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").expect("statm");
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .expect("statm");
+    pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize
+}
+
+#[test]
+fn rss_drops_after_burst() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let count = 64 * MB / layout.size();
+    let mut ptrs: Vec<*mut u8> = Vec::with_capacity(count);
+
+    let before = rss();
+    for _ in 0..count {
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr.write_bytes(0xa5, layout.size()) };
+        ptrs.push(ptr);
+    }
+    let peak = rss();
+    for ptr in ptrs.drain(..) {
+        unsafe { dealloc(ptr, layout) };
+    }
+    unialloc::decay::purge();
+    let after = rss();
+
+    assert!(
+        peak - before >= 32 * MB,
+        "before: {}, peak: {}",
+        before,
+        peak
+    );
+    assert!(
+        after.saturating_sub(before) < (peak - before) / 4,
+        "before: {}, peak: {}, after: {}",
+        before,
+        peak,
+        after
+    );
+}
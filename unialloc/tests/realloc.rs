@@ -0,0 +1,61 @@
+//! Resizes large blocks through `realloc`
+//!
+//! Whether a block grows in place depends on its neighbours in the backend,
+//! so everything runs in a single test.
+use std::alloc::{alloc, dealloc, realloc, Layout};
+
+include!("allocator.rs");
+
+const KB: usize = 1 << 10;
+const MB: usize = 1 << 20;
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+fn filled(ptr: *mut u8, size: usize, byte: u8) -> bool {
+    unsafe { std::slice::from_raw_parts(ptr, size) }
+        .iter()
+        .all(|&b| b == byte)
+}
+
+#[test]
+fn large_blocks() {
+    unsafe {
+        // over free pages right after it a block grows in place
+        let ptr = alloc(layout(64 * KB));
+        let next = alloc(layout(192 * KB));
+        ptr.write_bytes(1, 64 * KB);
+        dealloc(next, layout(192 * KB));
+        let grown = realloc(ptr, layout(64 * KB), 200 * KB);
+        if next as usize == ptr as usize + 64 * KB {
+            assert_eq!(grown, ptr);
+            assert_eq!(UniAlloc.usable_size(ptr), Some(200 * KB));
+        }
+        assert!(filled(grown, 64 * KB, 1));
+        grown.add(64 * KB).write_bytes(2, 136 * KB);
+
+        // shrinking gives the tail back in place
+        let shrunk = realloc(grown, layout(200 * KB), 40 * KB);
+        assert_eq!(shrunk, grown);
+        assert_eq!(UniAlloc.usable_size(shrunk), Some(40 * KB));
+        assert!(filled(shrunk, 40 * KB, 1));
+        dealloc(shrunk, layout(40 * KB));
+
+        // very large blocks are moved by the OS
+        let ptr = alloc(layout(8 * MB));
+        ptr.write_bytes(3, 8 * MB);
+        let grown = realloc(ptr, layout(8 * MB), 64 * MB);
+        assert!(!grown.is_null());
+        assert_eq!(UniAlloc.usable_size(grown), Some(64 * MB));
+        assert!(filled(grown, 8 * MB, 3));
+        grown.add(64 * MB - 1).write(4);
+        if grown != ptr {
+            assert!(!UniAlloc.owns(ptr));
+        }
+        let shrunk = realloc(grown, layout(64 * MB), 4 * MB);
+        assert_eq!(shrunk, grown);
+        assert!(filled(shrunk, 4 * MB, 3));
+        dealloc(shrunk, layout(4 * MB));
+    }
+}
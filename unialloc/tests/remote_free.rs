@@ -0,0 +1,87 @@
+include!("allocator.rs");
+use std::sync::mpsc::channel;
+use std::thread;
+
+#[test]
+fn producer_consumer() {
+    let (tx, rx) = channel::<Vec<Box<[u64; 8]>>>();
+    let consumer = thread::spawn(move || {
+        let mut count = 0;
+        for batch in rx {
+            for item in batch {
+                assert_eq!(item[7], 7);
+                count += 1;
+            }
+        }
+        count
+    });
+    let producer = thread::spawn(move || {
+        for _ in 0..200 {
+            let batch: Vec<_> = (0..1000).map(|_| Box::new([7u64; 8])).collect();
+            tx.send(batch).expect("send");
+        }
+    });
+    producer.join().expect("producer");
+    assert_eq!(consumer.join().expect("consumer"), 200 * 1000);
+}
+
+#[test]
+fn free_after_owner_exit() {
+    let items = thread::spawn(|| (0..10000).map(Box::new).collect::<Vec<_>>())
+        .join()
+        .expect("owner");
+    for (i, item) in items.into_iter().enumerate() {
+        assert_eq!(*item, i);
+    }
+}
+
+#[test]
+fn free_returns_to_owner() {
+    // the per-CPU caches do not track owners
+    if unialloc::sysinfo::has_rseq() {
+        eprintln!("free_returns_to_owner: skipped, the per-CPU caches are in use");
+        return;
+    }
+    assert!(
+        !unialloc::sysinfo::rseq_expected(),
+        "the kernel has rseq but the per-CPU caches are not in use"
+    );
+    let (freed_tx, freed_rx) = channel::<Vec<usize>>();
+    let (done_tx, done_rx) = channel::<()>();
+    let (exit_tx, exit_rx) = channel::<()>();
+    // the freeing thread stays alive, so its cache cannot give the objects
+    // back to the zone
+    let consumer = thread::spawn(move || {
+        let ptrs = freed_rx.recv().expect("ptrs");
+        for ptr in ptrs {
+            drop(unsafe { Box::from_raw(ptr as *mut [u64; 8]) });
+        }
+        done_tx.send(()).expect("done");
+        exit_rx.recv().expect("exit");
+    });
+
+    let freed: Vec<usize> = (0..100)
+        .map(|_| Box::into_raw(Box::new([7u64; 8])) as usize)
+        .collect();
+    freed_tx.send(freed.clone()).expect("send");
+    done_rx.recv().expect("done");
+
+    // once the objects the owner holds run out, the freed ones come back
+    let mut local = Vec::with_capacity(1 << 16);
+    let mut found = false;
+    while local.len() < local.capacity() {
+        let item = Box::new([0u64; 8]);
+        if freed.contains(&(&*item as *const _ as usize)) {
+            found = true;
+            break;
+        }
+        local.push(item);
+    }
+    exit_tx.send(()).expect("exit");
+    consumer.join().expect("consumer");
+    assert!(
+        found,
+        "no freed object back after {} allocations",
+        local.len()
+    );
+}
@@ -0,0 +1,48 @@
+//! Allocates zero-sized blocks through every interface
+#![feature(allocator_api)]
+use std::alloc::{alloc, dealloc, Allocator, Layout};
+
+include!("allocator.rs");
+
+#[test]
+fn aligned_and_foreign() {
+    for align in [1, 8, 64, 4096, 1 << 16] {
+        let layout = Layout::from_size_align(0, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        if align <= 4096 {
+            // no block, so no one else's either
+            assert!(!UniAlloc.owns(ptr));
+        }
+        unsafe { dealloc(ptr, layout) };
+
+        let block = UniAlloc.allocate(layout).unwrap();
+        assert_eq!(block.as_mut_ptr() as usize % align, 0);
+        unsafe { UniAlloc.deallocate(block.cast(), layout) };
+    }
+}
+
+#[test]
+fn resize_from_and_to_zero() {
+    let empty = Layout::from_size_align(0, 8).unwrap();
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let ptr = UniAlloc.allocate(empty).unwrap().cast();
+        let grown = UniAlloc.grow(ptr, empty, layout).unwrap();
+        grown.as_mut_ptr().write_bytes(1, 100);
+        let shrunk = UniAlloc.shrink(grown.cast(), layout, empty).unwrap();
+        assert_eq!(shrunk.len(), 0);
+        UniAlloc.deallocate(shrunk.cast(), empty);
+    }
+}
+
+#[test]
+fn zero_sized_types() {
+    let v: Vec<()> = (0..1000).map(|_| ()).collect();
+    assert_eq!(v.len(), 1000);
+    let boxes: Vec<Box<[u8; 0]>> = (0..100).map(|_| Box::new([])).collect();
+    drop(boxes);
+    let empty: Vec<u64> = Vec::with_capacity(0);
+    drop(empty);
+}
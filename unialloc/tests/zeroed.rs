@@ -0,0 +1,59 @@
+//! Checks that zeroed blocks read as zeros, recycled ones included
+#![feature(allocator_api)]
+use std::alloc::{alloc_zeroed, dealloc, Allocator, Layout};
+
+include!("allocator.rs");
+
+fn zeroed(ptr: *const u8, size: usize) -> bool {
+    unsafe { std::slice::from_raw_parts(ptr, size) }
+        .iter()
+        .all(|&b| b == 0)
+}
+
+#[test]
+fn recycled_blocks() {
+    for (size, align) in [
+        (24, 8),
+        (20_000, 8),
+        (100_000, 8),
+        (300_000, 8),
+        (4 << 20, 8),
+        (100_000, 1 << 16),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        for _ in 0..4 {
+            let ptr = unsafe { alloc_zeroed(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            assert!(zeroed(ptr, size), "size: {}", size);
+            unsafe {
+                ptr.write_bytes(0xa5, size);
+                dealloc(ptr, layout);
+            }
+        }
+    }
+}
+
+#[test]
+fn allocate_zeroed_whole_block() {
+    for size in [20, 1000, 50_000] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let block = UniAlloc.allocate(layout).unwrap();
+        unsafe {
+            block.as_mut_ptr().write_bytes(1, block.len());
+            UniAlloc.deallocate(block.cast(), layout);
+        }
+        let block = UniAlloc.allocate_zeroed(layout).unwrap();
+        assert!(zeroed(block.as_mut_ptr(), block.len()));
+        unsafe { UniAlloc.deallocate(block.cast(), layout) };
+    }
+}
+
+#[test]
+fn zeroed_vec() {
+    for _ in 0..8 {
+        let mut v = vec![0u64; 1 << 17];
+        assert!(v.iter().all(|&x| x == 0));
+        v.iter_mut().for_each(|x| *x = u64::MAX);
+    }
+}